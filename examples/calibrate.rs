@@ -0,0 +1,88 @@
+//! Calibrates a reader with the views of a board, and writes a file for `calibration`.
+//!
+//! ```sh
+//! cargo run --example calibrate -- assets/pipeline.yaml main assets/calib/main.yaml \
+//!     chessboard 9 6 0.025 [views]
+//! cargo run --example calibrate -- assets/pipeline.yaml main assets/calib/main.yaml \
+//!     charuco 7 5 0.04 0.03 [views]
+//! ```
+
+use std::env;
+use std::process;
+
+use podo_std_eye::{Board, Calibrator, EyeDriver};
+
+const USAGE: &str = "Usage: calibrate <config> <reader> <output> \
+                     (chessboard <cols> <rows> <square> | \
+                     charuco <cols> <rows> <square> <marker>) [views]";
+
+fn parse_args(args: &[String]) -> Option<(Board, usize)> {
+    let number = |index: usize| args.get(index)?.parse::<f32>().ok();
+    let (board, rest) = match args.get(0)?.as_str() {
+        "chessboard" => (
+            Board::Chessboard {
+                cols: number(1)? as u32,
+                rows: number(2)? as u32,
+                square: number(3)?,
+            },
+            4,
+        ),
+        "charuco" => (
+            Board::Charuco {
+                cols: number(1)? as u32,
+                rows: number(2)? as u32,
+                square: number(3)?,
+                marker: number(4)?,
+            },
+            5,
+        ),
+        _ => return None,
+    };
+    let views = match args.get(rest) {
+        Some(views) => views.parse().ok()?,
+        None => 20,
+    };
+    Some((board, views))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (board, views) = match args.get(3..).and_then(parse_args) {
+        Some(parsed) => parsed,
+        None => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    let (config, name, output) = (&args[0], &args[1], &args[2]);
+
+    let driver = EyeDriver::try_with_config(config).unwrap();
+    let reader = match driver.get(name) {
+        Some(reader) => reader,
+        None => {
+            eprintln!("No such reader: {}", name);
+            process::exit(2);
+        }
+    };
+    reader.start().unwrap();
+
+    let mut calibrator = Calibrator::new(board).unwrap();
+    let mut frame = None;
+    while calibrator.len() < views {
+        reader.get(&mut frame).unwrap();
+        let frame = frame.as_ref().unwrap();
+        if calibrator.add(&frame.image).unwrap() {
+            println!("Collected a view: {}/{}", calibrator.len(), views);
+        }
+    }
+    reader.stop().unwrap();
+
+    let (calibration, report) = calibrator.calibrate().unwrap();
+    for (index, error) in report.per_view.iter().enumerate() {
+        println!("View #{}: {:.4} px", index, error);
+    }
+    println!("Reprojection error (RMS): {:.4} px", report.rms);
+
+    calibration.save(output).unwrap();
+    println!("Saved the calibration to {}", output);
+}
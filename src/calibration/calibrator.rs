@@ -0,0 +1,338 @@
+use super::Calibration;
+
+use opencv::aruco;
+use opencv::calib3d;
+use opencv::core::{self, Mat, Point2f, Point3f, Ptr, Rect, Size, TermCriteria};
+use opencv::imgproc;
+use opencv::prelude::*;
+use opencv::types::{
+    VectorOfMat, VectorOfPoint2f, VectorOfPoint3f, VectorOfVectorOfPoint2f,
+    VectorOfVectorOfPoint3f, VectorOfi32,
+};
+use podo_core_driver::RuntimeError;
+use serde::{Deserialize, Serialize};
+
+/// The pattern printed on the calibration target.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum Board {
+    /// Counts the inner corners, and measures the squares in any unit.
+    Chessboard { cols: u32, rows: u32, square: f32 },
+    /// Counts the squares, and measures the squares & the markers in any unit.
+    /// The markers come from the predefined dictionary `DICT_4X4_50`.
+    Charuco {
+        cols: u32,
+        rows: u32,
+        square: f32,
+        marker: f32,
+    },
+}
+
+/// The corners of the board found in a view.
+struct View {
+    object: VectorOfPoint3f,
+    image: VectorOfPoint2f,
+    /// The normalized center & area of the corners, to tell the views apart.
+    pose: [f32; 3],
+}
+
+/// The quality of a calibration.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CalibrationReport {
+    /// The RMS reprojection error of all views, in pixels.
+    pub rms: f64,
+    /// The RMS reprojection error of each view, in pixels.
+    pub per_view: Vec<f64>,
+}
+
+enum Detector {
+    Chessboard {
+        size: Size,
+        object: VectorOfPoint3f,
+    },
+    Charuco {
+        dictionary: Ptr<aruco::Dictionary>,
+        board: Ptr<aruco::CharucoBoard>,
+        parameters: Ptr<aruco::DetectorParameters>,
+    },
+}
+
+/// Collects diverse views of a board, and calibrates a camera with them.
+pub struct Calibrator {
+    detector: Detector,
+    views: Vec<View>,
+    size: Option<Size>,
+    /// The minimum distance between the poses of two views.
+    min_distance: f32,
+}
+
+// the detector is used by one thread at a time
+unsafe impl Send for Calibrator {}
+
+impl Calibrator {
+    pub fn new(board: Board) -> Result<Self, RuntimeError> {
+        let detector = match board {
+            Board::Chessboard { cols, rows, square } => {
+                let mut object = VectorOfPoint3f::new();
+                for row in 0..rows {
+                    for col in 0..cols {
+                        let (x, y) = (col as f32 * square, row as f32 * square);
+                        object.push(Point3f::new(x, y, 0.));
+                    }
+                }
+                Detector::Chessboard {
+                    size: Size::new(cols as i32, rows as i32),
+                    object,
+                }
+            }
+            Board::Charuco {
+                cols,
+                rows,
+                square,
+                marker,
+            } => {
+                let dictionary = aruco::get_predefined_dictionary(
+                    aruco::PREDEFINED_DICTIONARY_NAME::DICT_4X4_50,
+                )?;
+                let board = aruco::CharucoBoard::create(
+                    cols as i32,
+                    rows as i32,
+                    square,
+                    marker,
+                    &dictionary,
+                )?;
+                Detector::Charuco {
+                    dictionary,
+                    board,
+                    parameters: aruco::DetectorParameters::create()?,
+                }
+            }
+        };
+
+        Ok(Self {
+            detector,
+            views: vec![],
+            size: None,
+            min_distance: 0.05,
+        })
+    }
+
+    /// Accepts the views whose poses differ by the given distance, 0.05 by default.
+    pub fn with_min_distance(mut self, min_distance: f32) -> Self {
+        self.min_distance = min_distance;
+        self
+    }
+
+    /// Returns the number of the collected views.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.views.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.views.is_empty()
+    }
+
+    /// Looks for the board in an image, and returns whether the view has been collected.
+    ///
+    /// A view is skipped if the board is not found, or is too similar to a collected one.
+    pub fn add(&mut self, image: &Mat) -> Result<bool, RuntimeError> {
+        let size = Size::new(image.cols(), image.rows());
+        match self.size {
+            Some(s) if s != size => {
+                return RuntimeError::expect("The views should be of the same size");
+            }
+            _ => self.size = Some(size),
+        }
+
+        let gray = match image.channels()? {
+            1 => Mat::copy(image)?,
+            _ => {
+                let mut gray = Mat::default()?;
+                imgproc::cvt_color(image, &mut gray, imgproc::COLOR_BGR2GRAY, 0)?;
+                gray
+            }
+        };
+        let (object, corners) = match self.detect(&gray)? {
+            Some(found) => found,
+            None => return Ok(false),
+        };
+
+        let pose = pose_of(&corners, size)?;
+        let is_new = self.views.iter().all(|view| {
+            let d = view
+                .pose
+                .iter()
+                .zip(pose.iter())
+                .map(|(a, b)| (a - b).powi(2))
+                .sum::<f32>();
+            d.sqrt() >= self.min_distance
+        });
+        if is_new {
+            self.views.push(View {
+                object,
+                image: corners,
+                pose,
+            });
+        }
+        Ok(is_new)
+    }
+
+    fn detect(
+        &self,
+        gray: &Mat,
+    ) -> Result<Option<(VectorOfPoint3f, VectorOfPoint2f)>, RuntimeError> {
+        match &self.detector {
+            Detector::Chessboard { size, object } => {
+                let mut corners = VectorOfPoint2f::new();
+                let flags = calib3d::CALIB_CB_ADAPTIVE_THRESH | calib3d::CALIB_CB_NORMALIZE_IMAGE;
+                if !calib3d::find_chessboard_corners(gray, *size, &mut corners, flags)? {
+                    return Ok(None);
+                }
+                let criteria = TermCriteria::new(
+                    core::TermCriteria_COUNT + core::TermCriteria_EPS,
+                    30,
+                    0.001,
+                )?;
+                imgproc::corner_sub_pix(
+                    gray,
+                    &mut corners,
+                    Size::new(11, 11),
+                    Size::new(-1, -1),
+                    criteria,
+                )?;
+                Ok(Some((object.iter().collect(), corners)))
+            }
+            Detector::Charuco {
+                dictionary,
+                board,
+                parameters,
+            } => {
+                let mut markers = VectorOfVectorOfPoint2f::new();
+                let mut marker_ids = VectorOfi32::new();
+                let mut rejected = VectorOfVectorOfPoint2f::new();
+                aruco::detect_markers(
+                    gray,
+                    dictionary,
+                    &mut markers,
+                    &mut marker_ids,
+                    parameters,
+                    &mut rejected,
+                    &core::no_array()?,
+                    &core::no_array()?,
+                )?;
+                if marker_ids.is_empty() {
+                    return Ok(None);
+                }
+
+                let mut corners = VectorOfPoint2f::new();
+                let mut ids = VectorOfi32::new();
+                aruco::interpolate_corners_charuco(
+                    &markers,
+                    &marker_ids,
+                    gray,
+                    board,
+                    &mut corners,
+                    &mut ids,
+                    &core::no_array()?,
+                    &core::no_array()?,
+                    2,
+                )?;
+                // the pose is ambiguous with the too few corners
+                if ids.len() < 6 {
+                    return Ok(None);
+                }
+
+                let board_corners = board.chessboard_corners();
+                let mut object = VectorOfPoint3f::new();
+                for id in ids.iter() {
+                    object.push(board_corners.get(id as usize)?);
+                }
+                Ok(Some((object, corners)))
+            }
+        }
+    }
+
+    /// Calibrates the camera with the collected views.
+    pub fn calibrate(&self) -> Result<(Calibration, CalibrationReport), RuntimeError> {
+        let size = match self.size {
+            Some(size) if self.views.len() >= 3 => size,
+            _ => return RuntimeError::expect("The calibration needs 3 views at least"),
+        };
+
+        let mut objects = VectorOfVectorOfPoint3f::new();
+        let mut images = VectorOfVectorOfPoint2f::new();
+        for view in &self.views {
+            objects.push(view.object.iter().collect());
+            images.push(view.image.iter().collect());
+        }
+
+        let mut camera_matrix = Mat::default()?;
+        let mut distortion = Mat::default()?;
+        let mut rvecs = VectorOfMat::new();
+        let mut tvecs = VectorOfMat::new();
+        let criteria = TermCriteria::new(
+            core::TermCriteria_COUNT + core::TermCriteria_EPS,
+            30,
+            f64::EPSILON,
+        )?;
+        let rms = calib3d::calibrate_camera(
+            &objects,
+            &images,
+            size,
+            &mut camera_matrix,
+            &mut distortion,
+            &mut rvecs,
+            &mut tvecs,
+            0,
+            criteria,
+        )?;
+
+        let per_view = self
+            .views
+            .iter()
+            .enumerate()
+            .map(|(index, view)| {
+                let mut projected = VectorOfPoint2f::new();
+                calib3d::project_points(
+                    &view.object,
+                    &rvecs.get(index)?,
+                    &tvecs.get(index)?,
+                    &camera_matrix,
+                    &distortion,
+                    &mut projected,
+                    &mut Mat::default()?,
+                    0.,
+                )?;
+                let error = projected
+                    .iter()
+                    .zip(view.image.iter())
+                    .map(|(a, b)| ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)) as f64)
+                    .sum::<f64>();
+                Ok((error / view.image.len() as f64).sqrt())
+            })
+            .collect::<Result<_, RuntimeError>>()?;
+
+        let calibration = Calibration {
+            camera_matrix,
+            distortion,
+            rectification: None,
+            projection: None,
+            width: size.width as u32,
+            height: size.height as u32,
+        };
+        Ok((calibration, CalibrationReport { rms, per_view }))
+    }
+}
+
+/// Returns the center & the area of the bounding box of the corners, relative to the image.
+fn pose_of(corners: &VectorOfPoint2f, size: Size) -> Result<[f32; 3], RuntimeError> {
+    let rect: Rect = imgproc::bounding_rect(corners)?;
+    let (w, h) = (size.width as f32, size.height as f32);
+    let center = Point2f::new(
+        (rect.x as f32 + rect.width as f32 / 2.) / w,
+        (rect.y as f32 + rect.height as f32 / 2.) / h,
+    );
+    let area = (rect.width * rect.height) as f32 / (w * h);
+    Ok([center.x, center.y, area.sqrt()])
+}
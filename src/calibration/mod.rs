@@ -0,0 +1,295 @@
+//! The calibration of cameras, in the file format of OpenCV.
+//!
+//! ```yaml
+//! %YAML:1.0
+//! image_width: 640
+//! image_height: 480
+//! camera_matrix: !!opencv-matrix
+//!    rows: 3
+//!    cols: 3
+//!    dt: d
+//!    data: [ 5.2e+02, 0., 3.2e+02, 0., 5.2e+02, 2.4e+02, 0., 0., 1. ]
+//! distortion_coefficients: !!opencv-matrix
+//!    rows: 1
+//!    cols: 5
+//!    dt: d
+//!    data: [ -0.28, 0.07, 0., 0., 0. ]
+//! # optional, given by the stereo rectification
+//! rectification_matrix: !!opencv-matrix
+//!    ...
+//! projection_matrix: !!opencv-matrix
+//!    ...
+//! ```
+
+mod calibrator;
+
+pub use self::calibrator::{Board, CalibrationReport, Calibrator};
+
+use std::fmt;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use crate::frame::Image;
+
+use opencv::calib3d;
+use opencv::core::{self, FileStorage, Scalar, Size, CV_16SC2, CV_64F};
+use opencv::imgproc;
+use opencv::prelude::*;
+use podo_core_driver::RuntimeError;
+use serde::{Deserialize, Serialize};
+
+/// The pinhole parameters of the frames of a reader, without distortion.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Intrinsics {
+    pub fx: f64,
+    pub fy: f64,
+    pub cx: f64,
+    pub cy: f64,
+
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Intrinsics {
+    /// Returns the 3x3 camera matrix.
+    pub fn camera_matrix(&self) -> Result<Mat, RuntimeError> {
+        let data = [
+            self.fx, 0., self.cx, //
+            0., self.fy, self.cy, //
+            0., 0., 1.,
+        ];
+        let mut matrix = Mat::new_rows_cols_with_default(3, 3, CV_64F, Scalar::default())?;
+        for (index, value) in data.iter().enumerate() {
+            *matrix.at_2d_mut::<f64>(index as i32 / 3, index as i32 % 3)? = *value;
+        }
+        Ok(matrix)
+    }
+
+    fn from_matrix(matrix: &Mat, width: u32, height: u32) -> Result<Self, RuntimeError> {
+        Ok(Self {
+            fx: *matrix.at_2d::<f64>(0, 0)?,
+            fy: *matrix.at_2d::<f64>(1, 1)?,
+            cx: *matrix.at_2d::<f64>(0, 2)?,
+            cy: *matrix.at_2d::<f64>(1, 2)?,
+            width,
+            height,
+        })
+    }
+}
+
+pub struct Calibration {
+    pub camera_matrix: Mat,
+    pub distortion: Mat,
+    pub rectification: Option<Mat>,
+    pub projection: Option<Mat>,
+
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Calibration {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, RuntimeError> {
+        let filename = match path.as_ref().to_str() {
+            Some(filename) => filename,
+            None => return RuntimeError::expect("The path should be a valid UTF-8 string"),
+        };
+        let fs = FileStorage::new(filename, core::FileStorage_READ, "")?;
+        if !fs.is_opened()? {
+            return RuntimeError::message(format!("Failed to open calibration {:?}", filename));
+        }
+
+        let read = |name: &str| -> Result<Option<Mat>, RuntimeError> {
+            let node = fs.get(name)?;
+            if node.empty()? {
+                return Ok(None);
+            }
+            let mut matrix = Mat::default()?;
+            node.mat()?.convert_to(&mut matrix, CV_64F, 1., 0.)?;
+            Ok(Some(matrix))
+        };
+        let camera_matrix = match read("camera_matrix")? {
+            Some(matrix) => matrix,
+            None => return RuntimeError::expect("The calibration should have camera_matrix"),
+        };
+        let distortion = match read("distortion_coefficients")? {
+            Some(matrix) => matrix,
+            None => Mat::default()?,
+        };
+
+        Ok(Self {
+            camera_matrix,
+            distortion,
+            rectification: read("rectification_matrix")?,
+            projection: read("projection_matrix")?,
+            width: fs.get("image_width")?.to_i32()? as u32,
+            height: fs.get("image_height")?.to_i32()? as u32,
+        })
+    }
+
+    /// Writes the calibration in the file format of OpenCV, readable by `load`.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), RuntimeError> {
+        let mut text = String::from("%YAML:1.0\n---\n");
+        writeln!(text, "image_width: {}", self.width).unwrap();
+        writeln!(text, "image_height: {}", self.height).unwrap();
+        write_matrix(&mut text, "camera_matrix", &self.camera_matrix)?;
+        write_matrix(&mut text, "distortion_coefficients", &self.distortion)?;
+        if let Some(rectification) = self.rectification.as_ref() {
+            write_matrix(&mut text, "rectification_matrix", rectification)?;
+        }
+        if let Some(projection) = self.projection.as_ref() {
+            write_matrix(&mut text, "projection_matrix", projection)?;
+        }
+        Ok(fs::write(path, text)?)
+    }
+
+    /// Returns the camera matrix of the undistorted frames.
+    fn new_camera_matrix(&self) -> Result<Mat, RuntimeError> {
+        match self.projection.as_ref() {
+            Some(projection) => Ok(Mat::roi(projection, core::Rect::new(0, 0, 3, 3))?),
+            None => Ok(Mat::copy(&self.camera_matrix)?),
+        }
+    }
+
+    /// Precomputes the maps to undistort (and rectify) the frames.
+    pub fn undistortion(&self) -> Result<Undistortion, RuntimeError> {
+        if self.width == 0 || self.height == 0 {
+            return RuntimeError::expect("The calibration should have the image size");
+        }
+        let size = Size::new(self.width as i32, self.height as i32);
+        let new_camera_matrix = self.new_camera_matrix()?;
+        let rectification = match self.rectification.as_ref() {
+            Some(rectification) => Mat::copy(rectification)?,
+            None => Mat::default()?,
+        };
+
+        let mut map1 = Mat::default()?;
+        let mut map2 = Mat::default()?;
+        calib3d::init_undistort_rectify_map(
+            &self.camera_matrix,
+            &self.distortion,
+            &rectification,
+            &new_camera_matrix,
+            size,
+            CV_16SC2,
+            &mut map1,
+            &mut map2,
+        )?;
+
+        Ok(Undistortion {
+            map1,
+            map2,
+            intrinsics: Intrinsics::from_matrix(&new_camera_matrix, self.width, self.height)?,
+        })
+    }
+}
+
+pub(crate) fn write_matrix(
+    text: &mut String,
+    name: &str,
+    matrix: &Mat,
+) -> Result<(), RuntimeError> {
+    let mut data = Vec::with_capacity(matrix.total()?);
+    for row in 0..matrix.rows() {
+        for col in 0..matrix.cols() {
+            data.push(format!("{:e}", matrix.at_2d::<f64>(row, col)?));
+        }
+    }
+    writeln!(text, "{}: !!opencv-matrix", name).unwrap();
+    writeln!(text, "   rows: {}", matrix.rows()).unwrap();
+    writeln!(text, "   cols: {}", matrix.cols()).unwrap();
+    writeln!(text, "   dt: d").unwrap();
+    writeln!(text, "   data: [ {} ]", data.join(", ")).unwrap();
+    Ok(())
+}
+
+/// Undistorts the frames of the calibrated size, with the precomputed maps.
+pub struct Undistortion {
+    map1: Mat,
+    map2: Mat,
+    intrinsics: Intrinsics,
+}
+
+// the maps are only read after being computed
+unsafe impl Send for Undistortion {}
+unsafe impl Sync for Undistortion {}
+
+impl fmt::Debug for Undistortion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Undistortion")
+            .field("intrinsics", &self.intrinsics)
+            .finish()
+    }
+}
+
+impl Undistortion {
+    /// Returns the intrinsics of the undistorted frames.
+    #[inline]
+    pub fn intrinsics(&self) -> &Intrinsics {
+        &self.intrinsics
+    }
+
+    pub fn apply(&self, image: &mut Image) -> Result<(), RuntimeError> {
+        let (width, height) = (self.intrinsics.width, self.intrinsics.height);
+        if image.cols() as u32 != width || image.rows() as u32 != height {
+            return RuntimeError::message(format!(
+                "The image should be of the calibrated size {}x{}",
+                width, height
+            ));
+        }
+
+        let mut output = Mat::default()?;
+        imgproc::remap(
+            &**image,
+            &mut output,
+            &self.map1,
+            &self.map2,
+            imgproc::INTER_LINEAR,
+            core::BORDER_CONSTANT,
+            Scalar::default(),
+        )?;
+        *image = Image::from(output);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibration_save_load() {
+        let intrinsics = Intrinsics {
+            fx: 520.,
+            fy: 515.5,
+            cx: 319.5,
+            cy: 239.5,
+            width: 640,
+            height: 480,
+        };
+        let mut distortion =
+            Mat::new_rows_cols_with_default(1, 5, CV_64F, Scalar::default()).unwrap();
+        *distortion.at_2d_mut::<f64>(0, 0).unwrap() = -0.28;
+        *distortion.at_2d_mut::<f64>(0, 1).unwrap() = 0.07;
+        let calibration = Calibration {
+            camera_matrix: intrinsics.camera_matrix().unwrap(),
+            distortion,
+            rectification: None,
+            projection: None,
+            width: 640,
+            height: 480,
+        };
+
+        let name = format!("podo-eye-calibration-{}.yaml", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        calibration.save(&path).unwrap();
+        let loaded = Calibration::load(&path).unwrap();
+        fs::remove_file(&path).ok();
+
+        assert_eq!((loaded.width, loaded.height), (640, 480));
+        assert!(loaded.rectification.is_none());
+        assert_eq!(*loaded.distortion.at_2d::<f64>(0, 0).unwrap(), -0.28);
+        assert_eq!(*loaded.distortion.at_2d::<f64>(0, 1).unwrap(), 0.07);
+        assert_eq!(loaded.undistortion().unwrap().intrinsics(), &intrinsics);
+    }
+}
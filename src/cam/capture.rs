@@ -1,12 +1,14 @@
 use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time::Duration;
 
 use super::queue::Queue;
+use crate::calibration::Intrinsics;
 use crate::common::VideoReader;
 use crate::config::{Configurable, VideoColor, VideoMeta};
 use crate::frame::Frame;
+use crate::pipeline::Pipeline;
 
 use chrono::prelude::*;
 use opencv::prelude::*;
@@ -18,6 +20,8 @@ use serde::Deserialize;
 pub struct CamConfig {
     pub(crate) device: u16,
     pub(crate) export: Option<bool>,
+    /// Triggers the camera together with the others of the same sync group.
+    pub(crate) sync: Option<String>,
     #[serde(flatten)]
     pub(crate) meta: VideoMeta,
 }
@@ -39,9 +43,64 @@ impl Configurable for CamConfig {
     }
 }
 
+/// The recorded counts of the replayed frames, after the frames pushed before the replay.
+struct Counts {
+    base: usize,
+    counts: Vec<usize>,
+}
+
+impl Counts {
+    /// Returns the position in the queue of a frame, given its recorded count.
+    fn position(&self, count: usize) -> usize {
+        match self.counts.binary_search(&count) {
+            Ok(index) => self.base + index + 1,
+            Err(index) => self.base + index,
+        }
+    }
+
+    /// Returns the recorded count of a frame, given its position in the queue.
+    fn count(&self, position: usize) -> usize {
+        self.counts[position - self.base - 1]
+    }
+}
+
+/// Reproduces the original timing of a recording.
+struct Replay {
+    timeline: Vec<DateTime<Utc>>,
+    index: usize,
+    begin: DateTime<Utc>,
+}
+
+impl Replay {
+    fn new(timeline: Vec<DateTime<Utc>>) -> Self {
+        Self {
+            timeline,
+            index: 0,
+            begin: Utc::now(),
+        }
+    }
+
+    /// Waits until the original time of the next frame, and returns its timestamp.
+    fn wait_next(&mut self) -> Result<DateTime<Utc>, RuntimeError> {
+        let timestamp = match self.timeline.get(self.index) {
+            Some(timestamp) => *timestamp,
+            None => return RuntimeError::expect("The replay is over"),
+        };
+        let due = self.begin + (timestamp - self.timeline[0]);
+        // a late frame is not waited
+        if let Ok(time) = (due - Utc::now()).to_std() {
+            thread::sleep(time);
+        }
+        self.index += 1;
+        Ok(timestamp)
+    }
+}
+
 struct Thread {
     camera: videoio::VideoCapture,
     color: VideoColor,
+    replay: Option<Replay>,
+    pipeline: Arc<Pipeline>,
 
     queue: Arc<Queue>,
     alive: AliveFlag,
@@ -55,18 +114,24 @@ impl Thread {
         alive: AliveFlag,
         config: &C,
         path: &PathBuf,
+        timeline: Option<Vec<DateTime<Utc>>>,
+        pipeline: Arc<Pipeline>,
     ) -> Result<thread::JoinHandle<Result<(), RuntimeError>>, RuntimeError>
     where
         C: Configurable,
     {
         let (camera, color) = config.spawn(path)?;
-        let us_per_frame = match config.meta().fps {
-            0 => 0,
-            _fps => (1_000_000_f64 / _fps as f64) as i64,
+        let replay = timeline.map(Replay::new);
+        let us_per_frame = match (config.meta().fps, &replay) {
+            // the replay keeps its own timing
+            (_, Some(_)) | (0, _) => 0,
+            (_fps, None) => (1_000_000_f64 / _fps as f64) as i64,
         };
         let this = Self {
             camera,
             color,
+            replay,
+            pipeline,
             queue,
             alive,
             us_per_frame,
@@ -78,22 +143,34 @@ impl Thread {
     #[inline]
     fn inner_loop(self) -> Result<(), RuntimeError> {
         let color = self.color;
+        let pipeline = self.pipeline;
         let sync = self.us_per_frame > 0;
+        let mut replay = self.replay;
+        let wait_consumed = !sync && replay.is_none();
         let mut camera = self.camera;
         let result = loop {
             // normal shutdown
             if let false = self.alive.is_running() {
                 break Ok(());
             }
-            let timestamp = Utc::now();
+            let timestamp = match replay.as_mut() {
+                Some(replay) => match replay.wait_next() {
+                    Ok(timestamp) => timestamp,
+                    Err(e) => break Err(e),
+                },
+                None => Utc::now(),
+            };
             // unexpected shutdown
             if let Err(e) = self.queue.push_inner(
                 |image| match camera.read(image as &mut Mat)? {
-                    true => color.convert(&mut *image),
+                    true => {
+                        color.convert(&mut *image)?;
+                        pipeline.apply(image)
+                    }
                     false => RuntimeError::expect("opencv::VideoCapture::read failed"),
                 },
                 timestamp,
-                !sync,
+                wait_consumed,
             ) {
                 break Err(e);
             }
@@ -128,9 +205,11 @@ where
     queue: Arc<Queue>,
     alive: AliveFlag,
     thread: Mutex<Option<thread::JoinHandle<Result<(), RuntimeError>>>>,
+    counts: RwLock<Option<Counts>>,
 
     config: C,
     path: PathBuf,
+    pipeline: Arc<Pipeline>,
 }
 
 impl<C> VideoCapture<C>
@@ -144,10 +223,18 @@ where
             queue: Arc::new(Queue::new(&alive, 2)?),
             alive,
             thread: Mutex::new(None),
+            counts: RwLock::new(None),
             config,
             path: path.as_ref().to_path_buf(),
+            pipeline: Default::default(),
         })
     }
+
+    /// Preprocesses the frames in the capture thread.
+    pub fn with_pipeline(mut self, pipeline: Pipeline) -> Self {
+        self.pipeline = Arc::new(pipeline);
+        self
+    }
 }
 
 impl<C> VideoReader for VideoCapture<C>
@@ -155,12 +242,24 @@ where
     C: Configurable,
 {
     fn start(&self) -> Result<(), RuntimeError> {
+        let (counts, timeline) = match self.config.timeline(&self.path)? {
+            Some(timeline) => {
+                let (counts, timeline) = timeline.into_iter().unzip();
+                let base = self.queue.pushed();
+                (Some(Counts { base, counts }), Some(timeline))
+            }
+            None => (None, None),
+        };
+        *self.counts.write().unwrap() = counts;
+
         self.alive.start()?;
         let t = Thread::new_thread(
             self.queue.clone(),
             self.alive.clone(),
             &self.config,
             &self.path,
+            timeline,
+            self.pipeline.clone(),
         )?;
         self.thread.lock().unwrap().replace(t);
         Ok(())
@@ -192,18 +291,32 @@ where
         let frame = match frame.as_mut() {
             Some(frame) => frame,
             None => {
-                frame.replace(Frame::new(self.config.meta().clone())?);
+                frame.replace(Frame::new(self.pipeline.meta(self.config.meta()))?);
                 frame.as_mut().unwrap()
             }
         };
         match self.alive.is_running() {
-            true => self.queue.pop_inner(frame),
+            true => match &*self.counts.read().unwrap() {
+                // the replayed frames keep their recorded counts
+                Some(counts) => {
+                    frame.count = counts.position(frame.count);
+                    self.queue.pop_inner(frame)?;
+                    frame.count = counts.count(frame.count);
+                    Ok(())
+                }
+                None => self.queue.pop_inner(frame),
+            },
             false => match self.stop() {
-                Ok(()) => unreachable!(),
+                Ok(()) => RuntimeError::expect("The reader has been stopped"),
                 Err(e) => Err(e),
             },
         }
     }
+
+    #[inline]
+    fn intrinsics(&self) -> Option<Intrinsics> {
+        self.pipeline.intrinsics(None)
+    }
 }
 
 impl<C> Drop for VideoCapture<C>
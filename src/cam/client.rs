@@ -3,10 +3,13 @@ use std::sync::{mpsc, Arc, Mutex, RwLock};
 use std::thread;
 
 use super::queue::Queue;
+use crate::calibration::Intrinsics;
 use crate::common::VideoReader;
 use crate::config::VideoMeta;
-use crate::export::{EyeRequest, EyeRequestType, EyeResponse, PORT};
+use crate::export::{relay_id, EyeRequest, EyeRequestType, EyeResponse, PORT};
 use crate::frame::Frame;
+use crate::pipeline::Pipeline;
+use crate::protocol::{Encoding, Handshake, Packet, PROTOCOL_VERSION};
 
 use podo_core_driver::*;
 use serde::Deserialize;
@@ -15,6 +18,9 @@ use simple_socket::SocketClient;
 #[derive(Debug, Deserialize)]
 pub struct ClientConfig {
     pub(crate) ip: String,
+    pub(crate) export: Option<bool>,
+    /// The only encoding to accept, any of them by default.
+    pub(crate) encoding: Option<Encoding>,
 }
 
 struct Thread {
@@ -24,7 +30,12 @@ struct Thread {
     meta: mpsc::Sender<VideoMeta>,
 
     name: String,
-    client: SocketClient<EyeRequest, EyeResponse>,
+    route: Vec<u64>,
+    encodings: Vec<Encoding>,
+    session: u64,
+    encoding: Encoding,
+    client: SocketClient<Packet, Packet>,
+    pipeline: Arc<Pipeline>,
 }
 
 impl Thread {
@@ -34,7 +45,9 @@ impl Thread {
         alive: AliveFlag,
         meta: mpsc::Sender<VideoMeta>,
         name: &str,
+        route: Vec<u64>,
         config: &ClientConfig,
+        pipeline: Arc<Pipeline>,
     ) -> Result<thread::JoinHandle<Result<(), RuntimeError>>, RuntimeError> {
         let ip = config.ip.parse()?;
 
@@ -46,19 +59,102 @@ impl Thread {
             alive,
             meta,
             name: name.to_string(),
+            route,
+            encodings: match config.encoding {
+                Some(encoding) => vec![encoding],
+                None => Encoding::SUPPORTED.to_vec(),
+            },
+            // the handshake is always bincode
+            session: 0,
+            encoding: Encoding::Bincode,
             client,
+            pipeline,
         };
         let t = thread::spawn(move || this.inner_loop());
         Ok(t)
     }
 
+    /// Sends a request in the session, and returns the response with the session of it.
+    fn exchange(&mut self, typ: EyeRequestType) -> Result<(u64, EyeResponse), RuntimeError> {
+        let request = EyeRequest {
+            reader: self.name.clone(),
+            typ,
+            route: self.route.clone(),
+        };
+        let packet = Packet::seal(self.session, self.encoding, &request)?;
+        let packet = self.client.request(&packet)?;
+        Ok((packet.session, packet.open(self.encoding)?))
+    }
+
+    #[inline]
+    fn request(&mut self, typ: EyeRequestType) -> Result<EyeResponse, RuntimeError> {
+        match self.exchange(typ)? {
+            (_, EyeResponse::NoHandshake) => RuntimeError::expect("The session has been expired"),
+            (_, response) => Ok(response),
+        }
+    }
+
+    fn handshake(&mut self) -> Result<(), RuntimeError> {
+        let hello = Handshake {
+            encodings: self.encodings.clone(),
+            ..Default::default()
+        };
+        match self.exchange(EyeRequestType::Hello(hello))? {
+            (session, EyeResponse::Hello(hello)) => {
+                if hello.version != PROTOCOL_VERSION {
+                    return RuntimeError::message(format!(
+                        "Unsupported protocol: {}",
+                        hello.version
+                    ));
+                }
+                match hello.encodings.as_slice() {
+                    [encoding] if self.encodings.contains(encoding) && session != 0 => {
+                        self.session = session;
+                        self.encoding = *encoding;
+                        Ok(())
+                    }
+                    _ => RuntimeError::message(format!(
+                        "Unsupported encodings: {:?}",
+                        hello.encodings
+                    )),
+                }
+            }
+            (_, EyeResponse::Unsupported(version)) => {
+                RuntimeError::message(format!("Unsupported protocol: {}", version))
+            }
+            (_, EyeResponse::Failed(e)) => RuntimeError::message(e),
+            _ => RuntimeError::unexpected(),
+        }
+    }
+
+    fn get(&mut self) -> Result<Result<Frame, String>, RuntimeError> {
+        match self.request(EyeRequestType::Get)? {
+            EyeResponse::Frame(frame) => Ok(frame),
+            _ => RuntimeError::unexpected(),
+        }
+    }
+
     #[inline]
     fn inner_loop(mut self) -> Result<(), RuntimeError> {
-        if let EyeResponse::NoSuchReader(name) = self.client.request(&EyeRequest {
-            reader: self.name.clone(),
-            typ: EyeRequestType::Start,
-        })? {
-            return RuntimeError::message(format!("No such reader: {}", name));
+        let result = match self.handshake() {
+            Ok(()) => self.request(EyeRequestType::Start),
+            Err(e) => Err(e),
+        };
+        let result = match result {
+            Ok(EyeResponse::Awk) => Ok(()),
+            Ok(EyeResponse::NoSuchReader(name)) => {
+                RuntimeError::message(format!("No such reader: {}", name))
+            }
+            Ok(EyeResponse::RelayLoop(name)) => {
+                RuntimeError::message(format!("Relay loop detected: {}", name))
+            }
+            Ok(EyeResponse::Failed(e)) => RuntimeError::message(e),
+            Ok(_) => RuntimeError::unexpected(),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            self.alive.stop().ok();
+            return Err(e);
         }
 
         let mut uninit_meta = true;
@@ -68,22 +164,21 @@ impl Thread {
                 break Ok(());
             }
 
-            let frame = match self.client.request(&EyeRequest {
-                reader: self.name.clone(),
-                typ: EyeRequestType::Get,
-            })? {
+            let frame = match self.get()? {
+                Ok(frame) => frame,
                 // unexpected shutdown
-                EyeResponse::Frame(Ok(frame)) => frame,
-                EyeResponse::Frame(Err(_)) => break RuntimeError::expect("Internal error"),
-                _ => unreachable!(),
+                Err(_) => break RuntimeError::expect("Internal error"),
             };
 
             if uninit_meta {
-                self.meta.send(frame.meta)?;
+                self.meta.send(self.pipeline.meta(&frame.meta))?;
                 uninit_meta = false;
             }
 
-            let image = frame.image;
+            let mut image = frame.image;
+            if let Err(e) = self.pipeline.apply(&mut image) {
+                break Err(e);
+            }
             let timestamp = frame.timestamp;
             if let Err(e) = self.queue.push_inner_inplace(image, timestamp, false) {
                 break Err(e);
@@ -93,12 +188,9 @@ impl Thread {
         // graceful shutdown
         self.alive.stop().ok();
 
-        match self.client.request(&EyeRequest {
-            reader: self.name.clone(),
-            typ: EyeRequestType::Stop,
-        }) {
+        match self.request(EyeRequestType::Stop) {
             Ok(_) => result,
-            Err(e) => Err(e.into()),
+            Err(e) => Err(e),
         }
     }
 }
@@ -112,6 +204,7 @@ pub struct ClientCapture {
 
     name: String,
     config: ClientConfig,
+    pipeline: Arc<Pipeline>,
 }
 
 impl ClientCapture {
@@ -124,8 +217,15 @@ impl ClientCapture {
             meta: RwLock::new(None),
             name: name.to_string(),
             config,
+            pipeline: Default::default(),
         })
     }
+
+    /// Preprocesses the frames in the receiving thread.
+    pub fn with_pipeline(mut self, pipeline: Pipeline) -> Self {
+        self.pipeline = Arc::new(pipeline);
+        self
+    }
 }
 
 impl ClientCapture {
@@ -141,26 +241,55 @@ impl ClientCapture {
     }
 }
 
-impl VideoReader for ClientCapture {
-    fn start(&self) -> Result<(), RuntimeError> {
+impl ClientCapture {
+    fn start_with_route(&self, route: Vec<u64>) -> Result<(), RuntimeError> {
         self.alive.start()?;
 
         let (tx, rx) = mpsc::channel();
 
-        let t = Thread::new_thread(
+        let t = match Thread::new_thread(
             self.queue.clone(),
             self.alive.clone(),
             tx,
             &self.name,
+            route,
             &self.config,
-        )?;
+            self.pipeline.clone(),
+        ) {
+            Ok(t) => t,
+            Err(e) => {
+                self.alive.stop().ok();
+                return Err(e);
+            }
+        };
 
-        let meta = rx.recv()?;
+        let meta = match rx.recv() {
+            Ok(meta) => meta,
+            // the thread has been terminated before receiving any frame
+            Err(_) => {
+                self.alive.stop().ok();
+                return match t.join() {
+                    Ok(Ok(())) => RuntimeError::expect("Failed to receive VideoMeta"),
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => RuntimeError::unexpected(),
+                };
+            }
+        };
         *self.meta.write().unwrap() = Some(meta);
 
         self.thread.lock().unwrap().replace(t);
         Ok(())
     }
+}
+
+impl VideoReader for ClientCapture {
+    fn start(&self) -> Result<(), RuntimeError> {
+        self.start_with_route(vec![relay_id()])
+    }
+
+    fn start_relay(&self, route: &[u64]) -> Result<(), RuntimeError> {
+        self.start_with_route(route.to_vec())
+    }
 
     #[inline]
     fn stop(&self) -> Result<(), RuntimeError> {
@@ -181,7 +310,7 @@ impl VideoReader for ClientCapture {
 
     #[inline]
     fn is_export(&self) -> bool {
-        false
+        self.config.export.unwrap_or_default()
     }
 
     fn get(&self, frame: &mut Option<Frame>) -> Result<(), RuntimeError> {
@@ -195,11 +324,16 @@ impl VideoReader for ClientCapture {
         match self.alive.is_running() {
             true => self.queue.pop_inner(frame),
             false => match self.stop() {
-                Ok(()) => unreachable!(),
+                Ok(()) => RuntimeError::expect("The reader has been stopped"),
                 Err(e) => Err(e),
             },
         }
     }
+
+    #[inline]
+    fn intrinsics(&self) -> Option<Intrinsics> {
+        self.pipeline.intrinsics(None)
+    }
 }
 
 impl Drop for ClientCapture {
@@ -0,0 +1,386 @@
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread;
+
+use super::queue::Queue;
+use crate::calibration::Intrinsics;
+use crate::common::{ArcVideoReader, VideoReader};
+use crate::config::VideoMeta;
+use crate::frame::Frame;
+use crate::pipeline::Pipeline;
+
+use opencv::prelude::*;
+use podo_core_driver::*;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct DerivedConfig {
+    /// The name of the reader to consume.
+    pub(crate) upstream: String,
+    pub(crate) export: Option<bool>,
+}
+
+/// Starts the inner reader on the first `start`, and stops it on the last `stop`.
+///
+/// Every reader of `EyeDriver` is shared this way, so that each consumer and user
+/// holds a lease of its own by `start` and `stop`.
+pub struct SharedReader {
+    inner: ArcVideoReader,
+    count: Mutex<usize>,
+}
+
+impl SharedReader {
+    pub fn new(inner: ArcVideoReader) -> Self {
+        Self {
+            inner,
+            count: Mutex::new(0),
+        }
+    }
+}
+
+impl VideoReader for SharedReader {
+    fn start(&self) -> Result<(), RuntimeError> {
+        let mut count = self.count.lock().unwrap();
+        if *count == 0 {
+            self.inner.start()?;
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    #[cfg(feature = "simple-socket")]
+    fn start_relay(&self, route: &[u64]) -> Result<(), RuntimeError> {
+        let mut count = self.count.lock().unwrap();
+        if *count == 0 {
+            self.inner.start_relay(route)?;
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), RuntimeError> {
+        let mut count = self.count.lock().unwrap();
+        match *count {
+            // collects the result of a terminated reader
+            0 => self.inner.stop(),
+            1 => {
+                *count = 0;
+                self.inner.stop()
+            }
+            _ => {
+                *count -= 1;
+                Ok(())
+            }
+        }
+    }
+
+    #[inline]
+    fn is_running(&self) -> bool {
+        self.inner.is_running()
+    }
+
+    fn restart_exclusive(&self) -> Result<bool, RuntimeError> {
+        // no lease is taken nor given back while restarting
+        let count = self.count.lock().unwrap();
+        if *count != 1 {
+            return Ok(false);
+        }
+        self.inner.stop().ok();
+        self.inner.start()?;
+        Ok(true)
+    }
+
+    #[inline]
+    fn is_export(&self) -> bool {
+        self.inner.is_export()
+    }
+
+    #[inline]
+    fn get(&self, frame: &mut Option<Frame>) -> Result<(), RuntimeError> {
+        self.inner.get(frame)
+    }
+
+    #[inline]
+    fn intrinsics(&self) -> Option<Intrinsics> {
+        self.inner.intrinsics()
+    }
+}
+
+struct Thread {
+    upstream: ArcVideoReader,
+    pipeline: Arc<Pipeline>,
+    meta: mpsc::Sender<VideoMeta>,
+
+    queue: Arc<Queue>,
+    alive: AliveFlag,
+}
+
+impl Thread {
+    fn inner_loop(self) -> Result<(), RuntimeError> {
+        let mut frame = None;
+        let mut uninit_meta = true;
+        let result = loop {
+            // normal shutdown
+            if let false = self.alive.is_running() {
+                break Ok(());
+            }
+            // unexpected shutdown
+            if let Err(e) = self.upstream.get(&mut frame) {
+                break Err(e);
+            }
+            let frame: &Frame = frame.as_ref().unwrap();
+
+            if uninit_meta {
+                self.meta.send(self.pipeline.meta(&frame.meta)).ok();
+                uninit_meta = false;
+            }
+
+            let pipeline = &self.pipeline;
+            if let Err(e) = self.queue.push_inner(
+                |image| {
+                    frame.image.copy_to(&mut **image)?;
+                    pipeline.apply(image)
+                },
+                frame.timestamp,
+                false,
+            ) {
+                break Err(e);
+            }
+        };
+
+        // graceful shutdown
+        self.alive.stop().ok();
+        self.upstream.stop()?;
+        result
+    }
+}
+
+/// Transforms the frames of another reader, in a thread of its own.
+pub struct DerivedCapture {
+    queue: Arc<Queue>,
+    alive: AliveFlag,
+    thread: Mutex<Option<thread::JoinHandle<Result<(), RuntimeError>>>>,
+
+    meta: RwLock<Option<VideoMeta>>,
+    pipeline: Arc<Pipeline>,
+
+    upstream: ArcVideoReader,
+    config: DerivedConfig,
+}
+
+impl DerivedCapture {
+    /// Creates a reader of the upstream, which should be a `SharedReader`.
+    pub fn from_config(
+        config: DerivedConfig,
+        upstream: ArcVideoReader,
+    ) -> Result<Self, RuntimeError> {
+        let alive = AliveFlag::default();
+        Ok(Self {
+            queue: Arc::new(Queue::new(&alive, 2)?),
+            alive,
+            thread: Mutex::new(None),
+            meta: RwLock::new(None),
+            pipeline: Default::default(),
+            upstream,
+            config,
+        })
+    }
+
+    /// Transforms the frames with the pipeline.
+    pub fn with_pipeline(mut self, pipeline: Pipeline) -> Self {
+        self.pipeline = Arc::new(pipeline);
+        self
+    }
+
+    fn get_meta(&self) -> VideoMeta {
+        loop {
+            {
+                if let Some(meta) = &*self.meta.read().unwrap() {
+                    break meta.clone();
+                }
+            }
+            std::thread::yield_now();
+        }
+    }
+}
+
+impl VideoReader for DerivedCapture {
+    fn start(&self) -> Result<(), RuntimeError> {
+        self.alive.start()?;
+        if let Err(e) = self.upstream.start() {
+            self.alive.stop().ok();
+            return Err(e);
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let this = Thread {
+            upstream: self.upstream.clone(),
+            pipeline: self.pipeline.clone(),
+            meta: tx,
+            queue: self.queue.clone(),
+            alive: self.alive.clone(),
+        };
+        let t = thread::spawn(move || this.inner_loop());
+
+        let meta = match rx.recv() {
+            Ok(meta) => meta,
+            // the thread has been terminated before receiving any frame
+            Err(_) => {
+                self.alive.stop().ok();
+                return match t.join() {
+                    Ok(Ok(())) => RuntimeError::expect("Failed to receive VideoMeta"),
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => RuntimeError::unexpected(),
+                };
+            }
+        };
+        *self.meta.write().unwrap() = Some(meta);
+
+        self.thread.lock().unwrap().replace(t);
+        Ok(())
+    }
+
+    #[inline]
+    fn stop(&self) -> Result<(), RuntimeError> {
+        self.alive.stop().ok();
+        match self.thread.lock().unwrap().take() {
+            Some(thread) => match thread.join() {
+                Ok(res) => res,
+                Err(_) => RuntimeError::unexpected(),
+            },
+            None => Ok(()),
+        }
+    }
+
+    #[inline]
+    fn is_running(&self) -> bool {
+        self.alive.is_running()
+    }
+
+    #[inline]
+    fn is_export(&self) -> bool {
+        self.config.export.unwrap_or_default()
+    }
+
+    fn get(&self, frame: &mut Option<Frame>) -> Result<(), RuntimeError> {
+        let frame = match frame.as_mut() {
+            Some(frame) => frame,
+            None => {
+                frame.replace(Frame::new(self.get_meta())?);
+                frame.as_mut().unwrap()
+            }
+        };
+        match self.alive.is_running() {
+            true => self.queue.pop_inner(frame),
+            false => match self.stop() {
+                Ok(()) => RuntimeError::expect("The reader has been stopped"),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    #[inline]
+    fn intrinsics(&self) -> Option<Intrinsics> {
+        self.pipeline.intrinsics(self.upstream.intrinsics())
+    }
+}
+
+impl Drop for DerivedCapture {
+    fn drop(&mut self) {
+        self.stop().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts the starts & the stops, and fails to start twice like the sources.
+    #[derive(Default)]
+    struct Counter {
+        alive: AliveFlag,
+        starts: AtomicUsize,
+        stops: AtomicUsize,
+    }
+
+    impl VideoReader for Counter {
+        fn start(&self) -> Result<(), RuntimeError> {
+            self.alive.start()?;
+            self.starts.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn stop(&self) -> Result<(), RuntimeError> {
+            if self.alive.is_running() {
+                self.alive.stop().ok();
+                self.stops.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(())
+        }
+
+        fn is_running(&self) -> bool {
+            self.alive.is_running()
+        }
+
+        fn is_export(&self) -> bool {
+            false
+        }
+
+        fn get(&self, _frame: &mut Option<Frame>) -> Result<(), RuntimeError> {
+            RuntimeError::unimplemented()
+        }
+    }
+
+    #[test]
+    fn shared_reader_leases() {
+        let inner = Arc::new(Counter::default());
+        let shared = SharedReader::new(inner.clone());
+        let count = |counter: &AtomicUsize| counter.load(Ordering::SeqCst);
+
+        // a derived reader, and then a recorder
+        shared.start().unwrap();
+        shared.start().unwrap();
+        assert_eq!(count(&inner.starts), 1);
+
+        // the derived reader stops under the recorder
+        shared.stop().unwrap();
+        assert!(shared.is_running());
+        assert_eq!(count(&inner.stops), 0);
+
+        shared.stop().unwrap();
+        assert!(!shared.is_running());
+        assert_eq!(count(&inner.stops), 1);
+
+        // an unbalanced stop does not take another lease away
+        shared.stop().unwrap();
+        shared.start().unwrap();
+        assert!(shared.is_running());
+        assert_eq!(count(&inner.starts), 2);
+        shared.stop().unwrap();
+        assert_eq!(count(&inner.stops), 2);
+    }
+
+    #[test]
+    fn shared_reader_restart() {
+        let inner = Arc::new(Counter::default());
+        let shared = SharedReader::new(inner.clone());
+        let count = |counter: &AtomicUsize| counter.load(Ordering::SeqCst);
+
+        // nothing to restart without a lease
+        assert!(!shared.restart_exclusive().unwrap());
+
+        shared.start().unwrap();
+        assert!(shared.restart_exclusive().unwrap());
+        assert_eq!((count(&inner.starts), count(&inner.stops)), (2, 1));
+
+        // the other consumer keeps reading
+        shared.start().unwrap();
+        assert!(!shared.restart_exclusive().unwrap());
+        assert_eq!(count(&inner.starts), 2);
+
+        shared.stop().unwrap();
+        shared.stop().unwrap();
+        assert!(!shared.is_running());
+    }
+}
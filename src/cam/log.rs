@@ -0,0 +1,235 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::queue::Queue;
+use crate::calibration::Intrinsics;
+use crate::common::VideoReader;
+use crate::config::VideoMeta;
+use crate::frame::Frame;
+use crate::pipeline::Pipeline;
+use crate::record::log::LogFile;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use podo_core_driver::*;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct LogConfig {
+    pub(crate) path: String,
+    pub(crate) stream: String,
+    /// Skips the given seconds from the beginning of the log.
+    pub(crate) from_secs: Option<f64>,
+    pub(crate) export: Option<bool>,
+}
+
+/// Shares the beginning of a replay among the streams of a log,
+/// to keep their relative timing.
+///
+/// The streams started during a replay follow the position of the first one,
+/// regardless of their own `from_secs`.
+#[derive(Default)]
+pub struct LogClock {
+    inner: Mutex<(usize, Option<(DateTime<Utc>, DateTime<Utc>)>)>,
+}
+
+impl LogClock {
+    /// Returns the time when the replay has begun, and the timestamp of the log at that time.
+    fn acquire(&self, since: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+        let (count, begin) = &mut *self.inner.lock().unwrap();
+        *count += 1;
+        *begin.get_or_insert_with(|| (Utc::now(), since))
+    }
+
+    fn release(&self) {
+        let (count, begin) = &mut *self.inner.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            begin.take();
+        }
+    }
+}
+
+struct Thread {
+    file: LogFile,
+    stream: u32,
+    since: DateTime<Utc>,
+    clock: Arc<LogClock>,
+    pipeline: Arc<Pipeline>,
+
+    queue: Arc<Queue>,
+    alive: AliveFlag,
+}
+
+impl Thread {
+    fn inner_loop(mut self) -> Result<(), RuntimeError> {
+        let (begin, since) = self.clock.acquire(self.since);
+        let entries = self.file.seek(self.stream, since);
+
+        let mut entries = entries.iter();
+        let result = loop {
+            // normal shutdown
+            if let false = self.alive.is_running() {
+                break Ok(());
+            }
+            let entry = match entries.next() {
+                Some(entry) => entry,
+                None => break RuntimeError::expect("The replay is over"),
+            };
+
+            // a late frame is not waited
+            let due = begin + (entry.timestamp - since);
+            if let Ok(time) = (due - Utc::now()).to_std() {
+                thread::sleep(time);
+            }
+
+            // unexpected shutdown
+            let mut frame = match self.file.read_frame(entry) {
+                Ok(frame) => frame,
+                Err(e) => break Err(e),
+            };
+            if let Err(e) = self.pipeline.apply(&mut frame.image) {
+                break Err(e);
+            }
+            if let Err(e) = self
+                .queue
+                .push_inner_inplace(frame.image, entry.timestamp, false)
+            {
+                break Err(e);
+            }
+        };
+
+        // graceful shutdown
+        self.clock.release();
+        self.alive.stop().ok();
+        result
+    }
+}
+
+pub struct LogCapture {
+    queue: Arc<Queue>,
+    alive: AliveFlag,
+    thread: Mutex<Option<thread::JoinHandle<Result<(), RuntimeError>>>>,
+
+    meta: VideoMeta,
+    clock: Arc<LogClock>,
+    pipeline: Arc<Pipeline>,
+
+    config: LogConfig,
+    path: PathBuf,
+}
+
+impl LogCapture {
+    pub fn from_config<P: AsRef<Path>>(
+        config: LogConfig,
+        path: P,
+        clock: Arc<LogClock>,
+    ) -> Result<Self, RuntimeError> {
+        let mut path = path.as_ref().to_path_buf();
+        path.push(&config.path);
+
+        let meta = match LogFile::open(&path)?.stream(&config.stream) {
+            Some(stream) => stream.meta.clone(),
+            None => return RuntimeError::message(format!("No such stream: {}", &config.stream)),
+        };
+
+        let alive = AliveFlag::default();
+        Ok(Self {
+            queue: Arc::new(Queue::new(&alive, 2)?),
+            alive,
+            thread: Mutex::new(None),
+            meta,
+            clock,
+            pipeline: Default::default(),
+            config,
+            path,
+        })
+    }
+
+    /// Preprocesses the frames in the replay thread.
+    pub fn with_pipeline(mut self, pipeline: Pipeline) -> Self {
+        self.pipeline = Arc::new(pipeline);
+        self
+    }
+}
+
+impl VideoReader for LogCapture {
+    fn start(&self) -> Result<(), RuntimeError> {
+        let file = LogFile::open(&self.path)?;
+        let stream = match file.stream(&self.config.stream) {
+            Some(stream) => stream.id,
+            None => {
+                return RuntimeError::message(format!("No such stream: {}", &self.config.stream))
+            }
+        };
+        let origin = file.origin().unwrap_or_else(Utc::now);
+        let from = self.config.from_secs.unwrap_or_default();
+        let since = origin + Duration::microseconds((from * 1_000_000_f64) as i64);
+
+        self.alive.start()?;
+        let this = Thread {
+            file,
+            stream,
+            since,
+            clock: self.clock.clone(),
+            pipeline: self.pipeline.clone(),
+            queue: self.queue.clone(),
+            alive: self.alive.clone(),
+        };
+        let t = thread::spawn(move || this.inner_loop());
+        self.thread.lock().unwrap().replace(t);
+        Ok(())
+    }
+
+    #[inline]
+    fn stop(&self) -> Result<(), RuntimeError> {
+        self.alive.stop().ok();
+        match self.thread.lock().unwrap().take() {
+            Some(thread) => match thread.join() {
+                Ok(res) => res,
+                Err(_) => RuntimeError::unexpected(),
+            },
+            None => Ok(()),
+        }
+    }
+
+    #[inline]
+    fn is_running(&self) -> bool {
+        self.alive.is_running()
+    }
+
+    #[inline]
+    fn is_export(&self) -> bool {
+        self.config.export.unwrap_or_default()
+    }
+
+    fn get(&self, frame: &mut Option<Frame>) -> Result<(), RuntimeError> {
+        let frame = match frame.as_mut() {
+            Some(frame) => frame,
+            None => {
+                frame.replace(Frame::new(self.pipeline.meta(&self.meta))?);
+                frame.as_mut().unwrap()
+            }
+        };
+        match self.alive.is_running() {
+            true => self.queue.pop_inner(frame),
+            false => match self.stop() {
+                Ok(()) => RuntimeError::expect("The reader has been stopped"),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    #[inline]
+    fn intrinsics(&self) -> Option<Intrinsics> {
+        self.pipeline.intrinsics(None)
+    }
+}
+
+impl Drop for LogCapture {
+    fn drop(&mut self) {
+        // the end of the replay is not an error here
+        self.stop().ok();
+    }
+}
@@ -1,12 +1,28 @@
 mod capture;
 #[cfg(feature = "simple-socket")]
 mod client;
+mod derived;
+mod log;
+mod mosaic;
+mod panorama;
 mod queue;
 mod rtsp;
+mod sequence;
+mod stereo;
+mod sync;
 mod video;
 
 pub use self::capture::{CamConfig, VideoCapture};
 #[cfg(feature = "simple-socket")]
 pub use self::client::{ClientCapture, ClientConfig};
+pub use self::derived::{DerivedCapture, DerivedConfig, SharedReader};
+pub use self::log::{LogCapture, LogClock, LogConfig};
+pub use self::mosaic::{MosaicCapture, MosaicConfig, MosaicLayout, MosaicTile};
+pub use self::panorama::{
+    PanoramaCapture, PanoramaConfig, PanoramaExposure, PanoramaMode, PanoramaSeam,
+};
 pub use self::rtsp::RtspConfig;
+pub use self::sequence::{SequenceCapture, SequenceConfig};
+pub use self::stereo::{FramePair, StereoCapture, StereoConfig, StereoRectifyConfig, StereoStats};
+pub use self::sync::{SyncCapture, SyncGroup};
 pub use self::video::VideoConfig;
@@ -0,0 +1,407 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::queue::Queue;
+use crate::common::{ArcVideoReader, VideoReader};
+use crate::config::{VideoColor, VideoMeta};
+use crate::frame::{Frame, Image};
+use crate::overlay::draw_text;
+use crate::pipeline::Pipeline;
+
+use chrono::prelude::*;
+use opencv::core::{Point, Rect, Scalar, Size, CV_8UC3};
+use opencv::imgproc;
+use opencv::prelude::*;
+use podo_core_driver::*;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct MosaicConfig {
+    /// The names of the readers to show, in the row-major order.
+    pub(crate) inputs: Vec<String>,
+    /// The grid of the tiles, as square as possible by default.
+    pub(crate) layout: Option<MosaicLayout>,
+    pub(crate) tile: MosaicTile,
+    pub(crate) fps: u32,
+    /// Draws the name of the reader on the top of each tile.
+    pub(crate) labels: Option<bool>,
+    /// Draws the timestamp of the frame on the bottom of each tile.
+    pub(crate) timestamps: Option<bool>,
+    pub(crate) export: Option<bool>,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct MosaicLayout {
+    pub(crate) cols: u32,
+    pub(crate) rows: u32,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+pub struct MosaicTile {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+impl MosaicConfig {
+    fn layout(&self) -> MosaicLayout {
+        match self.layout {
+            Some(layout) => layout,
+            None => {
+                let count = self.inputs.len() as u32;
+                let cols = (count as f64).sqrt().ceil() as u32;
+                MosaicLayout {
+                    cols,
+                    rows: (count + cols - 1) / cols,
+                }
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), RuntimeError> {
+        if self.inputs.is_empty() {
+            return RuntimeError::expect("The mosaic should have an input at least");
+        }
+        let layout = self.layout();
+        if ((layout.cols * layout.rows) as usize) < self.inputs.len() {
+            return RuntimeError::expect("The layout should have a tile for each input");
+        }
+        if self.tile.width == 0 || self.tile.height == 0 || self.fps == 0 {
+            return RuntimeError::expect("The tile size and the fps should not be zero");
+        }
+        Ok(())
+    }
+
+    fn meta(&self) -> VideoMeta {
+        let layout = self.layout();
+        VideoMeta {
+            codec: None,
+            color: Some(VideoColor::Color),
+            width: layout.cols * self.tile.width,
+            height: layout.rows * self.tile.height,
+            fps: self.fps,
+        }
+    }
+}
+
+/// The latest frame of an input, or none while the input is failed.
+#[derive(Default)]
+struct Latest {
+    inner: Mutex<Option<(Mat, DateTime<Utc>)>>,
+}
+
+impl Latest {
+    fn put(&self, frame: &Frame) -> Result<(), RuntimeError> {
+        let mut latest = self.inner.lock().unwrap();
+        match latest.as_mut() {
+            Some((image, timestamp)) => {
+                frame.image.copy_to(image)?;
+                *timestamp = frame.timestamp;
+            }
+            None => {
+                let mut image = Mat::default()?;
+                frame.image.copy_to(&mut image)?;
+                latest.replace((image, frame.timestamp));
+            }
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn clear(&self) {
+        self.inner.lock().unwrap().take();
+    }
+}
+
+/// Keeps the latest frame of an input, so that no tick waits for it.
+fn feed(input: ArcVideoReader, latest: Arc<Latest>, alive: AliveFlag, retry: Duration) {
+    let mut frame = None;
+    while alive.is_running() {
+        // a failed input is not read until it runs again
+        if !input.is_running() {
+            latest.clear();
+            thread::sleep(retry);
+            continue;
+        }
+        let result = input
+            .get(&mut frame)
+            .and_then(|()| latest.put(frame.as_ref().unwrap()));
+        if result.is_err() {
+            latest.clear();
+            frame = None;
+        }
+    }
+}
+
+struct Thread {
+    inputs: Vec<(String, ArcVideoReader)>,
+    layout: MosaicLayout,
+    tile: MosaicTile,
+    labels: bool,
+    timestamps: bool,
+    pipeline: Arc<Pipeline>,
+
+    queue: Arc<Queue>,
+    alive: AliveFlag,
+    us_per_frame: i64,
+}
+
+impl Thread {
+    fn inner_loop(self) -> Result<(), RuntimeError> {
+        let retry = Duration::from_micros(self.us_per_frame as u64);
+        let latest: Vec<Arc<Latest>> = self.inputs.iter().map(|_| Default::default()).collect();
+
+        // the feeders are detached, as a stalled input may never return from its get,
+        // and they have a flag of their own not to be revived by the next start
+        let feeding = AliveFlag::new(true);
+        for ((_, input), latest) in self.inputs.iter().zip(&latest) {
+            let (input, latest, alive) = (input.clone(), latest.clone(), feeding.clone());
+            thread::spawn(move || feed(input, latest, alive, retry));
+        }
+
+        let result = loop {
+            // normal shutdown
+            if let false = self.alive.is_running() {
+                break Ok(());
+            }
+            let timestamp = Utc::now();
+
+            // unexpected shutdown
+            if let Err(e) = self.queue.push_inner(
+                |image| {
+                    self.compose(&latest, image)?;
+                    self.pipeline.apply(image)
+                },
+                timestamp,
+                false,
+            ) {
+                break Err(e);
+            }
+
+            // spend unused time to keep the fps
+            let time_us = self.us_per_frame
+                - (Utc::now() - timestamp)
+                    .num_microseconds()
+                    .unwrap_or(self.us_per_frame);
+            if time_us > 0 {
+                thread::sleep(Duration::from_micros(time_us as u64));
+            }
+        };
+
+        // graceful shutdown
+        self.alive.stop().ok();
+        feeding.stop().ok();
+        let stopped: Vec<_> = self.inputs.iter().map(|(_, input)| input.stop()).collect();
+        stopped.into_iter().collect::<Result<(), _>>()?;
+        result
+    }
+
+    fn compose(&self, latest: &[Arc<Latest>], image: &mut Image) -> Result<(), RuntimeError> {
+        let (width, height) = (self.tile.width as i32, self.tile.height as i32);
+        let mut canvas = Mat::new_rows_cols_with_default(
+            self.layout.rows as i32 * height,
+            self.layout.cols as i32 * width,
+            CV_8UC3,
+            Scalar::default(),
+        )?;
+
+        for (index, ((name, _), latest)) in self.inputs.iter().zip(latest).enumerate() {
+            let mut tile = Mat::default()?;
+            let timestamp = match &*latest.inner.lock().unwrap() {
+                Some((image, timestamp)) => {
+                    let size = Size::new(width, height);
+                    imgproc::resize(image, &mut tile, size, 0., 0., imgproc::INTER_AREA)?;
+                    VideoColor::Color.convert(&mut tile)?;
+                    Some(*timestamp)
+                }
+                None => {
+                    tile = no_signal(width, height)?;
+                    None
+                }
+            };
+
+            if self.labels {
+                draw_text(&mut tile, name, Point::new(6, 18), 0.5, Scalar::all(255.))?;
+            }
+            if let Some(timestamp) = timestamp.filter(|_| self.timestamps) {
+                let text = timestamp.format("%H:%M:%S%.3f").to_string();
+                let origin = Point::new(6, height - 8);
+                draw_text(&mut tile, &text, origin, 0.5, Scalar::all(255.))?;
+            }
+
+            let cols = self.layout.cols as usize;
+            let (col, row) = ((index % cols) as i32, (index / cols) as i32);
+            let roi = Rect::new(col * width, row * height, width, height);
+            tile.copy_to(&mut Mat::roi(&canvas, roi)?)?;
+        }
+        *image = Image::from(canvas);
+        Ok(())
+    }
+}
+
+/// Returns a gray tile with "NO SIGNAL" in the middle.
+fn no_signal(width: i32, height: i32) -> Result<Mat, RuntimeError> {
+    const TEXT: &str = "NO SIGNAL";
+
+    let mut tile = Mat::new_rows_cols_with_default(height, width, CV_8UC3, Scalar::all(64.))?;
+    let mut baseline = 0;
+    let font = imgproc::FONT_HERSHEY_SIMPLEX;
+    let size = imgproc::get_text_size(TEXT, font, 0.6, 1, &mut baseline)?;
+    let origin = Point::new((width - size.width) / 2, (height + size.height) / 2);
+    draw_text(&mut tile, TEXT, origin, 0.6, Scalar::all(255.))?;
+    Ok(tile)
+}
+
+/// Tiles the frames of other readers into a grid, at its own fps.
+///
+/// Each tile shows the latest frame of its input, or "NO SIGNAL" while the input is failed.
+pub struct MosaicCapture {
+    queue: Arc<Queue>,
+    alive: AliveFlag,
+    thread: Mutex<Option<thread::JoinHandle<Result<(), RuntimeError>>>>,
+
+    meta: VideoMeta,
+    pipeline: Arc<Pipeline>,
+
+    inputs: Vec<ArcVideoReader>,
+    config: MosaicConfig,
+}
+
+impl MosaicCapture {
+    /// Creates a reader of the inputs, which should be `SharedReader`s.
+    pub fn from_config(
+        config: MosaicConfig,
+        inputs: Vec<ArcVideoReader>,
+    ) -> Result<Self, RuntimeError> {
+        config.validate()?;
+        let alive = AliveFlag::default();
+        Ok(Self {
+            queue: Arc::new(Queue::new(&alive, 2)?),
+            alive,
+            thread: Mutex::new(None),
+            meta: config.meta(),
+            pipeline: Default::default(),
+            inputs,
+            config,
+        })
+    }
+
+    /// Transforms the composed frames with the pipeline.
+    pub fn with_pipeline(mut self, pipeline: Pipeline) -> Self {
+        self.pipeline = Arc::new(pipeline);
+        self
+    }
+}
+
+impl VideoReader for MosaicCapture {
+    fn start(&self) -> Result<(), RuntimeError> {
+        self.alive.start()?;
+        for (index, input) in self.inputs.iter().enumerate() {
+            if let Err(e) = input.start() {
+                self.alive.stop().ok();
+                for input in &self.inputs[..index] {
+                    input.stop().ok();
+                }
+                return Err(e);
+            }
+        }
+
+        let this = Thread {
+            inputs: self
+                .config
+                .inputs
+                .iter()
+                .cloned()
+                .zip(self.inputs.iter().cloned())
+                .collect(),
+            layout: self.config.layout(),
+            tile: self.config.tile,
+            labels: self.config.labels.unwrap_or_default(),
+            timestamps: self.config.timestamps.unwrap_or_default(),
+            pipeline: self.pipeline.clone(),
+            queue: self.queue.clone(),
+            alive: self.alive.clone(),
+            us_per_frame: 1_000_000 / self.config.fps as i64,
+        };
+        let t = thread::spawn(move || this.inner_loop());
+        self.thread.lock().unwrap().replace(t);
+        Ok(())
+    }
+
+    #[inline]
+    fn stop(&self) -> Result<(), RuntimeError> {
+        self.alive.stop().ok();
+        match self.thread.lock().unwrap().take() {
+            Some(thread) => match thread.join() {
+                Ok(res) => res,
+                Err(_) => RuntimeError::unexpected(),
+            },
+            None => Ok(()),
+        }
+    }
+
+    #[inline]
+    fn is_running(&self) -> bool {
+        self.alive.is_running()
+    }
+
+    #[inline]
+    fn is_export(&self) -> bool {
+        self.config.export.unwrap_or_default()
+    }
+
+    fn get(&self, frame: &mut Option<Frame>) -> Result<(), RuntimeError> {
+        let frame = match frame.as_mut() {
+            Some(frame) => frame,
+            None => {
+                frame.replace(Frame::new(self.pipeline.meta(&self.meta))?);
+                frame.as_mut().unwrap()
+            }
+        };
+        match self.alive.is_running() {
+            true => self.queue.pop_inner(frame),
+            false => match self.stop() {
+                Ok(()) => RuntimeError::expect("The reader has been stopped"),
+                Err(e) => Err(e),
+            },
+        }
+    }
+}
+
+impl Drop for MosaicCapture {
+    fn drop(&mut self) {
+        self.stop().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mosaic_layout() {
+        let config: MosaicConfig = serde_yaml::from_str(
+            "
+            inputs: [a, b, c, d, e]
+            tile: { width: 320, height: 240 }
+            fps: 10
+            ",
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        let meta = config.meta();
+        assert_eq!((meta.width, meta.height), (960, 480));
+
+        let config: MosaicConfig = serde_yaml::from_str(
+            "
+            inputs: [a, b, c]
+            layout: { cols: 2, rows: 1 }
+            tile: { width: 320, height: 240 }
+            fps: 10
+            ",
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+}
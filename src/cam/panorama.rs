@@ -0,0 +1,790 @@
+use std::fmt;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread;
+
+use super::queue::Queue;
+use crate::calibration::write_matrix;
+use crate::common::{ArcVideoReader, Lease, VideoReader};
+use crate::config::{VideoColor, VideoMeta};
+use crate::frame::{Frame, Image};
+use crate::pipeline::{find_homography, Pipeline};
+
+use opencv::core::{self, FileStorage, Ptr, Scalar, Size, CV_32F, CV_64F, CV_8U, CV_8UC1};
+use opencv::features2d::{self, BFMatcher, ORB};
+use opencv::imgproc;
+use opencv::prelude::*;
+use opencv::stitching::{
+    self, Detail_ExposureCompensator, Detail_SeamFinder, Stitcher, Stitcher_Mode, Stitcher_Status,
+};
+use opencv::types::{VectorOfDMatch, VectorOfKeyPoint, VectorOfMat};
+use podo_core_driver::*;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct PanoramaConfig {
+    /// The names of the readers to stitch, from the left to the right.
+    pub(crate) inputs: Vec<String>,
+    /// The homographies of the inputs, relative to the config file.
+    ///
+    /// The warp is estimated with the first frames, if not given.
+    /// The inputs are blended by feathering, without the mode, the seam and the exposure.
+    pub(crate) homographies: Option<String>,
+    pub(crate) mode: Option<PanoramaMode>,
+    pub(crate) seam: Option<PanoramaSeam>,
+    pub(crate) exposure: Option<PanoramaExposure>,
+    pub(crate) export: Option<bool>,
+}
+
+impl PanoramaConfig {
+    fn validate(&self) -> Result<(), RuntimeError> {
+        if self.inputs.len() < 2 {
+            return RuntimeError::expect("The panorama should have 2 inputs at least");
+        }
+        // the fixed homographies are not given to the stitcher
+        let is_stitcher = self.mode.is_some() || self.seam.is_some() || self.exposure.is_some();
+        if self.homographies.is_some() && is_stitcher {
+            return RuntimeError::expect(
+                "The mode, the seam and the exposure are not applied with the homographies",
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PanoramaMode {
+    /// Rotating cameras, warped onto a sphere.
+    Panorama,
+    /// Flat scenes, warped by affine transforms.
+    Scans,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PanoramaSeam {
+    None,
+    Voronoi,
+    /// Finds the seams by dynamic programming, along the similar colors.
+    Dp,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PanoramaExposure {
+    None,
+    Gain,
+    GainBlocks,
+    Channels,
+    ChannelsBlocks,
+}
+
+/// Fixed homographies onto the panorama, in the file format of OpenCV.
+///
+/// ```yaml
+/// %YAML:1.0
+/// ---
+/// image_width: 1600
+/// image_height: 480
+/// homography_0: !!opencv-matrix
+///    rows: 3
+///    cols: 3
+///    dt: d
+///    data: [ ... ]
+/// homography_1: ...
+/// ```
+struct Homographies {
+    warps: Vec<Mat>,
+    size: Size,
+}
+
+// the homographies are only read after being loaded
+unsafe impl Send for Homographies {}
+unsafe impl Sync for Homographies {}
+
+impl fmt::Debug for Homographies {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Homographies")
+            .field("count", &self.warps.len())
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+impl Homographies {
+    fn load<P: AsRef<Path>>(path: P, count: usize) -> Result<Self, RuntimeError> {
+        let filename = match path.as_ref().to_str() {
+            Some(filename) => filename,
+            None => return RuntimeError::expect("The path should be a valid UTF-8 string"),
+        };
+        let fs = FileStorage::new(filename, core::FileStorage_READ, "")?;
+        if !fs.is_opened()? {
+            return RuntimeError::message(format!("Failed to open homographies {:?}", filename));
+        }
+
+        let warps = (0..count)
+            .map(|index| {
+                let node = fs.get(&format!("homography_{}", index))?;
+                if node.empty()? {
+                    return RuntimeError::message(format!("No such homography: {}", index));
+                }
+                let mut warp = Mat::default()?;
+                node.mat()?.convert_to(&mut warp, core::CV_64F, 1., 0.)?;
+                Ok(warp)
+            })
+            .collect::<Result<_, RuntimeError>>()?;
+        let size = Size::new(
+            fs.get("image_width")?.to_i32()?,
+            fs.get("image_height")?.to_i32()?,
+        );
+        if size.width <= 0 || size.height <= 0 {
+            return RuntimeError::expect("The homographies should have the image size");
+        }
+        Ok(Self { warps, size })
+    }
+
+    /// Estimates the homographies onto the first image, matching the features of the neighbors.
+    fn estimate(images: &[Mat]) -> Result<Self, RuntimeError> {
+        let mut orb = <dyn ORB>::create(
+            2000,
+            1.2,
+            8,
+            31,
+            0,
+            2,
+            features2d::ORB_ScoreType::HARRIS_SCORE,
+            31,
+            20,
+        )?;
+        let features = images
+            .iter()
+            .map(|image| {
+                let mut keypoints = VectorOfKeyPoint::new();
+                let mut descriptors = Mat::default()?;
+                orb.detect_and_compute(
+                    image,
+                    &core::no_array()?,
+                    &mut keypoints,
+                    &mut descriptors,
+                    false,
+                )?;
+                let points: Vec<_> = keypoints
+                    .iter()
+                    .map(|keypoint| [keypoint.pt.x, keypoint.pt.y])
+                    .collect();
+                Ok((points, descriptors))
+            })
+            .collect::<Result<Vec<_>, RuntimeError>>()?;
+
+        let matcher = BFMatcher::new(core::NORM_HAMMING, true)?;
+        let mut warps = vec![IDENTITY];
+        for pair in features.windows(2) {
+            let ((last_points, last_descriptors), (points, descriptors)) = (&pair[0], &pair[1]);
+            let mut matches = VectorOfDMatch::new();
+            matcher.train_match(
+                descriptors,
+                last_descriptors,
+                &mut matches,
+                &core::no_array()?,
+            )?;
+            let (src, dst): (Vec<_>, Vec<_>) = matches
+                .iter()
+                .map(|m| {
+                    (
+                        points[m.query_idx as usize],
+                        last_points[m.train_idx as usize],
+                    )
+                })
+                .unzip();
+            let homography = find_homography(&src, &dst)?;
+            let warp = multiply(warps.last().unwrap(), &homography);
+            warps.push(warp);
+        }
+
+        let sizes: Vec<_> = images
+            .iter()
+            .map(|image| (image.cols(), image.rows()))
+            .collect();
+        Self::fit(&warps, &sizes)
+    }
+
+    /// Translates the warps onto the bounds of the warped images.
+    fn fit(warps: &[[f64; 9]], sizes: &[(i32, i32)]) -> Result<Self, RuntimeError> {
+        let (mut min, mut max) = ([f64::MAX; 2], [f64::MIN; 2]);
+        for (warp, &(width, height)) in warps.iter().zip(sizes) {
+            let (width, height) = (width as f64, height as f64);
+            for &(x, y) in &[(0., 0.), (width, 0.), (0., height), (width, height)] {
+                let w = warp[6] * x + warp[7] * y + warp[8];
+                if w <= 0. {
+                    return RuntimeError::expect("The estimated warp should not be degenerate");
+                }
+                let u = (warp[0] * x + warp[1] * y + warp[2]) / w;
+                let v = (warp[3] * x + warp[4] * y + warp[5]) / w;
+                min = [min[0].min(u), min[1].min(v)];
+                max = [max[0].max(u), max[1].max(v)];
+            }
+        }
+
+        // a wild warp would allocate a huge panorama
+        let limit: i32 = sizes.iter().map(|(width, height)| width.max(height)).sum();
+        let (width, height) = ((max[0] - min[0]).ceil(), (max[1] - min[1]).ceil());
+        if width > 2. * limit as f64 || height > 2. * limit as f64 {
+            return RuntimeError::expect("The estimated warp should not be degenerate");
+        }
+
+        let shift = [1., 0., -min[0], 0., 1., -min[1], 0., 0., 1.];
+        let warps = warps
+            .iter()
+            .map(|warp| to_mat(&multiply(&shift, warp)))
+            .collect::<Result<_, RuntimeError>>()?;
+        Ok(Self {
+            warps,
+            size: Size::new(width as i32, height as i32),
+        })
+    }
+
+    fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), RuntimeError> {
+        let mut text = String::from("%YAML:1.0\n---\n");
+        writeln!(text, "image_width: {}", self.size.width).unwrap();
+        writeln!(text, "image_height: {}", self.size.height).unwrap();
+        for (index, warp) in self.warps.iter().enumerate() {
+            write_matrix(&mut text, &format!("homography_{}", index), warp)?;
+        }
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// Returns the normalized weights of the inputs, feathered towards their borders.
+    fn weights(&self, images: &[Mat]) -> Result<Vec<Mat>, RuntimeError> {
+        let mut total = Mat::new_rows_cols_with_default(
+            self.size.height,
+            self.size.width,
+            CV_32F,
+            Scalar::all(1e-6),
+        )?;
+        let mut distances = vec![];
+        for (image, warp) in images.iter().zip(&self.warps) {
+            let ones = Mat::new_rows_cols_with_default(
+                image.rows(),
+                image.cols(),
+                CV_8UC1,
+                Scalar::all(255.),
+            )?;
+            let mut mask = Mat::default()?;
+            imgproc::warp_perspective(
+                &ones,
+                &mut mask,
+                warp,
+                self.size,
+                imgproc::INTER_NEAREST,
+                core::BORDER_CONSTANT,
+                Scalar::default(),
+            )?;
+            let mut distance = Mat::default()?;
+            imgproc::distance_transform(&mask, &mut distance, imgproc::DIST_L2, 3, CV_32F)?;
+            imgproc::accumulate(&distance, &mut total, &core::no_array()?)?;
+            distances.push(distance);
+        }
+
+        distances
+            .iter()
+            .map(|distance| {
+                let mut weight = Mat::default()?;
+                core::divide2(distance, &total, &mut weight, 1., -1)?;
+                let mut channels = VectorOfMat::new();
+                for _ in 0..3 {
+                    channels.push(Mat::copy(&weight)?);
+                }
+                let mut weight3 = Mat::default()?;
+                core::merge(&channels, &mut weight3)?;
+                Ok(weight3)
+            })
+            .collect()
+    }
+}
+
+const IDENTITY: [f64; 9] = [1., 0., 0., 0., 1., 0., 0., 0., 1.];
+
+fn multiply(a: &[f64; 9], b: &[f64; 9]) -> [f64; 9] {
+    let mut product = [0.; 9];
+    for (index, value) in product.iter_mut().enumerate() {
+        let (row, col) = (index / 3, index % 3);
+        *value = (0..3).map(|k| a[row * 3 + k] * b[k * 3 + col]).sum();
+    }
+    product
+}
+
+fn to_mat(warp: &[f64; 9]) -> Result<Mat, RuntimeError> {
+    let mut mat = Mat::new_rows_cols_with_default(3, 3, CV_64F, Scalar::all(0.))?;
+    for (index, value) in warp.iter().enumerate() {
+        *mat.at_2d_mut::<f64>(index as i32 / 3, index as i32 % 3)? = *value;
+    }
+    Ok(mat)
+}
+
+/// The state of the warp, owned by the thread.
+enum Warper {
+    Stitcher {
+        stitcher: Ptr<Stitcher>,
+        estimated: bool,
+    },
+    Homographies {
+        homographies: Arc<Homographies>,
+        weights: Vec<Mat>,
+    },
+}
+
+// the stitcher is used by the thread only
+unsafe impl Send for Warper {}
+
+impl Warper {
+    fn stitcher(config: &PanoramaConfig) -> Result<Self, RuntimeError> {
+        let mode = match config.mode.unwrap_or(PanoramaMode::Panorama) {
+            PanoramaMode::Panorama => Stitcher_Mode::PANORAMA,
+            PanoramaMode::Scans => Stitcher_Mode::SCANS,
+        };
+        let seam = match config.seam.unwrap_or(PanoramaSeam::Voronoi) {
+            PanoramaSeam::None => stitching::Detail_SeamFinder_NO,
+            PanoramaSeam::Voronoi => stitching::Detail_SeamFinder_VORONOI_SEAM,
+            PanoramaSeam::Dp => stitching::Detail_SeamFinder_DP_SEAM,
+        };
+        let exposure = match config.exposure.unwrap_or(PanoramaExposure::Gain) {
+            PanoramaExposure::None => stitching::Detail_ExposureCompensator_NO,
+            PanoramaExposure::Gain => stitching::Detail_ExposureCompensator_GAIN,
+            PanoramaExposure::GainBlocks => stitching::Detail_ExposureCompensator_GAIN_BLOCKS,
+            PanoramaExposure::Channels => stitching::Detail_ExposureCompensator_CHANNELS,
+            PanoramaExposure::ChannelsBlocks => {
+                stitching::Detail_ExposureCompensator_CHANNELS_BLOCKS
+            }
+        };
+
+        let mut stitcher = Stitcher::create(mode)?;
+        stitcher.set_seam_finder(<dyn Detail_SeamFinder>::create_default(seam)?)?;
+        stitcher.set_exposure_compensator(<dyn Detail_ExposureCompensator>::create_default(
+            exposure,
+        )?)?;
+        Ok(Self::Stitcher {
+            stitcher,
+            estimated: false,
+        })
+    }
+
+    fn apply(&mut self, images: &[Mat], output: &mut Mat) -> Result<(), RuntimeError> {
+        match self {
+            Self::Stitcher {
+                stitcher,
+                estimated,
+            } => {
+                let images: VectorOfMat = images.iter().map(Mat::copy).collect::<Result<_, _>>()?;
+                // the warp is estimated once
+                if !*estimated {
+                    let status = stitcher.estimate_transform(&images, &core::no_array()?)?;
+                    if status != Stitcher_Status::OK {
+                        return RuntimeError::message(format!(
+                            "Failed to estimate the panorama: {:?}",
+                            status
+                        ));
+                    }
+                    *estimated = true;
+                }
+                let status = stitcher.compose_panorama_1(&images, output)?;
+                match status {
+                    Stitcher_Status::OK => Ok(()),
+                    _ => RuntimeError::message(format!(
+                        "Failed to compose the panorama: {:?}",
+                        status
+                    )),
+                }
+            }
+            Self::Homographies {
+                homographies,
+                weights,
+            } => {
+                if weights.is_empty() {
+                    *weights = homographies.weights(images)?;
+                }
+                let size = homographies.size;
+                let mut sum = Mat::new_rows_cols_with_default(
+                    size.height,
+                    size.width,
+                    core::CV_32FC3,
+                    Scalar::all(0.),
+                )?;
+                for ((image, warp), weight) in images.iter().zip(&homographies.warps).zip(&*weights)
+                {
+                    let mut warped = Mat::default()?;
+                    imgproc::warp_perspective(
+                        image,
+                        &mut warped,
+                        warp,
+                        size,
+                        imgproc::INTER_LINEAR,
+                        core::BORDER_CONSTANT,
+                        Scalar::default(),
+                    )?;
+                    let mut float = Mat::default()?;
+                    warped.convert_to(&mut float, CV_32F, 1., 0.)?;
+                    imgproc::accumulate_product(&float, weight, &mut sum, &core::no_array()?)?;
+                }
+                sum.convert_to(output, CV_8U, 1., 0.)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+struct Thread {
+    inputs: Vec<ArcVideoReader>,
+    warper: Warper,
+    pipeline: Arc<Pipeline>,
+    meta: mpsc::Sender<VideoMeta>,
+
+    queue: Arc<Queue>,
+    alive: AliveFlag,
+}
+
+impl Thread {
+    fn inner_loop(mut self) -> Result<(), RuntimeError> {
+        let mut frames: Vec<Option<Frame>> = self.inputs.iter().map(|_| None).collect();
+        let mut panorama_size = None;
+        let mut uninit_meta = true;
+        let result = loop {
+            // normal shutdown
+            if let false = self.alive.is_running() {
+                break Ok(());
+            }
+            // unexpected shutdown
+            let got = self
+                .inputs
+                .iter()
+                .zip(frames.iter_mut())
+                .map(|(input, frame)| input.get(frame))
+                .collect::<Result<(), _>>();
+            if let Err(e) = got {
+                break Err(e);
+            }
+
+            let first = frames[0].as_ref().unwrap();
+            let (timestamp, fps) = (first.timestamp, first.meta.fps);
+            let warper = &mut self.warper;
+            let pipeline = &self.pipeline;
+            let size = &mut panorama_size;
+            if let Err(e) = self.queue.push_inner(
+                |image| {
+                    let images = frames
+                        .iter()
+                        .map(|frame| {
+                            let mut image = Mat::copy(&frame.as_ref().unwrap().image)?;
+                            VideoColor::Color.convert(&mut image)?;
+                            Ok(image)
+                        })
+                        .collect::<Result<Vec<_>, RuntimeError>>()?;
+                    let mut output = Mat::default()?;
+                    warper.apply(&images, &mut output)?;
+
+                    // the meta is fixed by the first panorama, not to be resized
+                    match *size {
+                        None => *size = Some(output.size()?),
+                        Some(size) if size != output.size()? => {
+                            return RuntimeError::expect(
+                                "The size of the panorama should not change",
+                            );
+                        }
+                        Some(_) => {}
+                    }
+                    *image = Image::from(output);
+                    pipeline.apply(image)
+                },
+                timestamp,
+                false,
+            ) {
+                break Err(e);
+            }
+
+            if uninit_meta {
+                let size = panorama_size.unwrap();
+                let meta = VideoMeta {
+                    codec: None,
+                    color: Some(VideoColor::Color),
+                    width: size.width as u32,
+                    height: size.height as u32,
+                    fps,
+                };
+                self.meta.send(self.pipeline.meta(&meta)).ok();
+                uninit_meta = false;
+            }
+        };
+
+        // graceful shutdown
+        self.alive.stop().ok();
+        let stopped: Vec<_> = self.inputs.iter().map(|input| input.stop()).collect();
+        stopped.into_iter().collect::<Result<(), _>>()?;
+        result
+    }
+}
+
+/// Stitches the frames of other readers into a wide one, on every frame of them.
+///
+/// The panoramas keep the size of the first one, failing the reader otherwise.
+pub struct PanoramaCapture {
+    queue: Arc<Queue>,
+    alive: AliveFlag,
+    thread: Mutex<Option<thread::JoinHandle<Result<(), RuntimeError>>>>,
+
+    meta: RwLock<Option<VideoMeta>>,
+    pipeline: Arc<Pipeline>,
+    homographies: Option<Arc<Homographies>>,
+
+    inputs: Vec<ArcVideoReader>,
+    config: PanoramaConfig,
+}
+
+impl PanoramaCapture {
+    /// Creates a reader of the inputs, which should be `SharedReader`s.
+    pub fn from_config<P: AsRef<Path>>(
+        config: PanoramaConfig,
+        path: P,
+        inputs: Vec<ArcVideoReader>,
+    ) -> Result<Self, RuntimeError> {
+        config.validate()?;
+        if inputs.len() != config.inputs.len() {
+            return RuntimeError::expect("The panorama should be given all of the inputs");
+        }
+        let homographies = match config.homographies.as_ref() {
+            Some(file) => {
+                let homographies = Homographies::load(path.as_ref().join(file), inputs.len())?;
+                Some(Arc::new(homographies))
+            }
+            None => None,
+        };
+
+        let alive = AliveFlag::default();
+        Ok(Self {
+            queue: Arc::new(Queue::new(&alive, 2)?),
+            alive,
+            thread: Mutex::new(None),
+            meta: RwLock::new(None),
+            pipeline: Default::default(),
+            homographies,
+            inputs,
+            config,
+        })
+    }
+
+    /// Transforms the panoramas with the pipeline.
+    pub fn with_pipeline(mut self, pipeline: Pipeline) -> Self {
+        self.pipeline = Arc::new(pipeline);
+        self
+    }
+
+    /// Estimates the homographies with the current frames of the inputs, to be loaded as
+    /// `homographies` later.
+    ///
+    /// The warp of the stitcher is not a homography in the panorama mode, so the features
+    /// of the neighboring inputs are matched again.
+    pub fn save_homographies<P: AsRef<Path>>(&self, path: P) -> Result<(), RuntimeError> {
+        let leases = self
+            .inputs
+            .iter()
+            .map(Lease::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        let images = self
+            .inputs
+            .iter()
+            .map(|input| {
+                let mut frame = None;
+                input.get(&mut frame)?;
+                let mut image = Mat::default()?;
+                frame.unwrap().image.copy_to(&mut image)?;
+                VideoColor::Color.convert(&mut image)?;
+                Ok(image)
+            })
+            .collect::<Result<Vec<_>, RuntimeError>>();
+        for lease in leases {
+            lease.release()?;
+        }
+        Homographies::estimate(&images?)?.save(path)
+    }
+
+    fn get_meta(&self) -> VideoMeta {
+        loop {
+            {
+                if let Some(meta) = &*self.meta.read().unwrap() {
+                    break meta.clone();
+                }
+            }
+            std::thread::yield_now();
+        }
+    }
+}
+
+impl VideoReader for PanoramaCapture {
+    fn start(&self) -> Result<(), RuntimeError> {
+        let warper = match self.homographies.as_ref() {
+            Some(homographies) => Warper::Homographies {
+                homographies: homographies.clone(),
+                weights: vec![],
+            },
+            None => Warper::stitcher(&self.config)?,
+        };
+
+        self.alive.start()?;
+        for (index, input) in self.inputs.iter().enumerate() {
+            if let Err(e) = input.start() {
+                self.alive.stop().ok();
+                for input in &self.inputs[..index] {
+                    input.stop().ok();
+                }
+                return Err(e);
+            }
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let this = Thread {
+            inputs: self.inputs.clone(),
+            warper,
+            pipeline: self.pipeline.clone(),
+            meta: tx,
+            queue: self.queue.clone(),
+            alive: self.alive.clone(),
+        };
+        let t = thread::spawn(move || this.inner_loop());
+
+        let meta = match rx.recv() {
+            Ok(meta) => meta,
+            // the thread has been terminated before stitching any frames
+            Err(_) => {
+                self.alive.stop().ok();
+                return match t.join() {
+                    Ok(Ok(())) => RuntimeError::expect("Failed to receive VideoMeta"),
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => RuntimeError::unexpected(),
+                };
+            }
+        };
+        *self.meta.write().unwrap() = Some(meta);
+
+        self.thread.lock().unwrap().replace(t);
+        Ok(())
+    }
+
+    #[inline]
+    fn stop(&self) -> Result<(), RuntimeError> {
+        self.alive.stop().ok();
+        match self.thread.lock().unwrap().take() {
+            Some(thread) => match thread.join() {
+                Ok(res) => res,
+                Err(_) => RuntimeError::unexpected(),
+            },
+            None => Ok(()),
+        }
+    }
+
+    #[inline]
+    fn is_running(&self) -> bool {
+        self.alive.is_running()
+    }
+
+    #[inline]
+    fn is_export(&self) -> bool {
+        self.config.export.unwrap_or_default()
+    }
+
+    fn get(&self, frame: &mut Option<Frame>) -> Result<(), RuntimeError> {
+        let frame = match frame.as_mut() {
+            Some(frame) => frame,
+            None => {
+                frame.replace(Frame::new(self.get_meta())?);
+                frame.as_mut().unwrap()
+            }
+        };
+        match self.alive.is_running() {
+            true => self.queue.pop_inner(frame),
+            false => match self.stop() {
+                Ok(()) => RuntimeError::expect("The reader has been stopped"),
+                Err(e) => Err(e),
+            },
+        }
+    }
+}
+
+impl Drop for PanoramaCapture {
+    fn drop(&mut self) {
+        self.stop().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use opencv::core::Vec3f;
+
+    #[test]
+    fn panorama_config() {
+        let config: PanoramaConfig = serde_yaml::from_str(
+            "
+            inputs: [left, right]
+            mode: scans
+            seam: dp
+            exposure: gain_blocks
+            ",
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        let config: PanoramaConfig = serde_yaml::from_str(
+            "
+            inputs: [left, right]
+            homographies: homographies.yaml
+            seam: voronoi
+            ",
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+
+        let config: PanoramaConfig = serde_yaml::from_str("inputs: [left]").unwrap();
+        assert!(config.validate().is_err());
+
+        assert!(serde_yaml::from_str::<PanoramaConfig>("{inputs: [a, b], mode: flat}").is_err());
+    }
+
+    #[test]
+    fn panorama_weights() {
+        // the right image overlaps the left one by 2 columns
+        let translation = [1., 0., 3., 0., 1., 0., 0., 0., 1.];
+        let homographies = Homographies::fit(&[IDENTITY, translation], &[(5, 4), (5, 4)]).unwrap();
+        assert_eq!(homographies.size, Size::new(8, 4));
+
+        let image =
+            || Mat::new_rows_cols_with_default(4, 5, core::CV_8UC3, Scalar::all(0.)).unwrap();
+        let weights = homographies.weights(&[image(), image()]).unwrap();
+        let weight = |index: usize, col| weights[index].at_2d::<Vec3f>(1, col).unwrap()[0];
+
+        for col in 0..8 {
+            assert!((weight(0, col) + weight(1, col) - 1.).abs() < 1e-3);
+        }
+        assert!(weight(1, 1) < 1e-3);
+        assert!(weight(0, 6) < 1e-3);
+        // feathered across the overlap
+        assert!(weight(0, 3) > weight(0, 4));
+        assert!(weight(0, 3) < 1. && weight(1, 4) < 1.);
+    }
+
+    #[test]
+    fn panorama_fit() {
+        let translation = [1., 0., -2., 0., 1., 1., 0., 0., 1.];
+        let homographies = Homographies::fit(&[IDENTITY, translation], &[(5, 4), (5, 4)]).unwrap();
+        assert_eq!(homographies.size, Size::new(7, 5));
+        assert_eq!(*homographies.warps[0].at_2d::<f64>(0, 2).unwrap(), 2.);
+        assert_eq!(*homographies.warps[1].at_2d::<f64>(0, 2).unwrap(), 0.);
+        assert_eq!(*homographies.warps[1].at_2d::<f64>(1, 2).unwrap(), 1.);
+
+        // a flipped warp is rejected
+        let flipped = [1., 0., 0., 0., 1., 0., 0., 0., -1.];
+        assert!(Homographies::fit(&[IDENTITY, flipped], &[(5, 4), (5, 4)]).is_err());
+    }
+}
@@ -65,7 +65,6 @@ impl Queue {
         Ok(())
     }
 
-    #[cfg(feature = "simple-socket")]
     #[inline]
     pub fn push_inner_inplace(
         &self,
@@ -90,6 +89,12 @@ impl Queue {
         Ok(())
     }
 
+    /// Returns the number of the frames pushed so far.
+    #[inline]
+    pub fn pushed(&self) -> usize {
+        self.ptr.load(Ordering::Relaxed)
+    }
+
     fn wait(&self, sync: bool) -> usize {
         let ptr = self.ptr.load(Ordering::Relaxed);
         if sync {
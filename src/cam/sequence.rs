@@ -0,0 +1,227 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::queue::Queue;
+use crate::calibration::Intrinsics;
+use crate::common::VideoReader;
+use crate::config::VideoMeta;
+use crate::frame::{Frame, Image};
+use crate::pipeline::Pipeline;
+use crate::record::dataset::{self, ManifestEntry};
+
+use chrono::prelude::*;
+use chrono::Duration;
+use opencv::imgcodecs;
+use opencv::prelude::*;
+use podo_core_driver::*;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct SequenceConfig {
+    /// The directory of a dataset, relative to the config file.
+    pub(crate) path: String,
+    /// Picks the images of a reader, if the dataset has several ones.
+    pub(crate) reader: Option<String>,
+    /// Replays with the timing of the manifest, instead of the fps.
+    pub(crate) replay: Option<bool>,
+    pub(crate) export: Option<bool>,
+}
+
+struct Thread {
+    dir: PathBuf,
+    entries: Vec<ManifestEntry>,
+    replay: bool,
+    pipeline: Arc<Pipeline>,
+
+    queue: Arc<Queue>,
+    alive: AliveFlag,
+}
+
+impl Thread {
+    fn inner_loop(self) -> Result<(), RuntimeError> {
+        let begin = Utc::now();
+        let origin = self.entries.first().map(|e| e.timestamp).unwrap_or(begin);
+
+        let mut entries = self.entries.iter().enumerate();
+        let result = loop {
+            // normal shutdown
+            if let false = self.alive.is_running() {
+                break Ok(());
+            }
+            let (index, entry) = match entries.next() {
+                Some(entry) => entry,
+                None => break RuntimeError::expect("The replay is over"),
+            };
+
+            // a late frame is not waited
+            let due = match (self.replay, entry.meta.fps) {
+                (true, _) => begin + (entry.timestamp - origin),
+                (false, 0) => Utc::now(),
+                (false, fps) => {
+                    begin + Duration::microseconds(index as i64 * 1_000_000 / fps as i64)
+                }
+            };
+            if let Ok(time) = (due - Utc::now()).to_std() {
+                thread::sleep(time);
+            }
+            let timestamp = match self.replay {
+                true => entry.timestamp,
+                false => Utc::now(),
+            };
+
+            // unexpected shutdown
+            let mut image = match self.read(entry) {
+                Ok(image) => image,
+                Err(e) => break Err(e),
+            };
+            if let Err(e) = self.pipeline.apply(&mut image) {
+                break Err(e);
+            }
+            if let Err(e) = self.queue.push_inner_inplace(image, timestamp, false) {
+                break Err(e);
+            }
+        };
+
+        // graceful shutdown
+        self.alive.stop().ok();
+        result
+    }
+
+    fn read(&self, entry: &ManifestEntry) -> Result<Image, RuntimeError> {
+        let path = self.dir.join(&entry.file);
+        let filename = match path.to_str() {
+            Some(filename) => filename,
+            None => return RuntimeError::expect("The path should be a valid UTF-8 string"),
+        };
+        let image = imgcodecs::imread(filename, imgcodecs::IMREAD_UNCHANGED)?;
+        match image.empty()? {
+            false => Ok(Image::from(image)),
+            true => RuntimeError::message(format!("Failed to read {:?}", &path)),
+        }
+    }
+}
+
+/// Replays the images of a dataset, written by `DatasetWriter`.
+pub struct SequenceCapture {
+    queue: Arc<Queue>,
+    alive: AliveFlag,
+    thread: Mutex<Option<thread::JoinHandle<Result<(), RuntimeError>>>>,
+
+    meta: VideoMeta,
+    pipeline: Arc<Pipeline>,
+
+    config: SequenceConfig,
+    dir: PathBuf,
+}
+
+impl SequenceCapture {
+    pub fn from_config<P: AsRef<Path>>(
+        config: SequenceConfig,
+        path: P,
+    ) -> Result<Self, RuntimeError> {
+        let mut dir = path.as_ref().to_path_buf();
+        dir.push(&config.path);
+
+        let meta = match Self::entries(&config, &dir)?.first() {
+            Some(entry) => entry.meta.clone(),
+            None => return RuntimeError::message(format!("Empty dataset: {:?}", &dir)),
+        };
+
+        let alive = AliveFlag::default();
+        Ok(Self {
+            queue: Arc::new(Queue::new(&alive, 2)?),
+            alive,
+            thread: Mutex::new(None),
+            meta,
+            pipeline: Default::default(),
+            config,
+            dir,
+        })
+    }
+
+    /// Preprocesses the frames in the replay thread.
+    pub fn with_pipeline(mut self, pipeline: Pipeline) -> Self {
+        self.pipeline = Arc::new(pipeline);
+        self
+    }
+
+    fn entries(config: &SequenceConfig, dir: &Path) -> Result<Vec<ManifestEntry>, RuntimeError> {
+        let mut entries = dataset::read_manifest(dir)?;
+        if let Some(reader) = config.reader.as_ref() {
+            entries.retain(|entry| &entry.reader == reader);
+        }
+        Ok(entries)
+    }
+}
+
+impl VideoReader for SequenceCapture {
+    fn start(&self) -> Result<(), RuntimeError> {
+        // the manifest may have grown since
+        let entries = Self::entries(&self.config, &self.dir)?;
+
+        self.alive.start()?;
+        let this = Thread {
+            dir: self.dir.clone(),
+            entries,
+            replay: self.config.replay.unwrap_or_default(),
+            pipeline: self.pipeline.clone(),
+            queue: self.queue.clone(),
+            alive: self.alive.clone(),
+        };
+        let t = thread::spawn(move || this.inner_loop());
+        self.thread.lock().unwrap().replace(t);
+        Ok(())
+    }
+
+    #[inline]
+    fn stop(&self) -> Result<(), RuntimeError> {
+        self.alive.stop().ok();
+        match self.thread.lock().unwrap().take() {
+            Some(thread) => match thread.join() {
+                Ok(res) => res,
+                Err(_) => RuntimeError::unexpected(),
+            },
+            None => Ok(()),
+        }
+    }
+
+    #[inline]
+    fn is_running(&self) -> bool {
+        self.alive.is_running()
+    }
+
+    #[inline]
+    fn is_export(&self) -> bool {
+        self.config.export.unwrap_or_default()
+    }
+
+    fn get(&self, frame: &mut Option<Frame>) -> Result<(), RuntimeError> {
+        let frame = match frame.as_mut() {
+            Some(frame) => frame,
+            None => {
+                frame.replace(Frame::new(self.pipeline.meta(&self.meta))?);
+                frame.as_mut().unwrap()
+            }
+        };
+        match self.alive.is_running() {
+            true => self.queue.pop_inner(frame),
+            false => match self.stop() {
+                Ok(()) => RuntimeError::expect("The reader has been stopped"),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    #[inline]
+    fn intrinsics(&self) -> Option<Intrinsics> {
+        self.pipeline.intrinsics(None)
+    }
+}
+
+impl Drop for SequenceCapture {
+    fn drop(&mut self) {
+        // the end of the replay is not an error here
+        self.stop().ok();
+    }
+}
@@ -0,0 +1,528 @@
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread;
+
+use super::queue::Queue;
+use crate::calibration::{Calibration, Undistortion};
+use crate::common::{ArcVideoReader, VideoReader};
+use crate::config::VideoMeta;
+use crate::frame::{Frame, Image};
+
+use chrono::Duration;
+use opencv::core;
+use opencv::prelude::*;
+use opencv::types::VectorOfMat;
+use podo_core_driver::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize)]
+pub struct StereoConfig {
+    /// The names of the readers of each camera.
+    pub(crate) left: String,
+    pub(crate) right: String,
+    /// The maximum difference of the timestamps of a pair, 10 ms by default.
+    pub(crate) tolerance_ms: Option<u32>,
+    pub(crate) rectify: Option<StereoRectifyConfig>,
+    pub(crate) export: Option<bool>,
+}
+
+/// The calibration files of each camera, relative to the config file.
+///
+/// Both should have `rectification_matrix` and `projection_matrix` of `stereoRectify`.
+#[derive(Debug, Deserialize)]
+pub struct StereoRectifyConfig {
+    pub(crate) left: String,
+    pub(crate) right: String,
+}
+
+/// The (rectified) frames of both cameras, taken within the tolerance.
+///
+/// Each frame keeps the timestamp and the count of its own camera.
+#[derive(Debug)]
+pub struct FramePair {
+    pub left: Frame,
+    pub right: Frame,
+}
+
+/// Copies a frame with another image, such as a rectified one.
+fn assign(to: &mut Frame, from: &Frame, image: &Mat) -> Result<(), RuntimeError> {
+    image.copy_to(&mut *to.image)?;
+    to.meta = from.meta.clone();
+    to.meta.width = image.cols() as u32;
+    to.meta.height = image.rows() as u32;
+    to.timestamp = from.timestamp;
+    to.count = from.count;
+    Ok(())
+}
+
+/// The last pair, kept apart beside the side-by-side frames.
+#[derive(Default)]
+struct PairSlot {
+    inner: Mutex<Option<FramePair>>,
+}
+
+impl PairSlot {
+    fn put(&self, left: (&Frame, &Mat), right: (&Frame, &Mat)) -> Result<(), RuntimeError> {
+        let mut slot = self.inner.lock().unwrap();
+        if slot.is_none() {
+            slot.replace(FramePair {
+                left: Frame::new(left.0.meta.clone())?,
+                right: Frame::new(right.0.meta.clone())?,
+            });
+        }
+        let pair = slot.as_mut().unwrap();
+        assign(&mut pair.left, left.0, left.1)?;
+        assign(&mut pair.right, right.0, right.1)
+    }
+
+    /// Waits for a pair newer than the given one, by the count of the left frame.
+    fn get(&self, alive: &AliveFlag, pair: &mut Option<FramePair>) -> Result<(), RuntimeError> {
+        let last = pair
+            .as_ref()
+            .map(|pair| pair.left.count)
+            .unwrap_or_default();
+        loop {
+            alive.assert_running()?;
+            {
+                let slot = self.inner.lock().unwrap();
+                if let Some(latest) = slot.as_ref().filter(|latest| latest.left.count > last) {
+                    let pair = match pair.as_mut() {
+                        Some(pair) => pair,
+                        None => {
+                            pair.replace(FramePair {
+                                left: Frame::new(latest.left.meta.clone())?,
+                                right: Frame::new(latest.right.meta.clone())?,
+                            });
+                            pair.as_mut().unwrap()
+                        }
+                    };
+                    assign(&mut pair.left, &latest.left, &latest.left.image)?;
+                    return assign(&mut pair.right, &latest.right, &latest.right.image);
+                }
+            }
+            thread::yield_now();
+        }
+    }
+}
+
+/// The counts of the frames since the reader has been created.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StereoStats {
+    pub matched: usize,
+    /// The frames without any counterpart within the tolerance.
+    pub unmatched_left: usize,
+    pub unmatched_right: usize,
+    /// The frames overwritten in the queues of the cameras before being paired.
+    pub dropped_left: usize,
+    pub dropped_right: usize,
+}
+
+struct Thread {
+    left: ArcVideoReader,
+    right: ArcVideoReader,
+    rectify: Option<Arc<(Undistortion, Undistortion)>>,
+    tolerance: Duration,
+    meta: mpsc::Sender<VideoMeta>,
+    pairs: Arc<PairSlot>,
+    stats: Arc<Mutex<StereoStats>>,
+
+    queue: Arc<Queue>,
+    alive: AliveFlag,
+}
+
+impl Thread {
+    fn inner_loop(self) -> Result<(), RuntimeError> {
+        let mut left = None;
+        let mut right = None;
+        let mut advance = (true, true);
+        let mut uninit_meta = true;
+        let result = loop {
+            // normal shutdown
+            if let false = self.alive.is_running() {
+                break Ok(());
+            }
+            // unexpected shutdown
+            if advance.0 {
+                if let Err(e) = self.next(&self.left, &mut left, |s| &mut s.dropped_left) {
+                    break Err(e);
+                }
+            }
+            if advance.1 {
+                if let Err(e) = self.next(&self.right, &mut right, |s| &mut s.dropped_right) {
+                    break Err(e);
+                }
+            }
+            let left: &Frame = left.as_ref().unwrap();
+            let right: &Frame = right.as_ref().unwrap();
+
+            // the older frame waits no more for its counterpart
+            let skew = right.timestamp - left.timestamp;
+            if skew > self.tolerance {
+                self.stats.lock().unwrap().unmatched_left += 1;
+                advance = (true, false);
+                continue;
+            }
+            if skew < -self.tolerance {
+                self.stats.lock().unwrap().unmatched_right += 1;
+                advance = (false, true);
+                continue;
+            }
+            advance = (true, true);
+
+            if uninit_meta {
+                let mut meta = left.meta.clone();
+                if let Some(rectify) = self.rectify.as_ref() {
+                    meta.width = rectify.0.intrinsics().width;
+                    meta.height = rectify.0.intrinsics().height;
+                }
+                self.meta.send(meta).ok();
+                uninit_meta = false;
+            }
+
+            let (left_image, right_image) = match self.rectify(left, right) {
+                Ok(images) => images,
+                Err(e) => break Err(e),
+            };
+            if let Err(e) = self.pairs.put((left, &left_image), (right, &right_image)) {
+                break Err(e);
+            }
+            if let Err(e) = self.queue.push_inner(
+                |image| concat(&left_image, &right_image, image),
+                left.timestamp,
+                false,
+            ) {
+                break Err(e);
+            }
+            self.stats.lock().unwrap().matched += 1;
+        };
+
+        // graceful shutdown
+        self.alive.stop().ok();
+        let stopped = self.left.stop();
+        self.right.stop()?;
+        stopped?;
+        result
+    }
+
+    fn next<F>(
+        &self,
+        reader: &ArcVideoReader,
+        frame: &mut Option<Frame>,
+        dropped: F,
+    ) -> Result<(), RuntimeError>
+    where
+        F: FnOnce(&mut StereoStats) -> &mut usize,
+    {
+        let last = frame.as_ref().map(|frame| frame.count);
+        reader.get(frame)?;
+        if let Some(last) = last {
+            let count = frame.as_ref().unwrap().count;
+            *dropped(&mut self.stats.lock().unwrap()) += count.saturating_sub(last + 1);
+        }
+        Ok(())
+    }
+
+    /// Returns the images of the frames, rectified if needed.
+    fn rectify(&self, left: &Frame, right: &Frame) -> Result<(Image, Image), RuntimeError> {
+        let mut left = Image::from(Mat::copy(&left.image)?);
+        let mut right = Image::from(Mat::copy(&right.image)?);
+        if let Some(rectify) = self.rectify.as_ref() {
+            rectify.0.apply(&mut left)?;
+            rectify.1.apply(&mut right)?;
+        }
+        if left.rows() != right.rows()
+            || left.cols() != right.cols()
+            || left.typ()? != right.typ()?
+        {
+            return RuntimeError::expect("The stereo frames should be of the same size and type");
+        }
+        Ok((left, right))
+    }
+}
+
+/// Puts the images side by side.
+fn concat(left: &Mat, right: &Mat, image: &mut Image) -> Result<(), RuntimeError> {
+    let mut pair = VectorOfMat::new();
+    pair.push(Mat::copy(left)?);
+    pair.push(Mat::copy(right)?);
+    core::hconcat(&pair, &mut **image)?;
+    Ok(())
+}
+
+/// Pairs the frames of two readers by their timestamps.
+///
+/// `get` returns the pairs side by side, and `get_pair` returns them apart.
+pub struct StereoCapture {
+    queue: Arc<Queue>,
+    alive: AliveFlag,
+    thread: Mutex<Option<thread::JoinHandle<Result<(), RuntimeError>>>>,
+
+    /// The meta of a single camera.
+    meta: RwLock<Option<VideoMeta>>,
+    rectify: Option<Arc<(Undistortion, Undistortion)>>,
+    pairs: Arc<PairSlot>,
+    stats: Arc<Mutex<StereoStats>>,
+
+    left: ArcVideoReader,
+    right: ArcVideoReader,
+    config: StereoConfig,
+}
+
+impl StereoCapture {
+    /// Creates a reader of the cameras, which should be `SharedReader`s.
+    pub fn from_config<P: AsRef<Path>>(
+        config: StereoConfig,
+        path: P,
+        left: ArcVideoReader,
+        right: ArcVideoReader,
+    ) -> Result<Self, RuntimeError> {
+        let rectify = match config.rectify.as_ref() {
+            Some(rectify) => {
+                let left = Self::load(path.as_ref().join(&rectify.left))?;
+                let right = Self::load(path.as_ref().join(&rectify.right))?;
+                Some(Arc::new((left, right)))
+            }
+            None => None,
+        };
+
+        let alive = AliveFlag::default();
+        Ok(Self {
+            queue: Arc::new(Queue::new(&alive, 2)?),
+            alive,
+            thread: Mutex::new(None),
+            meta: RwLock::new(None),
+            rectify,
+            pairs: Default::default(),
+            stats: Default::default(),
+            left,
+            right,
+            config,
+        })
+    }
+
+    fn load(path: PathBuf) -> Result<Undistortion, RuntimeError> {
+        let calibration = Calibration::load(&path)?;
+        if calibration.rectification.is_none() || calibration.projection.is_none() {
+            return RuntimeError::message(format!(
+                "The stereo calibration should be rectified: {:?}",
+                &path
+            ));
+        }
+        calibration.undistortion()
+    }
+
+    /// Returns the next pair of the frames apart, after the given one if any.
+    #[inline]
+    pub fn get_pair(&self, pair: &mut Option<FramePair>) -> Result<(), RuntimeError> {
+        self.pairs.get(&self.alive, pair)
+    }
+
+    /// Returns the statistics of the pairing, since the reader has been created.
+    #[inline]
+    pub fn stats(&self) -> StereoStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    fn get_meta(&self) -> VideoMeta {
+        loop {
+            {
+                if let Some(meta) = &*self.meta.read().unwrap() {
+                    break meta.clone();
+                }
+            }
+            std::thread::yield_now();
+        }
+    }
+}
+
+impl VideoReader for StereoCapture {
+    fn start(&self) -> Result<(), RuntimeError> {
+        self.alive.start()?;
+        if let Err(e) = self.left.start() {
+            self.alive.stop().ok();
+            return Err(e);
+        }
+        if let Err(e) = self.right.start() {
+            self.alive.stop().ok();
+            self.left.stop().ok();
+            return Err(e);
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let this = Thread {
+            left: self.left.clone(),
+            right: self.right.clone(),
+            rectify: self.rectify.clone(),
+            tolerance: Duration::milliseconds(self.config.tolerance_ms.unwrap_or(10).into()),
+            meta: tx,
+            pairs: self.pairs.clone(),
+            stats: self.stats.clone(),
+            queue: self.queue.clone(),
+            alive: self.alive.clone(),
+        };
+        let t = thread::spawn(move || this.inner_loop());
+
+        let meta = match rx.recv() {
+            Ok(meta) => meta,
+            // the thread has been terminated before pairing any frames
+            Err(_) => {
+                self.alive.stop().ok();
+                return match t.join() {
+                    Ok(Ok(())) => RuntimeError::expect("Failed to receive VideoMeta"),
+                    Ok(Err(e)) => Err(e),
+                    Err(_) => RuntimeError::unexpected(),
+                };
+            }
+        };
+        *self.meta.write().unwrap() = Some(meta);
+
+        self.thread.lock().unwrap().replace(t);
+        Ok(())
+    }
+
+    #[inline]
+    fn stop(&self) -> Result<(), RuntimeError> {
+        self.alive.stop().ok();
+        match self.thread.lock().unwrap().take() {
+            Some(thread) => match thread.join() {
+                Ok(res) => res,
+                Err(_) => RuntimeError::unexpected(),
+            },
+            None => Ok(()),
+        }
+    }
+
+    #[inline]
+    fn is_running(&self) -> bool {
+        self.alive.is_running()
+    }
+
+    #[inline]
+    fn is_export(&self) -> bool {
+        self.config.export.unwrap_or_default()
+    }
+
+    fn get(&self, frame: &mut Option<Frame>) -> Result<(), RuntimeError> {
+        let frame = match frame.as_mut() {
+            Some(frame) => frame,
+            None => {
+                let mut meta = self.get_meta();
+                meta.width *= 2;
+                frame.replace(Frame::new(meta)?);
+                frame.as_mut().unwrap()
+            }
+        };
+        match self.alive.is_running() {
+            true => self.queue.pop_inner(frame),
+            false => match self.stop() {
+                Ok(()) => RuntimeError::expect("The reader has been stopped"),
+                Err(e) => Err(e),
+            },
+        }
+    }
+}
+
+impl Drop for StereoCapture {
+    fn drop(&mut self) {
+        self.stop().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+
+    use chrono::prelude::*;
+    use opencv::core::{Scalar, CV_8UC1};
+
+    /// Plays the counts & the milliseconds of the frames, and fails after them.
+    struct Script {
+        frames: Mutex<VecDeque<(usize, i64)>>,
+    }
+
+    impl Script {
+        fn new(frames: &[(usize, i64)]) -> ArcVideoReader {
+            Arc::new(Self {
+                frames: Mutex::new(frames.iter().copied().collect()),
+            })
+        }
+    }
+
+    impl VideoReader for Script {
+        fn start(&self) -> Result<(), RuntimeError> {
+            Ok(())
+        }
+
+        fn stop(&self) -> Result<(), RuntimeError> {
+            Ok(())
+        }
+
+        fn is_running(&self) -> bool {
+            true
+        }
+
+        fn is_export(&self) -> bool {
+            false
+        }
+
+        fn get(&self, frame: &mut Option<Frame>) -> Result<(), RuntimeError> {
+            let (count, ms) = match self.frames.lock().unwrap().pop_front() {
+                Some(next) => next,
+                None => return RuntimeError::expect("The script is over"),
+            };
+            let value = Scalar::all(count as f64);
+            let mat = Mat::new_rows_cols_with_default(2, 3, CV_8UC1, value)?;
+            frame.replace(Frame {
+                image: Image::from(mat),
+                meta: VideoMeta {
+                    codec: None,
+                    color: None,
+                    width: 3,
+                    height: 2,
+                    fps: 30,
+                },
+                timestamp: Utc.ymd(2020, 1, 2).and_hms(3, 4, 5) + Duration::milliseconds(ms),
+                count,
+            });
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stereo_pairing() {
+        let config = serde_yaml::from_str("{ left: left, right: right, tolerance_ms: 10 }");
+        // the left frame #4 has been overwritten in its queue
+        let left = Script::new(&[(1, 0), (2, 33), (3, 66), (5, 133)]);
+        let right = Script::new(&[(1, 2), (2, 50), (3, 68), (4, 134)]);
+        let stereo = StereoCapture::from_config(config.unwrap(), ".", left, right).unwrap();
+
+        stereo.start().unwrap();
+        while stereo.is_running() {
+            thread::yield_now();
+        }
+        assert!(stereo.stop().is_err());
+
+        assert_eq!(
+            stereo.stats(),
+            StereoStats {
+                matched: 3,
+                unmatched_left: 1,
+                unmatched_right: 1,
+                dropped_left: 1,
+                dropped_right: 0,
+            }
+        );
+
+        // each frame of the last pair keeps its own timestamp & count
+        let slot = stereo.pairs.inner.lock().unwrap();
+        let pair = slot.as_ref().unwrap();
+        assert_eq!((pair.left.count, pair.right.count), (5, 4));
+        assert_eq!(
+            pair.right.timestamp - pair.left.timestamp,
+            Duration::milliseconds(1)
+        );
+        assert_eq!(*pair.right.image.at_2d::<u8>(1, 2).unwrap(), 4);
+    }
+}
@@ -0,0 +1,383 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::capture::CamConfig;
+use super::queue::Queue;
+use crate::calibration::Intrinsics;
+use crate::common::VideoReader;
+use crate::config::{Configurable, VideoColor};
+use crate::frame::Frame;
+use crate::pipeline::Pipeline;
+
+use chrono::prelude::*;
+use opencv::prelude::*;
+use opencv::videoio;
+use podo_core_driver::*;
+
+/// The consecutive triggers failing to grab a camera, before the group fails.
+const MAX_GRAB_FAILURES: usize = 3;
+
+#[derive(Clone)]
+struct Member {
+    config: Arc<CamConfig>,
+    path: PathBuf,
+    pipeline: Arc<Pipeline>,
+
+    queue: Arc<Queue>,
+    alive: AliveFlag,
+}
+
+struct Thread {
+    cameras: Vec<(videoio::VideoCapture, VideoColor)>,
+    members: Vec<Member>,
+    failures: usize,
+
+    alive: AliveFlag,
+}
+
+impl Thread {
+    fn inner_loop(mut self) -> Result<(), RuntimeError> {
+        let result = loop {
+            // normal shutdown
+            if let false = self.alive.is_running() {
+                break Ok(());
+            }
+            // unexpected shutdown
+            if let Err(e) = self.trigger() {
+                break Err(e);
+            }
+        };
+        // graceful shutdown
+        {
+            self.alive.stop().ok();
+            for member in &self.members {
+                member.alive.stop().ok();
+            }
+            for (camera, _) in &mut self.cameras {
+                camera.release()?;
+            }
+        }
+        result
+    }
+
+    fn trigger(&mut self) -> Result<(), RuntimeError> {
+        // the cameras are grabbed back to back, and decoded later
+        let timestamp = Utc::now();
+        for (camera, _) in &mut self.cameras {
+            if !camera.grab()? {
+                // a dropped frame is skipped by all of the members together
+                self.failures += 1;
+                return match self.failures < MAX_GRAB_FAILURES {
+                    true => Ok(()),
+                    false => RuntimeError::expect("opencv::VideoCapture::grab failed"),
+                };
+            }
+        }
+        self.failures = 0;
+
+        for ((camera, color), member) in self.cameras.iter_mut().zip(&self.members) {
+            // the stopped members are still grabbed, to keep the cameras in sync
+            if !member.alive.is_running() {
+                continue;
+            }
+            let pipeline = &member.pipeline;
+            member.queue.push_inner(
+                |image| match camera.retrieve(image as &mut Mat, 0)? {
+                    true => {
+                        color.convert(&mut *image)?;
+                        pipeline.apply(image)
+                    }
+                    false => RuntimeError::expect("opencv::VideoCapture::retrieve failed"),
+                },
+                timestamp,
+                false,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The members holding the coordinator, and the coordinator itself.
+#[derive(Default)]
+struct Holders {
+    count: usize,
+    thread: Option<thread::JoinHandle<Result<(), RuntimeError>>>,
+}
+
+impl Holders {
+    /// Holds the coordinator, spawning a new one if none is running.
+    ///
+    /// A failed coordinator is replaced, while its members keep holding it until they stop.
+    fn acquire<F>(&mut self, alive: &AliveFlag, spawn: F) -> Result<(), RuntimeError>
+    where
+        F: FnOnce() -> Result<thread::JoinHandle<Result<(), RuntimeError>>, RuntimeError>,
+    {
+        if self.count == 0 || !alive.is_running() {
+            // the failure has been reported to the former members
+            if let Some(thread) = self.thread.take() {
+                thread.join().ok();
+            }
+            self.thread = Some(spawn()?);
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Stops the coordinator by the last member, or collects its result after a failure.
+    fn release(&mut self, alive: &AliveFlag) -> Result<(), RuntimeError> {
+        self.count = self.count.saturating_sub(1);
+        if self.count > 0 && alive.is_running() {
+            return Ok(());
+        }
+
+        alive.stop().ok();
+        match self.thread.take() {
+            Some(thread) => match thread.join() {
+                Ok(res) => res,
+                Err(_) => RuntimeError::unexpected(),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+/// Triggers the cameras of the members together, in a coordinator thread.
+///
+/// The coordinator runs while any member is running, and the frames of a trigger
+/// share its timestamp. A few dropped frames are skipped by all of the members, but a
+/// camera failing longer stops every member, as the others would be out of sync. The
+/// failure is returned to one of the members, unless a member started again has
+/// spawned a new coordinator before.
+#[derive(Default)]
+pub struct SyncGroup {
+    members: Mutex<Vec<Member>>,
+    holders: Mutex<Holders>,
+
+    alive: AliveFlag,
+}
+
+impl SyncGroup {
+    fn join(&self, member: Member) -> usize {
+        let mut members = self.members.lock().unwrap();
+        members.push(member);
+        members.len() - 1
+    }
+
+    fn set_pipeline(&self, index: usize, pipeline: Arc<Pipeline>) {
+        self.members.lock().unwrap()[index].pipeline = pipeline;
+    }
+
+    fn acquire(&self) -> Result<(), RuntimeError> {
+        self.holders.lock().unwrap().acquire(&self.alive, || {
+            let members = self.members.lock().unwrap().clone();
+            let cameras = members
+                .iter()
+                .map(|member| member.config.spawn(&member.path))
+                .collect::<Result<_, _>>()?;
+
+            self.alive.start()?;
+            let this = Thread {
+                cameras,
+                members,
+                failures: 0,
+                alive: self.alive.clone(),
+            };
+            Ok(thread::spawn(move || this.inner_loop()))
+        })
+    }
+
+    #[inline]
+    fn release(&self) -> Result<(), RuntimeError> {
+        self.holders.lock().unwrap().release(&self.alive)
+    }
+}
+
+/// A camera triggered by its `SyncGroup`, instead of a capture thread of its own.
+pub struct SyncCapture {
+    queue: Arc<Queue>,
+    alive: AliveFlag,
+    acquired: Mutex<bool>,
+
+    group: Arc<SyncGroup>,
+    index: usize,
+    config: Arc<CamConfig>,
+    pipeline: Arc<Pipeline>,
+}
+
+impl SyncCapture {
+    pub fn from_config<P: AsRef<Path>>(
+        config: CamConfig,
+        path: P,
+        group: Arc<SyncGroup>,
+    ) -> Result<Self, RuntimeError> {
+        let alive = AliveFlag::default();
+        let queue = Arc::new(Queue::new(&alive, 2)?);
+        let config = Arc::new(config);
+        let pipeline: Arc<Pipeline> = Default::default();
+
+        let index = group.join(Member {
+            config: config.clone(),
+            path: path.as_ref().to_path_buf(),
+            pipeline: pipeline.clone(),
+            queue: queue.clone(),
+            alive: alive.clone(),
+        });
+        Ok(Self {
+            queue,
+            alive,
+            acquired: Mutex::new(false),
+            group,
+            index,
+            config,
+            pipeline,
+        })
+    }
+
+    /// Preprocesses the frames in the coordinator thread.
+    pub fn with_pipeline(mut self, pipeline: Pipeline) -> Self {
+        self.pipeline = Arc::new(pipeline);
+        self.group.set_pipeline(self.index, self.pipeline.clone());
+        self
+    }
+}
+
+impl VideoReader for SyncCapture {
+    fn start(&self) -> Result<(), RuntimeError> {
+        let mut acquired = self.acquired.lock().unwrap();
+        self.alive.start()?;
+        if let Err(e) = self.group.acquire() {
+            self.alive.stop().ok();
+            return Err(e);
+        }
+        *acquired = true;
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<(), RuntimeError> {
+        let mut acquired = self.acquired.lock().unwrap();
+        self.alive.stop().ok();
+        match std::mem::replace(&mut *acquired, false) {
+            true => self.group.release(),
+            false => Ok(()),
+        }
+    }
+
+    #[inline]
+    fn is_running(&self) -> bool {
+        self.alive.is_running()
+    }
+
+    #[inline]
+    fn is_export(&self) -> bool {
+        self.config.is_export()
+    }
+
+    fn get(&self, frame: &mut Option<Frame>) -> Result<(), RuntimeError> {
+        let frame = match frame.as_mut() {
+            Some(frame) => frame,
+            None => {
+                frame.replace(Frame::new(self.pipeline.meta(self.config.meta()))?);
+                frame.as_mut().unwrap()
+            }
+        };
+        match self.alive.is_running() {
+            true => self.queue.pop_inner(frame),
+            // the failure may have been collected by another member
+            false => match self.stop() {
+                Ok(()) => RuntimeError::expect("The sync group has been terminated"),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    #[inline]
+    fn intrinsics(&self) -> Option<Intrinsics> {
+        self.pipeline.intrinsics(None)
+    }
+}
+
+impl Drop for SyncCapture {
+    fn drop(&mut self) {
+        self.stop().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    /// Spawns a coordinator running until the alive flag stops, or failing on the signal.
+    fn coordinator(
+        alive: &AliveFlag,
+    ) -> (
+        mpsc::Sender<()>,
+        impl FnOnce() -> Result<thread::JoinHandle<Result<(), RuntimeError>>, RuntimeError>,
+    ) {
+        let (tx, rx) = mpsc::channel();
+        let alive = alive.clone();
+        let spawn = move || {
+            alive.start()?;
+            Ok(thread::spawn(move || loop {
+                if let false = alive.is_running() {
+                    break Ok(());
+                }
+                if let Ok(()) = rx.recv_timeout(Duration::from_millis(1)) {
+                    alive.stop().ok();
+                    break RuntimeError::expect("failed");
+                }
+            }))
+        };
+        (tx, spawn)
+    }
+
+    #[test]
+    fn sync_holders() {
+        let alive = AliveFlag::default();
+        let mut holders = Holders::default();
+
+        // the first member spawns the coordinator, and the last one stops it
+        let (_, first) = coordinator(&alive);
+        holders.acquire(&alive, first).unwrap();
+        holders
+            .acquire(&alive, || panic!("already spawned"))
+            .unwrap();
+        assert_eq!(holders.count, 2);
+        holders.release(&alive).unwrap();
+        assert!(alive.is_running());
+        holders.release(&alive).unwrap();
+        assert!(!alive.is_running());
+        assert!(holders.thread.is_none());
+
+        // a failed spawn holds nothing
+        holders
+            .acquire(&alive, || RuntimeError::expect("no camera"))
+            .unwrap_err();
+        assert_eq!(holders.count, 0);
+
+        let (fail, second) = coordinator(&alive);
+        holders.acquire(&alive, second).unwrap();
+        holders
+            .acquire(&alive, || panic!("already spawned"))
+            .unwrap();
+        fail.send(()).unwrap();
+        while alive.is_running() {
+            thread::yield_now();
+        }
+        // the failure is collected by one of the members
+        assert!(holders.release(&alive).is_err());
+
+        // a member started again spawns another coordinator
+        let (_, third) = coordinator(&alive);
+        holders.acquire(&alive, third).unwrap();
+        assert!(alive.is_running());
+        assert_eq!(holders.count, 2);
+        holders.release(&alive).unwrap();
+        assert!(alive.is_running());
+        holders.release(&alive).unwrap();
+        assert!(!alive.is_running());
+    }
+}
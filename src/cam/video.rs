@@ -1,11 +1,17 @@
+use std::path::Path;
+
 use crate::config::{Configurable, VideoMeta};
+use crate::record::sidecar;
 
+use chrono::{DateTime, Utc};
 use podo_core_driver::*;
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
 pub struct VideoConfig {
     pub(crate) path: String,
+    /// Replays with the timing and the counts of the sidecar file, instead of the fps.
+    pub(crate) replay: Option<bool>,
     #[serde(flatten)]
     pub(crate) meta: VideoMeta,
 }
@@ -30,4 +36,17 @@ impl Configurable for VideoConfig {
     fn is_export(&self) -> bool {
         false
     }
+
+    fn timeline(
+        &self,
+        path: &PathBuf,
+    ) -> Result<Option<Vec<(usize, DateTime<Utc>)>>, RuntimeError> {
+        match self.replay {
+            Some(true) => {
+                let filename = self.filename(path)?;
+                Ok(Some(sidecar::read(Path::new(&filename))?))
+            }
+            _ => Ok(None),
+        }
+    }
 }
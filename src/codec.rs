@@ -0,0 +1,351 @@
+//! Compressed serde representations of `Image`.
+//!
+//! The raw layout stays the default. A compressed image keeps the same shape,
+//! but flags its `typ` with `IMAGE_ENCODED` and stores an encoded file in `data`.
+//! Deserialization detects the encoding from the magic bytes of `data`.
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize)]
+//! struct Record {
+//!     #[serde(with = "podo_std_eye::codec::png")]
+//!     image: Image,
+//! }
+//! ```
+
+use std::iter::FromIterator;
+
+use crate::frame::{Frame, Image};
+
+use opencv::core::{CV_8UC3, CV_8UC4};
+use opencv::imgcodecs;
+use opencv::imgproc::*;
+use opencv::prelude::*;
+use opencv::types::{VectorOfi32, VectorOfu8};
+use podo_core_driver::RuntimeError;
+use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Marks the `typ` of an image whose `data` holds an encoded file.
+pub(crate) const IMAGE_ENCODED: i32 = 1 << 30;
+
+const MAGIC_PNG: &[u8] = b"\x89PNG\r\n\x1a\n";
+const MAGIC_JPEG: &[u8] = b"\xff\xd8\xff";
+const MAGIC_QOI: &[u8] = b"qoif";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImageCodec {
+    Raw,
+    /// Lossless, supports 8-bit and 16-bit images.
+    Png,
+    /// Lossy, supports 8-bit grayscale & color images.
+    Jpeg {
+        quality: u8,
+    },
+    /// Lossless, supports 8-bit color images only.
+    Qoi,
+}
+
+impl Default for ImageCodec {
+    #[inline]
+    fn default() -> Self {
+        Self::Raw
+    }
+}
+
+impl ImageCodec {
+    pub(crate) fn encode(&self, image: &Mat) -> Result<Vec<u8>, RuntimeError> {
+        match self {
+            Self::Raw => RuntimeError::unimplemented(),
+            Self::Png => encode_imgcodecs(image, ".png", &[]),
+            Self::Jpeg { quality } => encode_imgcodecs(
+                image,
+                ".jpg",
+                &[imgcodecs::IMWRITE_JPEG_QUALITY, (*quality).into()],
+            ),
+            Self::Qoi => encode_qoi(image),
+        }
+    }
+}
+
+fn encode_imgcodecs(image: &Mat, ext: &str, params: &[i32]) -> Result<Vec<u8>, RuntimeError> {
+    let params = VectorOfi32::from_iter(params.iter().copied());
+    let mut buf = VectorOfu8::new();
+    match imgcodecs::imencode(ext, image, &mut buf, &params)? {
+        true => Ok(buf.to_vec()),
+        false => RuntimeError::expect("opencv::imgcodecs::imencode failed"),
+    }
+}
+
+fn encode_qoi(image: &Mat) -> Result<Vec<u8>, RuntimeError> {
+    // QOI stores the pixels in RGB(A) order
+    let code = match image.typ()? {
+        CV_8UC3 => COLOR_BGR2RGB,
+        CV_8UC4 => COLOR_BGRA2RGBA,
+        _ => return RuntimeError::unimplemented(),
+    };
+    let mut rgb = Mat::default()?;
+    cvt_color(image, &mut rgb, code, 0)?;
+
+    let len = rgb.total()? * rgb.elem_size()?;
+    let data = unsafe { std::slice::from_raw_parts(rgb.ptr(0)?, len) };
+    ::qoi::encode_to_vec(data, rgb.cols() as u32, rgb.rows() as u32)
+        .or_else(|e| RuntimeError::message(e.to_string()))
+}
+
+/// Decodes an encoded file, detecting its format from the magic bytes.
+pub(crate) fn decode(data: &[u8], max_len: usize) -> Result<Mat, String> {
+    if data.starts_with(MAGIC_PNG) || data.starts_with(MAGIC_JPEG) {
+        // the header is trusted no more than the declared shape
+        match declared_len(data) {
+            Some(len) if len <= max_len => {}
+            Some(_) => return Err("invalid image data: too large".to_string()),
+            None => return Err("invalid image data: malformed header".to_string()),
+        }
+
+        let buf = VectorOfu8::from_iter(data.iter().copied());
+        let image = imgcodecs::imdecode(&buf, imgcodecs::IMREAD_UNCHANGED)
+            .map_err(|e| format!("{:?}", e))?;
+        match image.empty() {
+            Ok(false) => Ok(image),
+            _ => Err("invalid image data: failed to decode".to_string()),
+        }
+    } else if data.starts_with(MAGIC_QOI) {
+        decode_qoi(data, max_len).map_err(|e| format!("{:?}", e))
+    } else {
+        Err("invalid image data: unknown encoding".to_string())
+    }
+}
+
+/// Returns the size of the pixel data declared in the header of a PNG or JPEG file.
+fn declared_len(data: &[u8]) -> Option<usize> {
+    let (width, height, pixel_size) = match data.starts_with(MAGIC_PNG) {
+        true => png_header(data)?,
+        false => jpeg_header(data)?,
+    };
+    width.checked_mul(height)?.checked_mul(pixel_size)
+}
+
+/// Reads a big-endian unsigned integer.
+#[inline]
+fn read_be(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |n, &b| n << 8 | b as usize)
+}
+
+/// Returns the width, the height and the bytes per pixel of the IHDR chunk.
+fn png_header(data: &[u8]) -> Option<(usize, usize, usize)> {
+    let ihdr = data.get(MAGIC_PNG.len()..MAGIC_PNG.len() + 18)?;
+    if &ihdr[4..8] != b"IHDR" {
+        return None;
+    }
+    let (width, height) = (read_be(&ihdr[8..12]), read_be(&ihdr[12..16]));
+    let depth_size = if ihdr[16] > 8 { 2 } else { 1 };
+    // the palette is decoded into BGR, and the gray alpha into BGRA
+    let channels = match ihdr[17] {
+        0 => 1,
+        2 | 3 => 3,
+        4 | 6 => 4,
+        _ => return None,
+    };
+    Some((width, height, depth_size * channels))
+}
+
+/// Returns the width, the height and the bytes per pixel of the first SOF segment.
+fn jpeg_header(data: &[u8]) -> Option<(usize, usize, usize)> {
+    let mut index = 2;
+    loop {
+        if *data.get(index)? != 0xff {
+            return None;
+        }
+        // the markers may be padded with 0xff
+        while *data.get(index)? == 0xff {
+            index += 1;
+        }
+        let marker = *data.get(index)?;
+        match marker {
+            // except DHT, JPG and DAC
+            0xc0..=0xcf if marker != 0xc4 && marker != 0xc8 && marker != 0xcc => {
+                let sof = data.get(index + 3..index + 9)?;
+                let (height, width) = (read_be(&sof[1..3]), read_be(&sof[3..5]));
+                let depth_size = if sof[0] > 8 { 2 } else { 1 };
+                return Some((width, height, depth_size * sof[5] as usize));
+            }
+            // SOS or EOI before any SOF
+            0xd9 | 0xda => return None,
+            _ => index += 1 + read_be(data.get(index + 1..index + 3)?),
+        }
+    }
+}
+
+fn decode_qoi(data: &[u8], max_len: usize) -> Result<Mat, RuntimeError> {
+    let header = ::qoi::decode_header(data).or_else(|e| RuntimeError::message(e.to_string()))?;
+    if header.n_bytes() > max_len {
+        return RuntimeError::expect("invalid image data: too large");
+    }
+
+    let (header, mut rgb) =
+        ::qoi::decode_to_vec(data).or_else(|e| RuntimeError::message(e.to_string()))?;
+    let (typ, code) = match header.channels.as_u8() {
+        3 => (CV_8UC3, COLOR_RGB2BGR),
+        _ => (CV_8UC4, COLOR_RGBA2BGRA),
+    };
+    let rgb = unsafe {
+        Mat::new_rows_cols_with_data(
+            header.height as i32,
+            header.width as i32,
+            typ,
+            rgb.as_mut_ptr() as *mut _,
+            opencv::core::Mat_AUTO_STEP,
+        )?
+    };
+
+    let mut image = Mat::default()?;
+    cvt_color(&rgb, &mut image, code, 0)?;
+    Ok(image)
+}
+
+/// Serializes an image or a frame with the given codec.
+pub struct Compressed<'a, T> {
+    inner: &'a T,
+    codec: ImageCodec,
+}
+
+impl<'a, T> Compressed<'a, T> {
+    #[inline]
+    pub fn new(inner: &'a T, codec: ImageCodec) -> Self {
+        Self { inner, codec }
+    }
+}
+
+impl<'a> Serialize for Compressed<'a, Image> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.inner.serialize_with(self.codec, serializer)
+    }
+}
+
+impl<'a> Serialize for Compressed<'a, Frame> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let frame = self.inner;
+
+        let mut state = serializer.serialize_struct("Frame", 4)?;
+        state.serialize_field("image", &Compressed::new(&frame.image, self.codec))?;
+        state.serialize_field("meta", &frame.meta)?;
+        state.serialize_field("timestamp", &frame.timestamp)?;
+        state.serialize_field("count", &frame.count)?;
+        state.end()
+    }
+}
+
+macro_rules! impl_serde_with {
+    ($name:ident, $codec:expr, $doc:expr) => {
+        #[doc = $doc]
+        pub mod $name {
+            use super::*;
+
+            pub fn serialize<S>(image: &Image, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: Serializer,
+            {
+                image.serialize_with($codec, serializer)
+            }
+
+            pub fn deserialize<'de, D>(deserializer: D) -> Result<Image, D::Error>
+            where
+                D: Deserializer<'de>,
+            {
+                Image::deserialize(deserializer)
+            }
+        }
+    };
+}
+
+impl_serde_with!(png, ImageCodec::Png, "Serializes an `Image` as PNG.");
+impl_serde_with!(
+    jpeg,
+    ImageCodec::Jpeg { quality: 95 },
+    "Serializes an `Image` as JPEG, with the quality of 95."
+);
+impl_serde_with!(qoi, ImageCodec::Qoi, "Serializes an `Image` as QOI.");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::sample_frame;
+
+    #[test]
+    fn round_trip_lossless() {
+        let image = sample_frame().image;
+
+        for codec in &[ImageCodec::Raw, ImageCodec::Png, ImageCodec::Qoi] {
+            let bytes = bincode::serialize(&Compressed::new(&image, *codec)).unwrap();
+            let clone: Image = bincode::deserialize(&bytes).unwrap();
+
+            assert_eq!(image.rows(), clone.rows());
+            assert_eq!(image.cols(), clone.cols());
+            assert_eq!(image.typ().unwrap(), clone.typ().unwrap());
+
+            let row = unsafe { std::slice::from_raw_parts(clone.ptr(5).unwrap(), 32 * 3) };
+            assert_eq!(&row[7 * 3..8 * 3], &[10, 20, 30]);
+        }
+    }
+
+    #[test]
+    fn round_trip_jpeg_frame() {
+        let frame = sample_frame();
+
+        let codec = ImageCodec::Jpeg { quality: 90 };
+        let raw = bincode::serialize(&frame).unwrap();
+        let bytes = bincode::serialize(&Compressed::new(&frame, codec)).unwrap();
+        assert!(bytes.len() < raw.len());
+
+        let clone: Frame = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(frame.timestamp, clone.timestamp);
+        assert_eq!(frame.image.rows(), clone.image.rows());
+        assert_eq!(frame.image.cols(), clone.image.cols());
+    }
+
+    #[test]
+    fn decode_max_len() {
+        let image = sample_frame().image;
+        let len = 24 * 32 * 3;
+
+        let codecs = [
+            ImageCodec::Png,
+            ImageCodec::Jpeg { quality: 90 },
+            ImageCodec::Qoi,
+        ];
+        for codec in &codecs {
+            let data = codec.encode(&image).unwrap();
+            if *codec != ImageCodec::Qoi {
+                assert_eq!(declared_len(&data), Some(len));
+            }
+            assert!(decode(&data, len).is_ok());
+            assert!(decode(&data, len - 1).is_err());
+        }
+
+        // a truncated header
+        let data = ImageCodec::Png.encode(&image).unwrap();
+        assert_eq!(declared_len(&data[..20]), None);
+        assert!(decode(&data[..20], len).is_err());
+    }
+
+    #[test]
+    fn serde_with() {
+        #[derive(Serialize, Deserialize)]
+        struct Record {
+            #[serde(with = "crate::codec::png")]
+            image: Image,
+        }
+
+        let record = Record {
+            image: sample_frame().image,
+        };
+        let bytes = bincode::serialize(&record).unwrap();
+        let clone: Record = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(record.image.rows(), clone.image.rows());
+    }
+}
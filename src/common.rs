@@ -2,13 +2,22 @@ use std::collections::btree_map::{Keys, Values};
 use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{mpsc, Arc};
 
-use crate::config::Config;
+use crate::calibration::{Calibration, Intrinsics};
+use crate::cam::{SharedReader, StereoCapture};
+use crate::config::{Config, SpawnContext};
 #[cfg(feature = "simple-socket")]
 use crate::export::EyeExportServerHandler;
 use crate::frame::Frame;
+use crate::group::{self, FrameGroup};
+use crate::health::{HealthChecker, HealthStatus};
+use crate::motion::{MotionDetector, MotionEvent, MotionSubscribers};
+use crate::overlay::{OverlayConfig, OverlayTarget};
+use crate::record::{EventRecorder, VideoRecorder};
+use crate::snapshot::ImageFormat;
 
+use chrono::Duration;
 use podo_core_driver::*;
 
 pub type ArcVideoReader = Arc<dyn VideoReader>;
@@ -17,32 +26,88 @@ pub trait VideoReader: Send + Sync {
     fn start(&self) -> Result<(), RuntimeError>;
     fn stop(&self) -> Result<(), RuntimeError>;
 
+    /// Starts the reader on behalf of a remote consumer, given the relay route of its request.
+    #[cfg(feature = "simple-socket")]
+    #[inline]
+    fn start_relay(&self, _route: &[u64]) -> Result<(), RuntimeError> {
+        self.start()
+    }
+
     fn is_running(&self) -> bool;
 
+    /// Restarts the reader if the caller holds its only lease, returning whether restarted.
+    #[inline]
+    fn restart_exclusive(&self) -> Result<bool, RuntimeError> {
+        Ok(false)
+    }
+
     fn is_export(&self) -> bool;
 
     fn get(&self, old: &mut Option<Frame>) -> Result<(), RuntimeError>;
+
+    /// Returns the pinhole parameters of the frames, if the reader has been calibrated.
+    #[inline]
+    fn intrinsics(&self) -> Option<Intrinsics> {
+        None
+    }
 }
 
-pub struct EyeDriver {
-    inner: BTreeMap<String, ArcVideoReader>,
+/// A lease of a `SharedReader`, taken by `start` and given back by `stop` just once.
+///
+/// A dropped lease is given back too, ignoring the result of the reader.
+pub(crate) struct Lease(Option<ArcVideoReader>);
+
+impl Lease {
+    pub(crate) fn new(reader: &ArcVideoReader) -> Result<Self, RuntimeError> {
+        reader.start()?;
+        Ok(Self(Some(reader.clone())))
+    }
+
+    /// Takes a lease on behalf of a remote consumer, given the relay route of its request.
     #[cfg(feature = "simple-socket")]
-    export: EyeExportServerHandler,
+    pub(crate) fn relay(reader: &ArcVideoReader, route: &[u64]) -> Result<Self, RuntimeError> {
+        reader.start_relay(route)?;
+        Ok(Self(Some(reader.clone())))
+    }
+
+    /// Gives the lease back, with the result of the reader.
+    pub(crate) fn release(mut self) -> Result<(), RuntimeError> {
+        self.0.take().unwrap().stop()
+    }
 }
 
-#[cfg(feature = "simple-socket")]
-impl From<BTreeMap<String, ArcVideoReader>> for EyeDriver {
-    fn from(inner: BTreeMap<String, ArcVideoReader>) -> Self {
-        let export = EyeExportServerHandler::new(&inner);
-        export.start().unwrap();
-        Self { inner, export }
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(reader) = self.0.take() {
+            reader.stop().ok();
+        }
     }
 }
 
-#[cfg(not(feature = "simple-socket"))]
+pub struct EyeDriver {
+    inner: BTreeMap<String, ArcVideoReader>,
+    recorders: BTreeMap<String, VideoRecorder>,
+    events: BTreeMap<String, Arc<EventRecorder>>,
+    motions: BTreeMap<String, MotionDetector>,
+    healths: BTreeMap<String, HealthChecker>,
+    stereos: BTreeMap<String, Arc<StereoCapture>>,
+    overlays: BTreeMap<String, Arc<OverlayConfig>>,
+    subscribers: Arc<MotionSubscribers>,
+    #[cfg(feature = "simple-socket")]
+    export: EyeExportServerHandler,
+}
+
+/// Shares every reader, so that its users and consumers hold leases of their own.
 impl From<BTreeMap<String, ArcVideoReader>> for EyeDriver {
     fn from(inner: BTreeMap<String, ArcVideoReader>) -> Self {
-        Self { inner }
+        let inner = inner
+            .into_iter()
+            .map(|(name, reader)| {
+                let reader: ArcVideoReader = Arc::new(SharedReader::new(reader));
+                (name, reader)
+            })
+            .collect();
+        Self::new(inner)
     }
 }
 
@@ -59,6 +124,39 @@ impl<'a> Into<BTreeMap<String, ArcVideoReader>> for &'a EyeDriver {
 }
 
 impl EyeDriver {
+    /// Creates a driver of the readers, which should be `SharedReader`s.
+    #[cfg(feature = "simple-socket")]
+    fn new(inner: BTreeMap<String, ArcVideoReader>) -> Self {
+        let export = EyeExportServerHandler::new(&inner);
+        export.start().unwrap();
+        Self {
+            inner,
+            recorders: BTreeMap::new(),
+            events: BTreeMap::new(),
+            motions: BTreeMap::new(),
+            healths: BTreeMap::new(),
+            stereos: BTreeMap::new(),
+            overlays: BTreeMap::new(),
+            subscribers: Default::default(),
+            export,
+        }
+    }
+
+    /// Creates a driver of the readers, which should be `SharedReader`s.
+    #[cfg(not(feature = "simple-socket"))]
+    fn new(inner: BTreeMap<String, ArcVideoReader>) -> Self {
+        Self {
+            inner,
+            recorders: BTreeMap::new(),
+            events: BTreeMap::new(),
+            motions: BTreeMap::new(),
+            healths: BTreeMap::new(),
+            stereos: BTreeMap::new(),
+            overlays: BTreeMap::new(),
+            subscribers: Default::default(),
+        }
+    }
+
     #[inline]
     pub fn get(&self, name: &str) -> Option<&ArcVideoReader> {
         self.inner.get(name)
@@ -73,11 +171,146 @@ impl EyeDriver {
     pub fn readers(&self) -> Values<String, ArcVideoReader> {
         self.inner.values()
     }
+
+    #[inline]
+    pub fn recorder(&self, name: &str) -> Option<&VideoRecorder> {
+        self.recorders.get(name)
+    }
+
+    #[inline]
+    pub fn event_recorder(&self, name: &str) -> Option<&EventRecorder> {
+        self.events.get(name).map(|event| &**event)
+    }
+
+    #[inline]
+    pub fn motion_detector(&self, name: &str) -> Option<&MotionDetector> {
+        self.motions.get(name)
+    }
+
+    #[inline]
+    pub fn health_checker(&self, name: &str) -> Option<&HealthChecker> {
+        self.healths.get(name)
+    }
+
+    /// Returns a stereo reader, to get its pairs apart.
+    #[inline]
+    pub fn stereo(&self, name: &str) -> Option<&StereoCapture> {
+        self.stereos.get(name).map(|stereo| &**stereo)
+    }
+
+    /// Returns the overlay of a reader, if it is drawn on the target.
+    #[inline]
+    pub fn overlay(&self, name: &str, target: OverlayTarget) -> Option<Arc<OverlayConfig>> {
+        self.overlays
+            .get(name)
+            .filter(|overlay| overlay.is_on(target))
+            .cloned()
+    }
+
+    /// Returns the health of a reader, if it is watched.
+    #[inline]
+    pub fn health(&self, name: &str) -> Option<HealthStatus> {
+        self.healths.get(name).map(HealthChecker::status)
+    }
+
+    /// Fails with the unhealthy readers, if any.
+    fn check_health(&self) -> Result<(), RuntimeError> {
+        let unhealthy: Vec<_> = self
+            .healths
+            .iter()
+            .map(|(name, checker)| (name, checker.status().state))
+            .filter(|(_, state)| !state.is_healthy())
+            .map(|(name, state)| format!("{} ({:?})", name, state))
+            .collect();
+        match unhealthy.is_empty() {
+            true => Ok(()),
+            false => RuntimeError::message(format!("Unhealthy readers: {}", unhealthy.join(", "))),
+        }
+    }
+
+    /// Returns a channel receiving the motion events of every reader, from now on.
+    #[inline]
+    pub fn motion_events(&self) -> mpsc::Receiver<MotionEvent> {
+        self.subscribers.subscribe()
+    }
+
+    /// Saves the current frame of a reader, as PNG or JPEG after the extension of the path.
+    ///
+    /// The reader is leased for the snapshot.
+    pub fn snapshot<P: AsRef<Path>>(
+        &self,
+        name: &str,
+        path: P,
+        with_tags: bool,
+    ) -> Result<(), RuntimeError> {
+        let reader = match self.inner.get(name) {
+            Some(reader) => reader,
+            None => return RuntimeError::message(format!("No such reader: {}", name)),
+        };
+        let format = match ImageFormat::from_path(&path) {
+            Some(format) => format,
+            None => return RuntimeError::expect("The extension should be png, jpg or jpeg"),
+        };
+
+        let lease = Lease::new(reader)?;
+        let mut frame = None;
+        let result = reader.get(&mut frame);
+        lease.release()?;
+        result?;
+
+        let frame = frame.unwrap();
+        match with_tags {
+            true => frame.save_with_tags(path, format, name),
+            false => frame.save(path, format),
+        }
+    }
+
+    /// Gets a frame of each reader, whose timestamps are within the tolerance.
+    ///
+    /// A partial result, not aligned, is returned after a second.
+    #[inline]
+    pub fn get_group(
+        &self,
+        names: &[&str],
+        tolerance: Duration,
+    ) -> Result<FrameGroup, RuntimeError> {
+        self.get_group_timeout(names, tolerance, Duration::seconds(1))
+    }
+
+    /// Gets a frame of each reader, whose timestamps are within the tolerance.
+    ///
+    /// The readers are leased for the group.
+    pub fn get_group_timeout(
+        &self,
+        names: &[&str],
+        tolerance: Duration,
+        timeout: Duration,
+    ) -> Result<FrameGroup, RuntimeError> {
+        let readers = names
+            .iter()
+            .map(|name| match self.inner.get(*name) {
+                Some(reader) => Ok(reader),
+                None => RuntimeError::message(format!("No such reader: {}", name)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // the leases taken so far are given back on a failure
+        let leases = readers
+            .iter()
+            .map(|reader| Lease::new(reader))
+            .collect::<Result<Vec<_>, _>>()?;
+        let result = group::collect(&readers, tolerance, timeout);
+        for lease in leases {
+            lease.release()?;
+        }
+        result
+    }
 }
 
 impl Driver for EyeDriver {
     #[cfg(not(feature = "simple-socket"))]
     fn status(&self) -> Result<DriverState, RuntimeError> {
+        self.check_health()?;
         if self.inner.values().any(|r| r.is_running()) {
             Ok(DriverState::Running(DriverRunningState::Normal))
         } else {
@@ -87,6 +320,7 @@ impl Driver for EyeDriver {
 
     #[cfg(feature = "simple-socket")]
     fn status(&self) -> Result<DriverState, RuntimeError> {
+        self.check_health()?;
         if self.export.is_busy() {
             Ok(DriverState::Running(DriverRunningState::Busy))
         } else if self.inner.values().any(|r| r.is_running()) {
@@ -120,14 +354,94 @@ impl EyeDriver {
         path: P,
         params: &DriverParams,
     ) -> Result<Self, RuntimeError> {
-        let driver = serde_yaml::from_value::<Config>(params.clone())?
-            .0
-            .into_iter()
-            .map(|(name, config)| {
-                let reader = config.spawn(&name, &path)?;
-                Ok((name, reader))
-            })
-            .collect::<Result<BTreeMap<_, _>, RuntimeError>>()?;
-        Ok(EyeDriver::from(driver))
+        let mut recorders = vec![];
+        let mut events = vec![];
+        let mut motions = vec![];
+        let mut healths = vec![];
+        let mut overlays = BTreeMap::new();
+        let mut ctx = SpawnContext::default();
+        let mut configs = serde_yaml::from_value::<Config>(params.clone())?;
+        // the upstreams are spawned before their consumers
+        for name in configs.spawn_order()? {
+            let config = configs.0.remove(&name).unwrap();
+            let mut pipeline = config.pipeline.unwrap_or_default();
+            if let Some(privacy) = config.privacy {
+                pipeline = pipeline.with_privacy(privacy);
+            }
+            if let Some(calibration) = config.calibration {
+                let calibration = Calibration::load(path.as_ref().join(calibration))?;
+                pipeline = pipeline.with_undistortion(calibration.undistortion()?);
+            }
+            let reader = config.source.spawn(&name, &path, pipeline, &mut ctx)?;
+            if let Some(record) = config.record {
+                recorders.push((name.clone(), record));
+            }
+            if let Some(event) = config.event {
+                events.push((name.clone(), event));
+            }
+            if let Some(motion) = config.motion {
+                motions.push((name.clone(), motion));
+            }
+            if let Some(health) = config.health {
+                healths.push((name.clone(), health));
+            }
+            if let Some(overlay) = config.overlay {
+                overlay.validate()?;
+                overlays.insert(name.clone(), Arc::new(overlay));
+            }
+            ctx.insert(name, reader);
+        }
+
+        let stereos = ctx.take_stereos();
+        let mut driver = EyeDriver::new(ctx.into_readers());
+        driver.stereos = stereos;
+        driver.overlays = overlays;
+        #[cfg(feature = "simple-socket")]
+        for name in driver.overlays.keys() {
+            if let Some(overlay) = driver.overlay(name, OverlayTarget::Export) {
+                driver.export.insert_overlay(name.clone(), overlay);
+            }
+        }
+        for (name, config) in recorders {
+            let reader = driver.inner[&name].clone();
+            let mut recorder = VideoRecorder::new(&name, reader, config, &path)?;
+            if let Some(overlay) = driver.overlay(&name, OverlayTarget::Record) {
+                recorder = recorder.with_overlay(overlay);
+            }
+            recorder.start()?;
+            driver.recorders.insert(name, recorder);
+        }
+        for (name, config) in events {
+            let reader = driver.inner[&name].clone();
+            let mut recorder = EventRecorder::new(&name, reader, config, &path)?;
+            if let Some(overlay) = driver.overlay(&name, OverlayTarget::Event) {
+                recorder = recorder.with_overlay(overlay);
+            }
+            recorder.start()?;
+            driver.events.insert(name, Arc::new(recorder));
+        }
+        for (name, config) in motions {
+            let reader = driver.inner[&name].clone();
+            let event = match config.trigger_event.unwrap_or_default() {
+                true => match driver.events.get(&name) {
+                    Some(event) => Some(event.clone()),
+                    None => {
+                        return RuntimeError::message(format!("No event recorder of {}", name));
+                    }
+                },
+                false => None,
+            };
+            let subscribers = driver.subscribers.clone();
+            let detector = MotionDetector::new(&name, reader, config, subscribers, event)?;
+            detector.start()?;
+            driver.motions.insert(name, detector);
+        }
+        for (name, config) in healths {
+            let reader = driver.inner[&name].clone();
+            let checker = HealthChecker::new(reader, config);
+            checker.start()?;
+            driver.healths.insert(name, checker);
+        }
+        Ok(driver)
     }
 }
@@ -1,9 +1,17 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::Path;
+use std::sync::Arc;
 
 use crate::cam::*;
 use crate::common::{ArcVideoReader, VideoReader};
+use crate::health::HealthConfig;
+use crate::motion::MotionConfig;
+use crate::overlay::OverlayConfig;
+use crate::pipeline::Pipeline;
+use crate::privacy::PrivacyMask;
+use crate::record::{EventConfig, RecordConfig};
 
+use chrono::{DateTime, Utc};
 use opencv::imgproc::*;
 use opencv::prelude::*;
 use opencv::videoio;
@@ -12,36 +20,192 @@ use podo_core_driver::*;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize)]
-pub struct Config(pub(crate) HashMap<String, OneConfig>);
+pub struct Config(pub(crate) HashMap<String, ReaderConfig>);
+
+impl Config {
+    /// Returns the names of the readers, each after its upstreams.
+    pub(crate) fn spawn_order(&self) -> Result<Vec<String>, RuntimeError> {
+        let mut order: Vec<String> = vec![];
+        let mut pending: Vec<&String> = self.0.keys().collect();
+        pending.sort();
+        while !pending.is_empty() {
+            let (ready, rest): (Vec<&String>, Vec<&String>) =
+                pending.into_iter().partition(|name| {
+                    let upstreams = self.0[*name].source.upstreams();
+                    upstreams
+                        .iter()
+                        .all(|upstream| order.iter().any(|n| n == upstream))
+                });
+            if ready.is_empty() {
+                let names: Vec<_> = rest.iter().map(|name| name.as_str()).collect();
+                return RuntimeError::message(format!(
+                    "Unresolved or cyclic upstreams: {}",
+                    names.join(", ")
+                ));
+            }
+            order.extend(ready.into_iter().cloned());
+            pending = rest;
+        }
+        Ok(order)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReaderConfig {
+    #[serde(flatten)]
+    pub(crate) source: OneConfig,
+
+    /// The calibration file of OpenCV, relative to the config file.
+    pub(crate) calibration: Option<String>,
+    pub(crate) pipeline: Option<Pipeline>,
+    /// The regions hidden from every consumer, in the coordinates of the raw frames.
+    pub(crate) privacy: Option<Vec<PrivacyMask>>,
+    /// The text burnt into the exported and recorded frames.
+    pub(crate) overlay: Option<OverlayConfig>,
+    pub(crate) record: Option<RecordConfig>,
+    pub(crate) event: Option<EventConfig>,
+    pub(crate) motion: Option<MotionConfig>,
+    pub(crate) health: Option<HealthConfig>,
+}
 
 #[derive(Debug, Deserialize)]
 pub enum OneConfig {
     Cam(CamConfig),
     Video(VideoConfig),
     Rtsp(RtspConfig),
+    Log(LogConfig),
+    Sequence(SequenceConfig),
+    Derived(DerivedConfig),
+    Stereo(StereoConfig),
+    Mosaic(MosaicConfig),
+    Panorama(PanoramaConfig),
     #[cfg(feature = "simple-socket")]
     Client(ClientConfig),
 }
 
+/// The states shared among the readers while spawning them.
+#[derive(Default)]
+pub(crate) struct SpawnContext {
+    log_clocks: HashMap<PathBuf, Arc<LogClock>>,
+    sync_groups: HashMap<String, Arc<SyncGroup>>,
+    stereos: BTreeMap<String, Arc<StereoCapture>>,
+    readers: BTreeMap<String, ArcVideoReader>,
+}
+
+impl SpawnContext {
+    /// Keeps a spawned reader, shared by its consumers and the users of `EyeDriver`.
+    #[inline]
+    pub(crate) fn insert(&mut self, name: String, reader: ArcVideoReader) {
+        self.readers
+            .insert(name, Arc::new(SharedReader::new(reader)));
+    }
+
+    /// Returns the stereo readers spawned so far, to get their pairs apart.
+    #[inline]
+    pub(crate) fn take_stereos(&mut self) -> BTreeMap<String, Arc<StereoCapture>> {
+        std::mem::take(&mut self.stereos)
+    }
+
+    #[inline]
+    pub(crate) fn into_readers(self) -> BTreeMap<String, ArcVideoReader> {
+        self.readers
+    }
+
+    /// Returns a reader consumed by another one.
+    fn upstream(&mut self, name: &str) -> Result<ArcVideoReader, RuntimeError> {
+        match self.readers.get(name) {
+            Some(reader) => Ok(reader.clone()),
+            None => RuntimeError::message(format!("No such upstream: {}", name)),
+        }
+    }
+
+    /// Returns the replay clock shared by the streams of a log file.
+    fn log_clock(&mut self, path: PathBuf) -> Arc<LogClock> {
+        self.log_clocks.entry(path).or_default().clone()
+    }
+
+    /// Returns the sync group shared by the cameras of the same name.
+    fn sync_group(&mut self, name: String) -> Arc<SyncGroup> {
+        self.sync_groups.entry(name).or_default().clone()
+    }
+}
+
 impl OneConfig {
+    /// Returns the names of the readers to spawn before this one.
+    pub(crate) fn upstreams(&self) -> Vec<&str> {
+        match self {
+            Self::Derived(config) => vec![&config.upstream],
+            Self::Stereo(config) => vec![&config.left, &config.right],
+            Self::Mosaic(config) => config.inputs.iter().map(String::as_str).collect(),
+            Self::Panorama(config) => config.inputs.iter().map(String::as_str).collect(),
+            _ => vec![],
+        }
+    }
+
     pub(crate) fn spawn<P: AsRef<Path>>(
         self,
-        _name: &str,
+        name: &str,
         path: P,
+        pipeline: Pipeline,
+        ctx: &mut SpawnContext,
     ) -> Result<ArcVideoReader, RuntimeError> {
+        pipeline.validate()?;
         let reader: Box<dyn VideoReader> = match self {
-            crate::config::OneConfig::Cam(config) => {
-                Box::new(VideoCapture::from_config(config, path)?)
-            }
+            crate::config::OneConfig::Cam(config) => match config.sync.clone() {
+                Some(sync) => {
+                    let group = ctx.sync_group(sync);
+                    Box::new(SyncCapture::from_config(config, path, group)?.with_pipeline(pipeline))
+                }
+                None => Box::new(VideoCapture::from_config(config, path)?.with_pipeline(pipeline)),
+            },
             crate::config::OneConfig::Video(config) => {
-                Box::new(VideoCapture::from_config(config, path)?)
+                Box::new(VideoCapture::from_config(config, path)?.with_pipeline(pipeline))
             }
             crate::config::OneConfig::Rtsp(config) => {
-                Box::new(VideoCapture::from_config(config, path)?)
+                Box::new(VideoCapture::from_config(config, path)?.with_pipeline(pipeline))
+            }
+            crate::config::OneConfig::Log(config) => {
+                let clock = ctx.log_clock(path.as_ref().join(&config.path));
+                Box::new(LogCapture::from_config(config, path, clock)?.with_pipeline(pipeline))
+            }
+            crate::config::OneConfig::Sequence(config) => {
+                Box::new(SequenceCapture::from_config(config, path)?.with_pipeline(pipeline))
+            }
+            crate::config::OneConfig::Derived(config) => {
+                let upstream = ctx.upstream(&config.upstream)?;
+                Box::new(DerivedCapture::from_config(config, upstream)?.with_pipeline(pipeline))
+            }
+            crate::config::OneConfig::Stereo(config) => {
+                // the pairs are split apart by their geometry
+                if !pipeline.is_empty() {
+                    return RuntimeError::expect("The stereo reader should not have a pipeline");
+                }
+                let left = ctx.upstream(&config.left)?;
+                let right = ctx.upstream(&config.right)?;
+                let reader = Arc::new(StereoCapture::from_config(config, path, left, right)?);
+                ctx.stereos.insert(name.to_string(), reader.clone());
+                return Ok(reader);
+            }
+            crate::config::OneConfig::Mosaic(config) => {
+                let inputs = config
+                    .inputs
+                    .iter()
+                    .map(|input| ctx.upstream(input))
+                    .collect::<Result<_, _>>()?;
+                Box::new(MosaicCapture::from_config(config, inputs)?.with_pipeline(pipeline))
+            }
+            crate::config::OneConfig::Panorama(config) => {
+                let inputs = config
+                    .inputs
+                    .iter()
+                    .map(|input| ctx.upstream(input))
+                    .collect::<Result<_, _>>()?;
+                let reader = PanoramaCapture::from_config(config, path, inputs)?;
+                Box::new(reader.with_pipeline(pipeline))
             }
             #[cfg(feature = "simple-socket")]
             crate::config::OneConfig::Client(config) => {
-                Box::new(ClientCapture::from_config(config, _name)?)
+                Box::new(ClientCapture::from_config(config, name)?.with_pipeline(pipeline))
             }
         };
         Ok(reader.into())
@@ -54,6 +218,15 @@ pub trait Configurable: Send + Sync {
 
     fn is_export(&self) -> bool;
 
+    /// Returns the recorded counts and the original timestamps of the frames to replay, if any.
+    #[inline]
+    fn timeline(
+        &self,
+        _path: &PathBuf,
+    ) -> Result<Option<Vec<(usize, DateTime<Utc>)>>, RuntimeError> {
+        Ok(None)
+    }
+
     #[inline]
     fn spawn(&self, path: &PathBuf) -> Result<(videoio::VideoCapture, VideoColor), RuntimeError> {
         let preference = videoio::CAP_ANY;
@@ -62,13 +235,8 @@ pub trait Configurable: Send + Sync {
         let mut camera = videoio::VideoCapture::from_file(&self.filename(path)?, preference)?;
         {
             if let Some(codec) = meta.codec.as_ref() {
-                if let 4 = codec.len() {
-                    let codec = unsafe { &*(codec.as_bytes() as *const [u8] as *const [i8]) };
-                    let (c1, c2, c3, c4) = (codec[0], codec[1], codec[2], codec[3]);
-                    camera.set(
-                        videoio::CAP_PROP_FOURCC,
-                        videoio::VideoWriter::fourcc(c1, c2, c3, c4)?.into(),
-                    )?;
+                if let Some(fourcc) = fourcc(codec)? {
+                    camera.set(videoio::CAP_PROP_FOURCC, fourcc.into())?;
                 }
             }
 
@@ -84,6 +252,18 @@ pub trait Configurable: Send + Sync {
     }
 }
 
+/// Returns the FOURCC code of a 4-character codec name, such as `MJPG`.
+pub(crate) fn fourcc(codec: &str) -> Result<Option<i32>, RuntimeError> {
+    match codec.len() {
+        4 => {
+            let codec = unsafe { &*(codec.as_bytes() as *const [u8] as *const [i8]) };
+            let (c1, c2, c3, c4) = (codec[0], codec[1], codec[2], codec[3]);
+            Ok(Some(videoio::VideoWriter::fourcc(c1, c2, c3, c4)?))
+        }
+        _ => Ok(None),
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct VideoMeta {
     pub(crate) codec: Option<String>,
@@ -132,3 +312,42 @@ impl Default for VideoColor {
         Self::Color
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_spawn_order() {
+        let config: Config = serde_yaml::from_str(
+            "
+            wall:
+                Mosaic:
+                    inputs: [gray, main]
+                    tile: { width: 320, height: 240 }
+                    fps: 10
+            gray:
+                Derived: { upstream: main }
+            main:
+                Sequence: { path: dataset }
+            ",
+        )
+        .unwrap();
+        assert_eq!(config.spawn_order().unwrap(), vec!["main", "gray", "wall"]);
+
+        let config: Config = serde_yaml::from_str(
+            "
+            a:
+                Derived: { upstream: b }
+            b:
+                Derived: { upstream: a }
+            c:
+                Derived: { upstream: missing }
+            main:
+                Sequence: { path: dataset }
+            ",
+        )
+        .unwrap();
+        assert!(config.spawn_order().is_err());
+    }
+}
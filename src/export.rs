@@ -1,10 +1,15 @@
 use std::collections::BTreeMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::common::ArcVideoReader;
+use crate::common::{ArcVideoReader, Lease};
 use crate::frame::Frame;
+use crate::overlay::OverlayConfig;
+use crate::protocol::{Encoding, Handshake, Packet, PROTOCOL_VERSION};
+use crate::snapshot::ImageFormat;
 
 use podo_core_driver::{AliveFlag, RuntimeError};
 use serde::{Deserialize, Serialize};
@@ -14,6 +19,7 @@ pub struct EyeExportServerHandler {
     alive: AliveFlag,
     busy: AliveFlag,
     nodes: BTreeMap<String, ArcVideoReader>,
+    overlays: Arc<RwLock<BTreeMap<String, Arc<OverlayConfig>>>>,
     inner: Mutex<Option<thread::JoinHandle<Result<(), RuntimeError>>>>,
 }
 
@@ -27,9 +33,15 @@ impl EyeExportServerHandler {
                 .filter(|(_, r)| r.is_export())
                 .map(|(n, r)| (n.clone(), r.clone()))
                 .collect(),
+            overlays: Default::default(),
             inner: Mutex::new(None),
         }
     }
+
+    /// Draws the overlay on the exported frames of a reader, even while running.
+    pub(crate) fn insert_overlay(&self, name: String, overlay: Arc<OverlayConfig>) {
+        self.overlays.write().unwrap().insert(name, overlay);
+    }
 }
 
 impl EyeExportServerHandler {
@@ -46,12 +58,13 @@ impl EyeExportServerHandler {
             return Ok(());
         }
 
-        let count = self.nodes.keys().map(|n| (n.clone(), 0)).collect();
+        let leases = self.nodes.keys().map(|n| (n.clone(), vec![])).collect();
 
         let server = EyeExportServer {
             alive: self.alive.clone(),
             busy: self.busy.clone(),
-            count,
+            leases,
+            overlays: self.overlays.clone(),
             inner: self.nodes.clone(),
         };
 
@@ -85,7 +98,9 @@ pub struct EyeExportServer {
     alive: AliveFlag,
     busy: AliveFlag,
 
-    count: BTreeMap<String, usize>,
+    /// The leases of the remote consumers, one per `Start`.
+    leases: BTreeMap<String, Vec<Lease>>,
+    overlays: Arc<RwLock<BTreeMap<String, Arc<OverlayConfig>>>>,
     inner: BTreeMap<String, ArcVideoReader>,
 }
 
@@ -101,31 +116,52 @@ impl EyeExportServer {
         let alive = self.alive.clone();
         let busy = self.busy.clone();
 
-        let handler = |req: EyeRequest| {
-            let reader = match self.inner.get(&req.reader) {
-                Some(reader) => reader,
-                None => return EyeResponse::NoSuchReader(req.reader),
-            };
+        // the encodings of the sessions, forgotten once no connection is left
+        let sessions = Mutex::new(BTreeMap::new());
+        let mut last_session = 0;
 
-            match req.typ {
-                EyeRequestType::Start => {
-                    *self.count.get_mut(&req.reader).unwrap() += 1;
-                    reader.start().ok();
-                    EyeResponse::Awk
+        let handler = |packet: Packet| {
+            let session = packet.session;
+            let encoding = match session {
+                // the handshake is always bincode
+                0 => Encoding::Bincode,
+                _ => match sessions.lock().unwrap().get(&session) {
+                    Some(encoding) => *encoding,
+                    None => return respond(0, Encoding::Bincode, EyeResponse::NoHandshake),
+                },
+            };
+            let req: EyeRequest = match packet.open(encoding) {
+                Ok(req) => req,
+                Err(e) => {
+                    return respond(session, encoding, EyeResponse::Failed(format!("{:?}", e)))
                 }
-                EyeRequestType::Stop => {
-                    *self.count.get_mut(&req.reader).unwrap() -= 1;
-                    if self.count[&req.reader] == 0 {
-                        reader.stop().ok();
+            };
+
+            match (session, req.typ) {
+                (0, EyeRequestType::Hello(hello)) => {
+                    if hello.version != PROTOCOL_VERSION {
+                        return respond(0, encoding, EyeResponse::Unsupported(PROTOCOL_VERSION));
                     }
-                    EyeResponse::Awk
+                    let negotiated = match hello.negotiate() {
+                        Some(negotiated) => negotiated,
+                        None => {
+                            let e = format!("Unsupported encodings: {:?}", hello.encodings);
+                            return respond(0, encoding, EyeResponse::Failed(e));
+                        }
+                    };
+                    last_session += 1;
+                    sessions.lock().unwrap().insert(last_session, negotiated);
+
+                    let hello = Handshake {
+                        version: PROTOCOL_VERSION,
+                        encodings: vec![negotiated],
+                    };
+                    respond(last_session, encoding, EyeResponse::Hello(hello))
                 }
-                EyeRequestType::Get => {
-                    let mut buffer = None;
-                    match reader.get(&mut buffer) {
-                        Ok(()) => EyeResponse::Frame(Ok(buffer.unwrap())),
-                        Err(e) => EyeResponse::Frame(Err(format!("{:?}", e))),
-                    }
+                (0, _) => respond(0, encoding, EyeResponse::NoHandshake),
+                (_, typ) => {
+                    let req = EyeRequest { typ, ..req };
+                    respond(session, encoding, self.handle(req))
                 }
             }
         };
@@ -135,6 +171,7 @@ impl EyeExportServer {
                 busy.start().ok();
             } else {
                 busy.stop().ok();
+                sessions.lock().unwrap().clear();
             }
 
             if alive.is_running() {
@@ -147,26 +184,234 @@ impl EyeExportServer {
         self.busy.stop().ok();
         Ok(())
     }
+
+    fn handle(&mut self, req: EyeRequest) -> EyeResponse {
+        let reader = match self.inner.get(&req.reader) {
+            Some(reader) => reader,
+            None => return EyeResponse::NoSuchReader(req.reader),
+        };
+
+        match req.typ {
+            EyeRequestType::Start => {
+                // the request has already passed through this process
+                if req.route.contains(&relay_id()) {
+                    return EyeResponse::RelayLoop(req.reader);
+                }
+
+                // the upstream of the reader, if any, is leased through the same route
+                let mut route = req.route;
+                route.push(relay_id());
+                match Lease::relay(reader, &route) {
+                    Ok(lease) => {
+                        self.leases.get_mut(&req.reader).unwrap().push(lease);
+                        EyeResponse::Awk
+                    }
+                    Err(e) => EyeResponse::Failed(format!("{:?}", e)),
+                }
+            }
+            EyeRequestType::Stop => match self.leases.get_mut(&req.reader).unwrap().pop() {
+                Some(lease) => match lease.release() {
+                    Ok(()) => EyeResponse::Awk,
+                    Err(e) => EyeResponse::Failed(format!("{:?}", e)),
+                },
+                None => EyeResponse::Awk,
+            },
+            EyeRequestType::Get => EyeResponse::Frame(
+                self.get(&req.reader, reader)
+                    .map_err(|e| format!("{:?}", e)),
+            ),
+            EyeRequestType::Snapshot(format) => {
+                let data = self
+                    .get(&req.reader, reader)
+                    .and_then(|frame| frame.snapshot(format, Some(&req.reader)))
+                    .map_err(|e| format!("{:?}", e));
+                EyeResponse::Encoded(data)
+            }
+            EyeRequestType::Hello(_) => {
+                EyeResponse::Failed("The session has already been opened".to_string())
+            }
+        }
+    }
+
+    /// Gets the current frame of a reader, with its overlay if any.
+    fn get(&self, name: &str, reader: &ArcVideoReader) -> Result<Frame, RuntimeError> {
+        let mut buffer = None;
+        reader.get(&mut buffer)?;
+        let mut frame = buffer.unwrap();
+        if let Some(overlay) = self.overlays.read().unwrap().get(name) {
+            overlay.draw(name, &mut frame)?;
+        }
+        Ok(frame)
+    }
+}
+
+/// Seals a response in the encoding of the session.
+fn respond(session: u64, encoding: Encoding, response: EyeResponse) -> Packet {
+    Packet::seal(session, encoding, &response)
+        .or_else(|e| {
+            let response = EyeResponse::Failed(format!("{:?}", e));
+            Packet::seal(session, encoding, &response)
+        })
+        .unwrap()
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct EyeRequest {
     pub reader: String,
     pub typ: EyeRequestType,
+    /// The relay ids of the processes this request has been forwarded through.
+    ///
+    /// The clients of another `PROTOCOL_VERSION` are turned away by the handshake.
+    pub route: Vec<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum EyeRequestType {
+    Hello(Handshake),
     Start,
     Stop,
+    /// Asks for the current frame, encoded along with the envelope.
     Get,
+    /// Asks for the current frame as a tagged PNG or JPEG file, sent as `Encoded`.
+    Snapshot(ImageFormat),
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum EyeResponse {
+    Hello(Handshake),
+    Unsupported(u32),
     Frame(Result<Frame, String>),
+    Encoded(Result<Vec<u8>, String>),
+    /// The request has no session, or an expired one.
+    NoHandshake,
     NoSuchReader(String),
+    RelayLoop(String),
+    Failed(String),
     Awk,
 }
 
 pub const PORT: u16 = 9804;
+
+/// Returns an id which identifies this process among the relays.
+pub fn relay_id() -> u64 {
+    static ID: AtomicU64 = AtomicU64::new(0);
+
+    match ID.load(Ordering::Relaxed) {
+        0 => {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|t| t.as_nanos() as u64)
+                .unwrap_or_default();
+            let id = (nanos ^ (u64::from(std::process::id()) << 32)) | 1;
+            match ID.compare_exchange(0, id, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => id,
+                Err(id) => id,
+            }
+        }
+        id => id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cam::SharedReader;
+    use crate::common::VideoReader;
+
+    /// Keeps the routes of the relayed starts.
+    #[derive(Default)]
+    struct Upstream {
+        alive: AliveFlag,
+        routes: Mutex<Vec<Vec<u64>>>,
+    }
+
+    impl VideoReader for Upstream {
+        fn start(&self) -> Result<(), RuntimeError> {
+            self.start_relay(&[])
+        }
+
+        fn start_relay(&self, route: &[u64]) -> Result<(), RuntimeError> {
+            self.alive.start()?;
+            self.routes.lock().unwrap().push(route.to_vec());
+            Ok(())
+        }
+
+        fn stop(&self) -> Result<(), RuntimeError> {
+            self.alive.stop().ok();
+            Ok(())
+        }
+
+        fn is_running(&self) -> bool {
+            self.alive.is_running()
+        }
+
+        fn is_export(&self) -> bool {
+            true
+        }
+
+        fn get(&self, _frame: &mut Option<Frame>) -> Result<(), RuntimeError> {
+            RuntimeError::unimplemented()
+        }
+    }
+
+    fn request(typ: EyeRequestType, route: Vec<u64>) -> EyeRequest {
+        EyeRequest {
+            reader: "main".to_string(),
+            typ,
+            route,
+        }
+    }
+
+    #[test]
+    fn relay_leases() {
+        let upstream = Arc::new(Upstream::default());
+        let reader: ArcVideoReader = Arc::new(SharedReader::new(upstream.clone()));
+        let mut server = EyeExportServer {
+            alive: AliveFlag::default(),
+            busy: AliveFlag::default(),
+            leases: vec![("main".to_string(), vec![])].into_iter().collect(),
+            overlays: Default::default(),
+            inner: vec![("main".to_string(), reader.clone())]
+                .into_iter()
+                .collect(),
+        };
+        let is_awk = |response| matches!(response, EyeResponse::Awk);
+
+        // a local consumer has started the reader first
+        let local = Lease::new(&reader).unwrap();
+
+        // the remote leases are forwarded with this process on the route
+        assert!(is_awk(
+            server.handle(request(EyeRequestType::Start, vec![7]))
+        ));
+        assert!(is_awk(
+            server.handle(request(EyeRequestType::Start, vec![]))
+        ));
+        assert_eq!(*upstream.routes.lock().unwrap(), vec![Vec::<u64>::new()]);
+
+        local.release().unwrap();
+        assert!(upstream.is_running());
+        assert!(is_awk(server.handle(request(EyeRequestType::Stop, vec![]))));
+        assert!(upstream.is_running());
+        assert!(is_awk(server.handle(request(EyeRequestType::Stop, vec![]))));
+        assert!(!upstream.is_running());
+
+        // the first remote consumer starts the upstream through its route
+        assert!(is_awk(
+            server.handle(request(EyeRequestType::Start, vec![7]))
+        ));
+        assert_eq!(upstream.routes.lock().unwrap()[1], vec![7, relay_id()]);
+        assert!(is_awk(server.handle(request(EyeRequestType::Stop, vec![]))));
+        assert!(!upstream.is_running());
+
+        // a request which has passed through this process is a loop
+        let looped = request(EyeRequestType::Start, vec![7, relay_id()]);
+        assert!(matches!(server.handle(looped), EyeResponse::RelayLoop(_)));
+        assert!(server.leases["main"].is_empty());
+
+        // a stop without a start is ignored
+        assert!(is_awk(server.handle(request(EyeRequestType::Stop, vec![]))));
+        assert!(!upstream.is_running());
+    }
+}
@@ -1,18 +1,23 @@
+use std::borrow::Cow;
 use std::ffi::c_void;
 use std::fmt;
+use std::iter::FromIterator;
 use std::ops;
 
+use crate::codec::{self, ImageCodec, IMAGE_ENCODED};
 use crate::config::VideoMeta;
 
 use chrono::{DateTime, Utc};
-use opencv::core::Mat_AUTO_STEP;
+use opencv::core::{Mat_AUTO_STEP, CV_CN_SHIFT, CV_MAT_DEPTH_MASK};
 use opencv::prelude::{Mat, MatTrait};
+use opencv::types::VectorOfi32;
 use podo_core_driver::RuntimeError;
 use serde::{
     de::{self, MapAccess, SeqAccess, Visitor},
-    ser::SerializeStruct,
+    ser::{self, SerializeStruct},
     Deserialize, Deserializer, Serialize, Serializer,
 };
+use serde_bytes::{ByteBuf, Bytes};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Frame {
@@ -34,6 +39,34 @@ impl Frame {
     }
 }
 
+/// A 32x24 frame of the BGR color (10, 20, 30), for the tests.
+#[cfg(test)]
+pub(crate) fn sample_frame() -> Frame {
+    let color = opencv::core::Scalar::new(10.0, 20.0, 30.0, 0.0);
+    let mat = Mat::new_rows_cols_with_default(24, 32, opencv::core::CV_8UC3, color).unwrap();
+    Frame {
+        image: Image::from(mat),
+        meta: VideoMeta {
+            codec: Some("MJPG".to_string()),
+            color: Some(crate::config::VideoColor::Color),
+            width: 32,
+            height: 24,
+            fps: 30,
+        },
+        timestamp: Utc::now(),
+        count: 7,
+    }
+}
+
+/// The maximum size of the pixel data of a deserialized image.
+pub const MAX_IMAGE_SIZE: usize = 256 << 20;
+
+/// The maximum number of channels of a deserialized image.
+const MAX_CHANNELS: i32 = 4;
+
+/// The maximum number of dimensions of a deserialized image.
+const MAX_DIMS: usize = 32;
+
 #[derive(Debug)]
 pub struct Image {
     inner: Mat,
@@ -48,14 +81,139 @@ impl Image {
         })
     }
 
-    fn from_bytes(rows: i32, cols: i32, typ: i32, mut data: Vec<u8>) -> Result<Self, RuntimeError> {
-        let ptr = data.as_mut_ptr() as *mut c_void;
-        let mat = unsafe { Mat::new_rows_cols_with_data(rows, cols, typ, ptr, Mat_AUTO_STEP)? };
+    fn from_bytes(size: Vec<i32>, typ: i32, mut data: Vec<u8>) -> Result<Self, String> {
+        let len = Self::expected_len(&size, typ)?;
+        if typ & IMAGE_ENCODED != 0 {
+            return Self::from_encoded(size, typ & !IMAGE_ENCODED, &data, len);
+        }
+        if data.len() != len {
+            return Err(format!(
+                "invalid image data: expected {} bytes, got {}",
+                len,
+                data.len()
+            ));
+        }
+        if len == 0 {
+            // an empty image keeps the declared shape and type
+            let size = VectorOfi32::from_iter(size);
+            let mat = unsafe { Mat::new_nd_vec(&size, typ) }.map_err(|e| format!("{:?}", e))?;
+            return Ok(Self::from(mat));
+        }
 
-        Ok(Self {
-            inner: mat,
-            data: Some(data),
-        })
+        match size.as_slice() {
+            &[rows, cols] => {
+                let ptr = data.as_mut_ptr() as *mut c_void;
+                let mat =
+                    unsafe { Mat::new_rows_cols_with_data(rows, cols, typ, ptr, Mat_AUTO_STEP) }
+                        .map_err(|e| format!("{:?}", e))?;
+
+                Ok(Self {
+                    inner: mat,
+                    data: Some(data),
+                })
+            }
+            _ => {
+                let size = VectorOfi32::from_iter(size);
+                let mut mat =
+                    unsafe { Mat::new_nd_vec(&size, typ) }.map_err(|e| format!("{:?}", e))?;
+                let ptr = mat.ptr_mut(0).map_err(|e| format!("{:?}", e))?;
+                unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, len) };
+
+                Ok(Self::from(mat))
+            }
+        }
+    }
+
+    fn from_encoded(size: Vec<i32>, typ: i32, data: &[u8], len: usize) -> Result<Self, String> {
+        if data.len() > MAX_IMAGE_SIZE {
+            return Err(format!("image too large: {} bytes", data.len()));
+        }
+
+        let image = Self::from(codec::decode(data, len)?);
+        let typ_decoded = image.typ().map_err(|e| format!("{:?}", e))?;
+        if image.shape() != size || typ_decoded != typ {
+            return Err(format!(
+                "invalid image data: expected {:?} of type {}, got {:?} of type {}",
+                size,
+                typ,
+                image.shape(),
+                typ_decoded
+            ));
+        }
+        Ok(image)
+    }
+
+    /// Validates the header of an untrusted image and returns the size of its pixel data.
+    fn expected_len(size: &[i32], typ: i32) -> Result<usize, String> {
+        let typ = typ & !IMAGE_ENCODED;
+        if size.len() < 2 || size.len() > MAX_DIMS || size.iter().any(|&s| s < 0) {
+            return Err(format!("invalid image size: {:?}", size));
+        }
+
+        let depth = typ & CV_MAT_DEPTH_MASK;
+        let channels = (typ >> CV_CN_SHIFT) + 1;
+        if typ < 0 || channels > MAX_CHANNELS {
+            return Err(format!("unsupported image type: {}", typ));
+        }
+        let depth_size = match depth {
+            opencv::core::CV_8U | opencv::core::CV_8S => 1,
+            opencv::core::CV_16U | opencv::core::CV_16S => 2,
+            opencv::core::CV_32S | opencv::core::CV_32F => 4,
+            opencv::core::CV_64F => 8,
+            _ => return Err(format!("unsupported image depth: {}", depth)),
+        };
+
+        size.iter()
+            .try_fold(depth_size * channels as usize, |len, &s| {
+                len.checked_mul(s as usize)
+            })
+            .filter(|&len| len <= MAX_IMAGE_SIZE)
+            .ok_or_else(|| format!("image too large: {:?} of type {}", size, typ))
+    }
+
+    /// Returns the size of each dimension, at least 2 of them.
+    fn shape(&self) -> Vec<i32> {
+        match self.inner.dims() {
+            0..=2 => vec![self.inner.rows(), self.inner.cols()],
+            _ => self.inner.mat_size().to_vec(),
+        }
+    }
+
+    /// Returns the pixel data without any gaps between the rows.
+    fn compact_data(&self) -> Result<Cow<[u8]>, RuntimeError> {
+        let elem_size = self.inner.elem_size()?;
+        let len = self.inner.total()? * elem_size;
+        if len == 0 {
+            return Ok(Cow::Borrowed(&[]));
+        }
+
+        if self.inner.is_continuous()? {
+            let ptr = self.inner.ptr(0)?;
+            return Ok(Cow::Borrowed(unsafe {
+                std::slice::from_raw_parts(ptr, len)
+            }));
+        }
+
+        match self.inner.dims() {
+            // a ROI or a padded step
+            2 => {
+                let row_len = self.inner.cols() as usize * elem_size;
+                let mut data = Vec::with_capacity(len);
+                for row in 0..self.inner.rows() {
+                    let ptr = self.inner.ptr(row)?;
+                    data.extend_from_slice(unsafe { std::slice::from_raw_parts(ptr, row_len) });
+                }
+                Ok(Cow::Owned(data))
+            }
+            _ => {
+                let mut compact = Mat::default()?;
+                self.inner.copy_to(&mut compact)?;
+                let ptr = compact.ptr(0)?;
+                Ok(Cow::Owned(
+                    unsafe { std::slice::from_raw_parts(ptr, len) }.to_vec(),
+                ))
+            }
+        }
     }
 }
 
@@ -82,29 +240,43 @@ impl ops::DerefMut for Image {
     }
 }
 
-impl Serialize for Image {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+impl Image {
+    pub(crate) fn serialize_with<S>(
+        &self,
+        codec: ImageCodec,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let rows = self.inner.rows() as usize;
-        let cols = self.inner.cols() as usize;
-        let typ = self.inner.typ().unwrap();
-        let elem_size = self.inner.elem_size().unwrap() as usize;
-
-        let len = rows * cols * elem_size;
+        let typ = self.inner.typ().map_err(ser::Error::custom)?;
+        let (typ, data) = match codec {
+            ImageCodec::Raw => (typ, self.compact_data()),
+            _ => (
+                typ | IMAGE_ENCODED,
+                codec.encode(&self.inner).map(Cow::Owned),
+            ),
+        };
+        let data = data.map_err(|e| ser::Error::custom(format!("{:?}", e)))?;
 
-        let mut state = serializer.serialize_struct("image", 4)?;
-        state.serialize_field("rows", &(rows as i32))?;
-        state.serialize_field("cols", &(cols as i32))?;
+        let mut state = serializer.serialize_struct("image", 3)?;
+        state.serialize_field("size", &self.shape())?;
         state.serialize_field("typ", &typ)?;
-
-        let slice = unsafe { std::slice::from_raw_parts(self.inner.ptr(0).unwrap(), len as usize) };
-        state.serialize_field("data", slice)?;
+        state.serialize_field("data", Bytes::new(&data))?;
         state.end()
     }
 }
 
+impl Serialize for Image {
+    #[inline]
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.serialize_with(ImageCodec::Raw, serializer)
+    }
+}
+
 impl<'de> Deserialize<'de> for Image {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -113,8 +285,7 @@ impl<'de> Deserialize<'de> for Image {
         #[derive(Deserialize)]
         #[serde(field_identifier, rename_all = "lowercase")]
         enum Field {
-            Rows,
-            Cols,
+            Size,
             Typ,
             Data,
         };
@@ -132,42 +303,34 @@ impl<'de> Deserialize<'de> for Image {
             where
                 V: SeqAccess<'de>,
             {
-                let rows = seq
+                let size: Vec<i32> = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(0, &self))?;
-                let cols = seq
+                let typ = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(1, &self))?;
-                let typ = seq
+                // reject the header before reading the pixels
+                Image::expected_len(&size, typ).map_err(de::Error::custom)?;
+                let data: ByteBuf = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(2, &self))?;
-                let data = seq
-                    .next_element()?
-                    .ok_or_else(|| de::Error::invalid_length(3, &self))?;
-                Ok(Image::from_bytes(rows, cols, typ, data).unwrap())
+                Image::from_bytes(size, typ, data.into_vec()).map_err(de::Error::custom)
             }
 
             fn visit_map<V>(self, mut map: V) -> Result<Image, V::Error>
             where
                 V: MapAccess<'de>,
             {
-                let mut rows = None;
-                let mut cols = None;
+                let mut size = None;
                 let mut typ = None;
                 let mut data = None;
                 while let Some(key) = map.next_key()? {
                     match key {
-                        Field::Rows => {
-                            if rows.is_some() {
-                                return Err(de::Error::duplicate_field("rows"));
-                            }
-                            rows = Some(map.next_value()?);
-                        }
-                        Field::Cols => {
-                            if cols.is_some() {
-                                return Err(de::Error::duplicate_field("cols"));
+                        Field::Size => {
+                            if size.is_some() {
+                                return Err(de::Error::duplicate_field("size"));
                             }
-                            cols = Some(map.next_value()?);
+                            size = Some(map.next_value()?);
                         }
                         Field::Typ => {
                             if typ.is_some() {
@@ -183,15 +346,14 @@ impl<'de> Deserialize<'de> for Image {
                         }
                     }
                 }
-                let rows = rows.ok_or_else(|| de::Error::missing_field("rows"))?;
-                let cols = cols.ok_or_else(|| de::Error::missing_field("cols"))?;
+                let size = size.ok_or_else(|| de::Error::missing_field("size"))?;
                 let typ = typ.ok_or_else(|| de::Error::missing_field("typ"))?;
-                let data = data.ok_or_else(|| de::Error::missing_field("data"))?;
-                Ok(Image::from_bytes(rows, cols, typ, data).unwrap())
+                let data: ByteBuf = data.ok_or_else(|| de::Error::missing_field("data"))?;
+                Image::from_bytes(size, typ, data.into_vec()).map_err(de::Error::custom)
             }
         }
 
-        const FIELDS: &[&str] = &["rows", "cols", "typ", "data"];
+        const FIELDS: &[&str] = &["size", "typ", "data"];
         deserializer.deserialize_struct("image", FIELDS, ImageVisitor)
     }
 }
@@ -213,3 +375,162 @@ fn serde_support() {
     assert_eq!(*image_clone.inner.at_2d::<f64>(11, 22).unwrap(), 42.0);
     assert_eq!(*image_clone.inner.at_2d::<f64>(22, 11).unwrap(), 0.0);
 }
+
+#[test]
+fn serde_support_empty() {
+    let mat = unsafe { Mat::new_rows_cols(0, 37, opencv::core::CV_16UC3).unwrap() };
+    let image = Image::from(mat);
+
+    let image_byte = bincode::serialize(&image).unwrap();
+    let image_clone: Image = bincode::deserialize(&image_byte).unwrap();
+
+    assert_eq!(image_clone.rows(), 0);
+    assert_eq!(image_clone.cols(), 37);
+    assert_eq!(image_clone.typ().unwrap(), opencv::core::CV_16UC3);
+}
+
+#[test]
+fn serde_support_roi() {
+    let mut mat = unsafe { Mat::new_rows_cols(42, 37, opencv::core::CV_64FC1).unwrap() };
+    *mat.at_2d_mut::<f64>(11, 22).unwrap() = 42.0;
+
+    let roi = Mat::roi(&mat, opencv::core::Rect::new(20, 10, 5, 3)).unwrap();
+    assert!(!roi.is_continuous().unwrap());
+
+    let image = Image::from(roi);
+
+    let image_byte = bincode::serialize(&image).unwrap();
+    let image_clone: Image = bincode::deserialize(&image_byte).unwrap();
+
+    assert_eq!(image_clone.rows(), 3);
+    assert_eq!(image_clone.cols(), 5);
+    assert_eq!(image.inner.typ().unwrap(), image_clone.typ().unwrap());
+
+    for row in 0..3 {
+        for col in 0..5 {
+            assert_eq!(
+                image.inner.at_2d::<f64>(row, col).unwrap(),
+                image_clone.inner.at_2d::<f64>(row, col).unwrap(),
+            );
+        }
+    }
+    assert_eq!(*image_clone.inner.at_2d::<f64>(1, 2).unwrap(), 42.0);
+}
+
+#[test]
+fn serde_support_padded_step() {
+    let (rows, cols, step) = (4, 3, 8);
+    let mut data: Vec<u8> = (0..(rows * step) as u8).collect();
+    let ptr = data.as_mut_ptr() as *mut c_void;
+    let mat = unsafe {
+        Mat::new_rows_cols_with_data(rows, cols, opencv::core::CV_8UC1, ptr, step as usize).unwrap()
+    };
+
+    let image = Image::from(mat);
+
+    let image_byte = bincode::serialize(&image).unwrap();
+    let image_clone: Image = bincode::deserialize(&image_byte).unwrap();
+
+    assert_eq!(image_clone.rows(), rows);
+    assert_eq!(image_clone.cols(), cols);
+    for row in 0..rows {
+        for col in 0..cols {
+            let expected = (row * step + col) as u8;
+            assert_eq!(*image_clone.inner.at_2d::<u8>(row, col).unwrap(), expected);
+        }
+    }
+    drop(image);
+    drop(data);
+}
+
+#[test]
+fn serde_support_nd() {
+    let size = VectorOfi32::from_iter(vec![3, 4, 5]);
+    let mut mat = unsafe { Mat::new_nd_vec(&size, opencv::core::CV_32SC1).unwrap() };
+    mat.set_to(&opencv::core::Scalar::all(0.0), &Mat::default().unwrap())
+        .unwrap();
+    *mat.at_3d_mut::<i32>(1, 2, 3).unwrap() = 42;
+
+    let image = Image::from(mat);
+
+    let image_byte = bincode::serialize(&image).unwrap();
+    let image_clone: Image = bincode::deserialize(&image_byte).unwrap();
+
+    assert_eq!(image_clone.dims(), 3);
+    assert_eq!(image_clone.shape(), vec![3, 4, 5]);
+    assert_eq!(*image_clone.inner.at_3d::<i32>(1, 2, 3).unwrap(), 42);
+    assert_eq!(*image_clone.inner.at_3d::<i32>(2, 1, 0).unwrap(), 0);
+}
+
+#[test]
+fn serde_reject_malformed() {
+    #[derive(Serialize)]
+    struct RawImage {
+        size: Vec<i32>,
+        typ: i32,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    }
+
+    let cases = vec![
+        // data shorter than the header claims
+        (vec![4, 4], opencv::core::CV_8UC3, vec![0; 4 * 4 * 3 - 1]),
+        // data longer than the header claims
+        (vec![4, 4], opencv::core::CV_8UC1, vec![0; 4 * 4 + 1]),
+        // negative size
+        (vec![-4, 4], opencv::core::CV_8UC1, vec![0; 16]),
+        // too few or too many dimensions
+        (vec![16], opencv::core::CV_8UC1, vec![0; 16]),
+        (vec![1; MAX_DIMS + 1], opencv::core::CV_8UC1, vec![0]),
+        // unsupported depth
+        (vec![4, 4], 7, vec![0; 32]),
+        // unsupported channels
+        (
+            vec![4, 4],
+            opencv::core::CV_8U + (4 << CV_CN_SHIFT),
+            vec![0; 4 * 4 * 5],
+        ),
+        // exceeds the limit
+        (vec![1 << 15, 1 << 15], opencv::core::CV_64FC4, vec![]),
+        (vec![i32::MAX, i32::MAX], opencv::core::CV_8UC1, vec![]),
+    ];
+
+    for (size, typ, data) in cases {
+        let raw = RawImage { size, typ, data };
+        let bytes = bincode::serialize(&raw).unwrap();
+        assert!(bincode::deserialize::<Image>(&bytes).is_err());
+    }
+}
+
+#[test]
+fn serde_fuzz() {
+    // xorshift, to keep the test deterministic
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+
+    let mat = unsafe { Mat::new_rows_cols(8, 6, opencv::core::CV_16UC2).unwrap() };
+    let origin = bincode::serialize(&Image::from(mat)).unwrap();
+
+    for _ in 0..4096 {
+        // flip some bytes of a valid image
+        let mut bytes = origin.clone();
+        for _ in 0..(next() % 4 + 1) {
+            let index = next() as usize % bytes.len();
+            bytes[index] = next() as u8;
+        }
+        if let Ok(image) = bincode::deserialize::<Image>(&bytes) {
+            let len = image.total().unwrap() * image.elem_size().unwrap();
+            assert!(len <= MAX_IMAGE_SIZE);
+        }
+
+        // or just random garbage
+        let len = next() as usize % 64;
+        let bytes: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+        bincode::deserialize::<Image>(&bytes).ok();
+    }
+}
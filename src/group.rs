@@ -0,0 +1,161 @@
+//! The frames of several readers, taken at nearly the same instant.
+
+use std::collections::VecDeque;
+
+use crate::common::ArcVideoReader;
+use crate::frame::Frame;
+
+use chrono::{DateTime, Duration, Utc};
+use podo_core_driver::RuntimeError;
+
+/// The number of the recent frames of each reader, to find the best-aligned set.
+const HISTORY: usize = 4;
+
+#[derive(Debug)]
+pub struct FrameGroup {
+    /// The frames in the order of the names.
+    pub frames: Vec<Frame>,
+    /// The difference between the oldest & the newest frames.
+    pub spread: Duration,
+    /// Whether the spread is within the tolerance, `false` if the group has been timed out.
+    pub aligned: bool,
+}
+
+/// Gets the frames of the running readers, until they are aligned or timed out.
+///
+/// The timeout is checked between the frames, so a stalled reader still blocks.
+pub(crate) fn collect(
+    readers: &[&ArcVideoReader],
+    tolerance: Duration,
+    timeout: Duration,
+) -> Result<FrameGroup, RuntimeError> {
+    if readers.is_empty() {
+        return RuntimeError::expect("The group should have a reader at least");
+    }
+    let deadline = Utc::now() + timeout;
+
+    let mut histories = readers
+        .iter()
+        .map(|reader| Ok(vec![next(reader, None)?].into()))
+        .collect::<Result<Vec<VecDeque<Frame>>, RuntimeError>>()?;
+    loop {
+        let timestamps: Vec<Vec<_>> = histories
+            .iter()
+            .map(|history| history.iter().map(|frame| frame.timestamp).collect())
+            .collect();
+        let (indices, spread) = align(&timestamps);
+
+        let aligned = spread <= tolerance;
+        if aligned || Utc::now() >= deadline {
+            let frames = histories
+                .iter_mut()
+                .zip(indices)
+                .map(|(history, index)| history.remove(index).unwrap())
+                .collect();
+            return Ok(FrameGroup {
+                frames,
+                spread,
+                aligned,
+            });
+        }
+
+        // the reader lagging the most catches up
+        let (lagging, history) = histories
+            .iter_mut()
+            .enumerate()
+            .min_by_key(|(_, history)| history.back().unwrap().timestamp)
+            .unwrap();
+        let frame = next(readers[lagging], history.back())?;
+        if history.len() == HISTORY {
+            history.pop_front();
+        }
+        history.push_back(frame);
+    }
+}
+
+/// Gets the frame after the last one, into a new buffer.
+fn next(reader: &ArcVideoReader, last: Option<&Frame>) -> Result<Frame, RuntimeError> {
+    let mut frame = match last {
+        Some(last) => {
+            let mut frame = Frame::new(last.meta.clone())?;
+            frame.count = last.count;
+            Some(frame)
+        }
+        None => None,
+    };
+    reader.get(&mut frame)?;
+    Ok(frame.unwrap())
+}
+
+/// Returns the index of a timestamp of each reader, and the spread of them.
+///
+/// Every timestamp is tried as an anchor, which the nearest ones of the others are picked for.
+/// The latest set wins the ties.
+fn align(timestamps: &[Vec<DateTime<Utc>>]) -> (Vec<usize>, Duration) {
+    let mut best: Option<(Vec<usize>, Duration, DateTime<Utc>)> = None;
+    for anchor in timestamps.iter().flatten() {
+        let indices: Vec<usize> = timestamps
+            .iter()
+            .map(|history| {
+                // the distances too far to count in microseconds are the farthest
+                let distance = |index: &usize| {
+                    let distance = (history[*index] - *anchor).num_microseconds();
+                    distance.map(i64::abs).unwrap_or(i64::MAX)
+                };
+                (0..history.len()).min_by_key(distance).unwrap()
+            })
+            .collect();
+        let picked = indices.iter().zip(timestamps).map(|(&index, h)| h[index]);
+        let (min, max) = (picked.clone().min().unwrap(), picked.max().unwrap());
+
+        let spread = max - min;
+        let is_better = match best.as_ref() {
+            Some((_, best_spread, best_max)) => {
+                spread < *best_spread || (spread == *best_spread && max > *best_max)
+            }
+            None => true,
+        };
+        if is_better {
+            best = Some((indices, spread, max));
+        }
+    }
+
+    let (indices, spread, _) = best.unwrap();
+    (indices, spread)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_align() {
+        let origin = Utc::now();
+        let at = |ms: &[i64]| -> Vec<_> {
+            ms.iter()
+                .map(|ms| origin + Duration::milliseconds(*ms))
+                .collect()
+        };
+
+        // the second camera runs 5 ms behind
+        let (indices, spread) = align(&[at(&[0, 33, 66]), at(&[-28, 5, 38, 71])]);
+        assert_eq!(indices, vec![2, 3]);
+        assert_eq!(spread, Duration::milliseconds(5));
+
+        // the older set is better aligned
+        let (indices, spread) = align(&[at(&[0, 40]), at(&[1, 60]), at(&[2, 80])]);
+        assert_eq!(indices, vec![0, 0, 0]);
+        assert_eq!(spread, Duration::milliseconds(2));
+
+        // a timestamp too far away is not the nearest one
+        let far = vec![chrono::MIN_DATETIME, origin + Duration::milliseconds(1)];
+        let (indices, spread) = align(&[at(&[0]), far]);
+        assert_eq!(indices, vec![0, 1]);
+        assert_eq!(spread, Duration::milliseconds(1));
+
+        // a single reader is always aligned
+        let (indices, spread) = align(&[at(&[0, 33])]);
+        assert_eq!(indices, vec![1]);
+        assert_eq!(spread, Duration::zero());
+    }
+}
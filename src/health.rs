@@ -0,0 +1,406 @@
+//! The watchdog of a reader, which keeps running while delivering broken frames.
+//!
+//! ```yaml
+//! main:
+//!     Cam:
+//!         device: 0
+//!         ...
+//!
+//!     health:
+//!         grace_ms: 3000
+//!         brightness: [20, 230]
+//!         restart: true
+//! ```
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::common::{ArcVideoReader, Lease};
+use crate::frame::Frame;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use opencv::core;
+use opencv::imgproc;
+use opencv::prelude::*;
+use podo_core_driver::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct HealthConfig {
+    /// The milliseconds of the same issue before the reader is unhealthy, 2000 by default.
+    pub(crate) grace_ms: Option<u32>,
+    /// The mean difference of the consecutive frames regarded as identical, 0 by default.
+    pub(crate) frozen_diff: Option<f64>,
+    /// The mean brightness of a uniform frame regarded as black, 10 by default.
+    pub(crate) black: Option<f64>,
+    /// The mean brightness of a uniform frame regarded as saturated, 245 by default.
+    pub(crate) saturated: Option<f64>,
+    /// The plausible range of the mean brightness, unchecked by default.
+    pub(crate) brightness: Option<[f64; 2]>,
+    /// Restarts the reader once unhealthy, only while the watchdog is its sole consumer.
+    pub(crate) restart: Option<bool>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthState {
+    /// No frame has been checked yet.
+    Unknown,
+    Healthy,
+    /// The frames are identical to each other.
+    Frozen,
+    /// The frames are uniformly black.
+    Black,
+    /// The frames are uniformly white.
+    Saturated,
+    /// The mean brightness is out of the plausible range.
+    Implausible,
+    /// The reader has been failed.
+    Failed,
+}
+
+impl HealthState {
+    #[inline]
+    pub fn is_healthy(&self) -> bool {
+        match self {
+            Self::Unknown | Self::Healthy => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HealthStatus {
+    pub state: HealthState,
+    /// The timestamp of the state being entered.
+    pub since: DateTime<Utc>,
+    /// The number of the restarts of the reader by the watchdog.
+    pub restarts: usize,
+}
+
+impl Default for HealthStatus {
+    fn default() -> Self {
+        Self {
+            state: HealthState::Unknown,
+            since: Utc::now(),
+            restarts: 0,
+        }
+    }
+}
+
+/// Finds the issues of the frames, one by one.
+pub(crate) struct Checker {
+    grace: Duration,
+    frozen_diff: f64,
+    black: f64,
+    saturated: f64,
+    brightness: Option<[f64; 2]>,
+
+    last: Option<Mat>,
+    state: HealthState,
+    /// The issue of the recent frames, and the timestamp of its first frame.
+    pending: Option<(HealthState, DateTime<Utc>)>,
+}
+
+impl Checker {
+    pub(crate) fn new(config: &HealthConfig) -> Self {
+        Self {
+            grace: Duration::milliseconds(config.grace_ms.unwrap_or(2000).into()),
+            frozen_diff: config.frozen_diff.unwrap_or_default(),
+            black: config.black.unwrap_or(10.),
+            saturated: config.saturated.unwrap_or(245.),
+            brightness: config.brightness,
+            last: None,
+            state: HealthState::Unknown,
+            pending: None,
+        }
+    }
+
+    /// Returns the state after the frame, which turns unhealthy after the grace.
+    pub(crate) fn check(
+        &mut self,
+        image: &Mat,
+        timestamp: DateTime<Utc>,
+    ) -> Result<HealthState, RuntimeError> {
+        let issue = self.issue(image)?;
+        self.state = match issue {
+            None => {
+                self.pending = None;
+                HealthState::Healthy
+            }
+            Some(issue) => {
+                let since = match self.pending {
+                    Some((pending, since)) if pending == issue => since,
+                    _ => timestamp,
+                };
+                self.pending = Some((issue, since));
+                // the last state is kept in the grace
+                match timestamp - since >= self.grace {
+                    true => issue,
+                    false => self.state,
+                }
+            }
+        };
+        Ok(self.state)
+    }
+
+    fn issue(&mut self, image: &Mat) -> Result<Option<HealthState>, RuntimeError> {
+        let mut gray = Mat::default()?;
+        match image.channels()? {
+            1 => image.copy_to(&mut gray)?,
+            _ => imgproc::cvt_color(image, &mut gray, imgproc::COLOR_BGR2GRAY, 0)?,
+        }
+
+        let (mut mean, mut stddev) = (Mat::default()?, Mat::default()?);
+        core::mean_std_dev(&gray, &mut mean, &mut stddev, &Mat::default()?)?;
+        let (mean, stddev) = (*mean.at::<f64>(0)?, *stddev.at::<f64>(0)?);
+
+        // the black & white images are also identical to each other
+        let is_uniform = stddev < 5.;
+        let issue = if is_uniform && mean <= self.black {
+            Some(HealthState::Black)
+        } else if is_uniform && mean >= self.saturated {
+            Some(HealthState::Saturated)
+        } else if self.is_frozen(&gray)? {
+            Some(HealthState::Frozen)
+        } else {
+            match self.brightness {
+                Some([min, max]) if mean < min || mean > max => Some(HealthState::Implausible),
+                _ => None,
+            }
+        };
+        self.last = Some(gray);
+        Ok(issue)
+    }
+
+    fn is_frozen(&self, gray: &Mat) -> Result<bool, RuntimeError> {
+        match self.last.as_ref() {
+            Some(last) if last.size()? == gray.size()? => {
+                let mut diff = Mat::default()?;
+                core::absdiff(last, gray, &mut diff)?;
+                Ok(core::mean(&diff, &Mat::default()?)?[0] <= self.frozen_diff)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+struct Thread {
+    reader: ArcVideoReader,
+    alive: AliveFlag,
+    lease: Lease,
+    status: Arc<Mutex<HealthStatus>>,
+
+    config: HealthConfig,
+}
+
+impl Thread {
+    fn inner_loop(self) -> Result<(), RuntimeError> {
+        let mut checker = Checker::new(&self.config);
+
+        let mut frame = None;
+        let result = loop {
+            // normal shutdown
+            if let false = self.alive.is_running() {
+                break Ok(());
+            }
+            // unexpected shutdown
+            if let Err(e) = self.reader.get(&mut frame) {
+                self.set(HealthState::Failed);
+                break Err(e);
+            }
+            let current: &Frame = frame.as_ref().unwrap();
+
+            let state = match checker.check(&current.image, current.timestamp) {
+                Ok(state) => state,
+                Err(e) => break Err(e),
+            };
+            self.set(state);
+
+            if !state.is_healthy() && self.config.restart.unwrap_or_default() {
+                // the reader is kept as it is while the others are reading
+                match self.reader.restart_exclusive() {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
+                        self.set(HealthState::Failed);
+                        break Err(e);
+                    }
+                }
+                self.status.lock().unwrap().restarts += 1;
+                self.set(HealthState::Unknown);
+
+                // the counts of the frames begin again
+                checker = Checker::new(&self.config);
+                frame = None;
+            }
+        };
+
+        // graceful shutdown
+        self.alive.stop().ok();
+        self.lease.release()?;
+        result
+    }
+
+    fn set(&self, state: HealthState) {
+        let mut status = self.status.lock().unwrap();
+        if status.state != state {
+            status.state = state;
+            status.since = Utc::now();
+        }
+    }
+}
+
+/// Watches the frames of a reader, whether they are frozen, black or implausible.
+pub struct HealthChecker {
+    reader: ArcVideoReader,
+    alive: AliveFlag,
+    thread: Mutex<Option<thread::JoinHandle<Result<(), RuntimeError>>>>,
+    status: Arc<Mutex<HealthStatus>>,
+
+    config: HealthConfig,
+}
+
+impl HealthChecker {
+    pub fn new(reader: ArcVideoReader, config: HealthConfig) -> Self {
+        Self {
+            reader,
+            alive: AliveFlag::default(),
+            thread: Mutex::new(None),
+            status: Default::default(),
+            config,
+        }
+    }
+
+    pub fn start(&self) -> Result<(), RuntimeError> {
+        let lease = Lease::new(&self.reader)?;
+
+        self.alive.start()?;
+        *self.status.lock().unwrap() = Default::default();
+        let this = Thread {
+            reader: self.reader.clone(),
+            alive: self.alive.clone(),
+            lease,
+            status: self.status.clone(),
+            config: self.config.clone(),
+        };
+        let t = thread::spawn(move || this.inner_loop());
+        self.thread.lock().unwrap().replace(t);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn stop(&self) -> Result<(), RuntimeError> {
+        self.alive.stop().ok();
+        match self.thread.lock().unwrap().take() {
+            Some(thread) => match thread.join() {
+                Ok(res) => res,
+                Err(_) => RuntimeError::unexpected(),
+            },
+            None => Ok(()),
+        }
+    }
+
+    #[inline]
+    pub fn is_running(&self) -> bool {
+        self.alive.is_running()
+    }
+
+    #[inline]
+    pub fn status(&self) -> HealthStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+impl Drop for HealthChecker {
+    fn drop(&mut self) {
+        self.stop().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use opencv::core::{Scalar, CV_8UC1};
+
+    #[test]
+    fn health_check() {
+        let config: HealthConfig = serde_yaml::from_str(
+            "
+            grace_ms: 100
+            brightness: [20, 230]
+            ",
+        )
+        .unwrap();
+        let mut checker = Checker::new(&config);
+
+        let origin = Utc::now();
+        let at = |ms| origin + Duration::milliseconds(ms);
+        let mut seed = 1u32;
+        let mut noise = |low: u32, high: u32| {
+            let mut image =
+                Mat::new_rows_cols_with_default(48, 64, CV_8UC1, Scalar::all(0.)).unwrap();
+            for y in 0..48 {
+                for x in 0..64 {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    let value = low + (seed >> 16) % (high - low);
+                    *image.at_2d_mut::<u8>(y, x).unwrap() = value as u8;
+                }
+            }
+            image
+        };
+        let uniform =
+            |value| Mat::new_rows_cols_with_default(48, 64, CV_8UC1, Scalar::all(value)).unwrap();
+
+        assert_eq!(
+            checker.check(&noise(64, 192), at(0)).unwrap(),
+            HealthState::Healthy
+        );
+        assert_eq!(
+            checker.check(&noise(64, 192), at(33)).unwrap(),
+            HealthState::Healthy
+        );
+
+        // frozen after the grace
+        let frozen = noise(64, 192);
+        for ms in &[66, 99, 132, 166] {
+            assert_eq!(
+                checker.check(&frozen, at(*ms)).unwrap(),
+                HealthState::Healthy
+            );
+        }
+        assert_eq!(
+            checker.check(&frozen, at(200)).unwrap(),
+            HealthState::Frozen
+        );
+
+        // another issue waits for its own grace
+        let black = uniform(0.);
+        assert_eq!(checker.check(&black, at(233)).unwrap(), HealthState::Frozen);
+        assert_eq!(checker.check(&black, at(333)).unwrap(), HealthState::Black);
+
+        // healthy at once
+        assert_eq!(
+            checker.check(&noise(64, 192), at(366)).unwrap(),
+            HealthState::Healthy
+        );
+
+        // the uniform dim frames are identical to each other
+        let dim = uniform(12.);
+        for ms in &[400, 433] {
+            assert_eq!(checker.check(&dim, at(*ms)).unwrap(), HealthState::Healthy);
+        }
+        assert_eq!(checker.check(&dim, at(533)).unwrap(), HealthState::Frozen);
+
+        assert_eq!(
+            checker.check(&noise(0, 25), at(566)).unwrap(),
+            HealthState::Frozen
+        );
+        assert_eq!(
+            checker.check(&noise(0, 25), at(700)).unwrap(),
+            HealthState::Implausible
+        );
+    }
+}
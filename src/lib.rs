@@ -1,10 +1,38 @@
+mod calibration;
 mod cam;
+pub mod codec;
 mod common;
 mod config;
 #[cfg(feature = "simple-socket")]
 mod export;
 mod frame;
+mod group;
+mod health;
+mod motion;
+mod overlay;
+mod pipeline;
+mod privacy;
+#[cfg(feature = "simple-socket")]
+mod protocol;
+mod record;
+mod snapshot;
 
+pub use self::calibration::{
+    Board, Calibration, CalibrationReport, Calibrator, Intrinsics, Undistortion,
+};
+pub use self::cam::{FramePair, StereoCapture, StereoStats};
 pub use self::common::{ArcVideoReader, EyeDriver};
 pub use self::config::{VideoColor, VideoMeta};
-pub use self::frame::Frame;
+pub use self::frame::{Frame, Image};
+pub use self::group::FrameGroup;
+pub use self::health::{HealthChecker, HealthConfig, HealthState, HealthStatus};
+pub use self::motion::{MotionBox, MotionDetector, MotionEvent, MotionKind, MotionMethod};
+pub use self::overlay::{OverlayConfig, OverlayPosition, OverlayTarget};
+pub use self::pipeline::{find_homography, FlipAxis, Pipeline, Stage, WarpPoints};
+pub use self::privacy::{PrivacyMask, PrivacyMode};
+#[cfg(feature = "simple-socket")]
+pub use self::protocol::{Encoding, PROTOCOL_VERSION};
+pub use self::record::{
+    DatasetWriter, EventConfig, EventRecorder, LogRecorder, RecordConfig, VideoRecorder,
+};
+pub use self::snapshot::{read_tags, ImageFormat, SnapshotTags};
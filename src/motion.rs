@@ -0,0 +1,414 @@
+//! The detection of motion in the frames of a reader, reported as events.
+//!
+//! ```yaml
+//! main:
+//!     Cam:
+//!         device: 0
+//!         ...
+//!
+//!     motion:
+//!         method: mog2
+//!         min_area: 400
+//!         ignore:
+//!             - { x: 0, y: 0, width: 640, height: 40 }
+//!         trigger_event: true
+//! ```
+
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use crate::common::{ArcVideoReader, Lease};
+use crate::frame::Frame;
+use crate::record::EventRecorder;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use opencv::core::{self, Point, Ptr, Rect, Scalar, Size};
+use opencv::imgproc;
+use opencv::prelude::*;
+use opencv::types::VectorOfVectorOfPoint;
+use opencv::video;
+use podo_core_driver::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct MotionConfig {
+    pub(crate) method: Option<MotionMethod>,
+    /// The threshold of the method, more sensitive if lower.
+    ///
+    /// It is the difference of a pixel for `diff` (25 by default), the variance for `mog2`
+    /// (16 by default), and the squared distance for `knn` (400 by default).
+    pub(crate) sensitivity: Option<f64>,
+    /// The minimum area of a moving region in pixels, 100 by default.
+    pub(crate) min_area: Option<f64>,
+    /// The regions to ignore, such as a clock overlay or a tree in the wind.
+    pub(crate) ignore: Option<Vec<MotionBox>>,
+    /// The milliseconds without any motion before a stop, 1000 by default.
+    pub(crate) cooldown_ms: Option<u32>,
+    /// Triggers the event recorder of the reader on every start.
+    pub(crate) trigger_event: Option<bool>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MotionMethod {
+    /// Differs from the previous frame.
+    Diff,
+    /// Subtracts the background modeled by a mixture of Gaussians.
+    Mog2,
+    /// Subtracts the background modeled by the nearest neighbours.
+    Knn,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MotionBox {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MotionKind {
+    Start,
+    Stop,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MotionEvent {
+    pub reader: String,
+    pub kind: MotionKind,
+    /// The timestamp of the first moving frame, or of the last one when stopped.
+    pub timestamp: DateTime<Utc>,
+    /// The moving regions of the frame, empty when stopped.
+    pub boxes: Vec<MotionBox>,
+}
+
+/// The channels of the users of `EyeDriver`, to send every event to.
+#[derive(Default)]
+pub(crate) struct MotionSubscribers(Mutex<Vec<mpsc::Sender<MotionEvent>>>);
+
+impl MotionSubscribers {
+    pub(crate) fn subscribe(&self) -> mpsc::Receiver<MotionEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.0.lock().unwrap().push(tx);
+        rx
+    }
+
+    fn send(&self, event: MotionEvent) {
+        // the dropped receivers are forgotten
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
+
+enum Model {
+    Diff(Option<Mat>),
+    Mog2(Ptr<dyn video::BackgroundSubtractorMOG2>),
+    Knn(Ptr<dyn video::BackgroundSubtractorKNN>),
+}
+
+/// Finds the moving regions of the frames, one by one.
+pub(crate) struct Detector {
+    model: Model,
+    threshold: f64,
+    min_area: f64,
+    ignore: Vec<MotionBox>,
+}
+
+impl Detector {
+    pub(crate) fn new(config: &MotionConfig) -> Result<Self, RuntimeError> {
+        let method = config.method.unwrap_or(MotionMethod::Diff);
+        let (model, threshold) = match method {
+            MotionMethod::Diff => (Model::Diff(None), config.sensitivity.unwrap_or(25.)),
+            MotionMethod::Mog2 => {
+                let threshold = config.sensitivity.unwrap_or(16.);
+                let subtractor = video::create_background_subtractor_mog2(500, threshold, false)?;
+                (Model::Mog2(subtractor), threshold)
+            }
+            MotionMethod::Knn => {
+                let threshold = config.sensitivity.unwrap_or(400.);
+                let subtractor = video::create_background_subtractor_knn(500, threshold, false)?;
+                (Model::Knn(subtractor), threshold)
+            }
+        };
+        Ok(Self {
+            model,
+            threshold,
+            min_area: config.min_area.unwrap_or(100.),
+            ignore: config.ignore.clone().unwrap_or_default(),
+        })
+    }
+
+    /// Returns the moving regions of the image, compared to the previous ones.
+    pub(crate) fn detect(&mut self, image: &Mat) -> Result<Vec<MotionBox>, RuntimeError> {
+        let mut gray = Mat::default()?;
+        match image.channels()? {
+            1 => image.copy_to(&mut gray)?,
+            _ => imgproc::cvt_color(image, &mut gray, imgproc::COLOR_BGR2GRAY, 0)?,
+        }
+        let mut blurred = Mat::default()?;
+        let size = Size::new(5, 5);
+        imgproc::gaussian_blur(&gray, &mut blurred, size, 0., 0., core::BORDER_DEFAULT)?;
+
+        let mut foreground = Mat::default()?;
+        match &mut self.model {
+            Model::Diff(previous) => {
+                let previous = match previous.replace(Mat::copy(&blurred)?) {
+                    Some(previous) => previous,
+                    // nothing has moved yet
+                    None => return Ok(vec![]),
+                };
+                let mut diff = Mat::default()?;
+                core::absdiff(&blurred, &previous, &mut diff)?;
+                let binary = imgproc::THRESH_BINARY;
+                imgproc::threshold(&diff, &mut foreground, self.threshold, 255., binary)?;
+            }
+            Model::Mog2(subtractor) => subtractor.apply(&blurred, &mut foreground, -1.)?,
+            Model::Knn(subtractor) => subtractor.apply(&blurred, &mut foreground, -1.)?,
+        }
+
+        for ignore in &self.ignore {
+            let rect = Rect::new(ignore.x, ignore.y, ignore.width, ignore.height);
+            imgproc::rectangle(
+                &mut foreground,
+                rect,
+                Scalar::all(0.),
+                imgproc::FILLED,
+                imgproc::LINE_8,
+                0,
+            )?;
+        }
+
+        // the nearby regions are merged
+        let mut dilated = Mat::default()?;
+        let kernel = Mat::default()?;
+        let anchor = Point::new(-1, -1);
+        let border = imgproc::morphology_default_border_value()?;
+        imgproc::dilate(
+            &foreground,
+            &mut dilated,
+            &kernel,
+            anchor,
+            2,
+            core::BORDER_CONSTANT,
+            border,
+        )?;
+
+        let mut contours = VectorOfVectorOfPoint::new();
+        imgproc::find_contours(
+            &mut dilated,
+            &mut contours,
+            imgproc::RETR_EXTERNAL,
+            imgproc::CHAIN_APPROX_SIMPLE,
+            Point::default(),
+        )?;
+        let mut boxes = vec![];
+        for contour in contours.iter() {
+            if imgproc::contour_area(&contour, false)? < self.min_area {
+                continue;
+            }
+            let rect = imgproc::bounding_rect(&contour)?;
+            boxes.push(MotionBox {
+                x: rect.x,
+                y: rect.y,
+                width: rect.width,
+                height: rect.height,
+            });
+        }
+        Ok(boxes)
+    }
+}
+
+struct Thread {
+    reader: ArcVideoReader,
+    alive: AliveFlag,
+    lease: Lease,
+    subscribers: Arc<MotionSubscribers>,
+    event: Option<Arc<EventRecorder>>,
+
+    name: String,
+    config: MotionConfig,
+}
+
+impl Thread {
+    fn inner_loop(self) -> Result<(), RuntimeError> {
+        let mut detector = Detector::new(&self.config)?;
+        let cooldown = Duration::milliseconds(self.config.cooldown_ms.unwrap_or(1000).into());
+        // the timestamp of the last moving frame
+        let mut moving: Option<DateTime<Utc>> = None;
+
+        let mut frame = None;
+        let result = loop {
+            // normal shutdown
+            if let false = self.alive.is_running() {
+                break Ok(());
+            }
+            // unexpected shutdown
+            if let Err(e) = self.reader.get(&mut frame) {
+                break Err(e);
+            }
+            let frame: &Frame = frame.as_ref().unwrap();
+
+            let boxes = match detector.detect(&frame.image) {
+                Ok(boxes) => boxes,
+                Err(e) => break Err(e),
+            };
+            match (moving, boxes.is_empty()) {
+                (None, false) => {
+                    if let Some(event) = self.event.as_ref() {
+                        // the recorder may have been stopped by the user
+                        event.trigger().ok();
+                    }
+                    self.send(MotionKind::Start, frame.timestamp, boxes);
+                    moving = Some(frame.timestamp);
+                }
+                (Some(_), false) => moving = Some(frame.timestamp),
+                (Some(last), true) if frame.timestamp - last >= cooldown => {
+                    self.send(MotionKind::Stop, last, vec![]);
+                    moving = None;
+                }
+                _ => {}
+            }
+        };
+
+        // graceful shutdown
+        self.alive.stop().ok();
+        if let Some(last) = moving {
+            self.send(MotionKind::Stop, last, vec![]);
+        }
+        self.lease.release()?;
+        result
+    }
+
+    fn send(&self, kind: MotionKind, timestamp: DateTime<Utc>, boxes: Vec<MotionBox>) {
+        self.subscribers.send(MotionEvent {
+            reader: self.name.clone(),
+            kind,
+            timestamp,
+            boxes,
+        });
+    }
+}
+
+/// Watches a reader for motion, and sends the events to the subscribers of `EyeDriver`.
+pub struct MotionDetector {
+    reader: ArcVideoReader,
+    alive: AliveFlag,
+    thread: Mutex<Option<thread::JoinHandle<Result<(), RuntimeError>>>>,
+    subscribers: Arc<MotionSubscribers>,
+    event: Option<Arc<EventRecorder>>,
+
+    name: String,
+    config: MotionConfig,
+}
+
+impl MotionDetector {
+    pub(crate) fn new(
+        name: &str,
+        reader: ArcVideoReader,
+        config: MotionConfig,
+        subscribers: Arc<MotionSubscribers>,
+        event: Option<Arc<EventRecorder>>,
+    ) -> Result<Self, RuntimeError> {
+        // fails early with a wrong config
+        Detector::new(&config)?;
+        Ok(Self {
+            reader,
+            alive: AliveFlag::default(),
+            thread: Mutex::new(None),
+            subscribers,
+            event,
+            name: name.to_string(),
+            config,
+        })
+    }
+
+    pub fn start(&self) -> Result<(), RuntimeError> {
+        let lease = Lease::new(&self.reader)?;
+
+        self.alive.start()?;
+        let this = Thread {
+            reader: self.reader.clone(),
+            alive: self.alive.clone(),
+            lease,
+            subscribers: self.subscribers.clone(),
+            event: self.event.clone(),
+            name: self.name.clone(),
+            config: self.config.clone(),
+        };
+        let t = thread::spawn(move || this.inner_loop());
+        self.thread.lock().unwrap().replace(t);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn stop(&self) -> Result<(), RuntimeError> {
+        self.alive.stop().ok();
+        match self.thread.lock().unwrap().take() {
+            Some(thread) => match thread.join() {
+                Ok(res) => res,
+                Err(_) => RuntimeError::unexpected(),
+            },
+            None => Ok(()),
+        }
+    }
+
+    #[inline]
+    pub fn is_running(&self) -> bool {
+        self.alive.is_running()
+    }
+}
+
+impl Drop for MotionDetector {
+    fn drop(&mut self) {
+        self.stop().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use opencv::core::CV_8UC1;
+
+    #[test]
+    fn motion_diff() {
+        let config: MotionConfig = serde_yaml::from_str(
+            "
+            method: diff
+            min_area: 50
+            ignore:
+                - { x: 0, y: 0, width: 40, height: 40 }
+            ",
+        )
+        .unwrap();
+        let mut detector = Detector::new(&config).unwrap();
+
+        let black = Mat::new_rows_cols_with_default(120, 160, CV_8UC1, Scalar::all(0.)).unwrap();
+        let square = |x, y| {
+            let mut image = Mat::copy(&black).unwrap();
+            let rect = Rect::new(x, y, 20, 20);
+            let white = Scalar::all(255.);
+            imgproc::rectangle(&mut image, rect, white, imgproc::FILLED, imgproc::LINE_8, 0)
+                .unwrap();
+            image
+        };
+
+        assert!(detector.detect(&black).unwrap().is_empty());
+        assert!(detector.detect(&black).unwrap().is_empty());
+
+        let boxes = detector.detect(&square(100, 60)).unwrap();
+        assert_eq!(boxes.len(), 1);
+        let MotionBox { x, y, .. } = boxes[0];
+        assert!((90..=100).contains(&x) && (50..=60).contains(&y));
+
+        // the ignored region
+        detector.detect(&black).unwrap();
+        detector.detect(&black).unwrap();
+        assert!(detector.detect(&square(5, 5)).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,244 @@
+//! The text burnt into the frames of the outputs, such as the exported and recorded ones.
+//!
+//! The frames got from `EyeDriver` are kept clean.
+//!
+//! ```yaml
+//! main:
+//!     Cam:
+//!         device: 0
+//!         ...
+//!
+//!     overlay:
+//!         text: "{reader} {timestamp} #{count}"
+//!         time_format: "%Y-%m-%d %H:%M:%S%.3f UTC"
+//!         position: bottom_left
+//!         scale: 0.6
+//!         color: [255, 255, 255]
+//!         on: [export, record, log]
+//! ```
+
+use crate::frame::Frame;
+
+use chrono::format::{Item, StrftimeItems};
+use opencv::core::{Point, Scalar};
+use opencv::imgproc;
+use opencv::prelude::*;
+use podo_core_driver::RuntimeError;
+use serde::Deserialize;
+
+const DEFAULT_TEXT: &str = "{reader} {timestamp} #{count}";
+const DEFAULT_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f UTC";
+
+const PLACEHOLDERS: &[&str] = &["reader", "timestamp", "count", "width", "height", "fps"];
+
+const FONT: i32 = imgproc::FONT_HERSHEY_SIMPLEX;
+
+/// The margin between the text and the borders of the image, in pixels.
+const MARGIN: i32 = 8;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlayPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverlayTarget {
+    /// The frames sent to the export clients, including the snapshots.
+    Export,
+    /// The segments of the video recorder.
+    Record,
+    /// The clips of the event recorder.
+    Event,
+    /// The streams of `LogRecorder`.
+    Log,
+    /// The images of `DatasetWriter`, given by `EyeDriver::overlay`.
+    Dataset,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct OverlayConfig {
+    /// The text with the placeholders of `{reader}`, `{timestamp}`, `{count}`,
+    /// `{width}`, `{height}` and `{fps}`, which may have several lines.
+    pub(crate) text: Option<String>,
+    /// The format of `{timestamp}` in UTC, as of `chrono::format::strftime`.
+    pub(crate) time_format: Option<String>,
+    /// The corner of the text, the top left by default.
+    pub(crate) position: Option<OverlayPosition>,
+    /// The scale of the font, 0.5 by default.
+    pub(crate) scale: Option<f64>,
+    /// The color of the text in BGR, white by default.
+    pub(crate) color: Option<[u8; 3]>,
+    /// The outputs to draw on, all of them by default.
+    pub(crate) on: Option<Vec<OverlayTarget>>,
+}
+
+impl OverlayConfig {
+    pub(crate) fn validate(&self) -> Result<(), RuntimeError> {
+        let mut text = self.text.as_deref().unwrap_or(DEFAULT_TEXT);
+        while let Some(begin) = text.find('{') {
+            let end = match text[begin..].find('}') {
+                Some(end) => begin + end,
+                None => {
+                    return RuntimeError::expect("The placeholder of the overlay is not closed")
+                }
+            };
+            let placeholder = &text[begin + 1..end];
+            if !PLACEHOLDERS.contains(&placeholder) {
+                return RuntimeError::message(format!(
+                    "No such placeholder of the overlay: {{{}}}",
+                    placeholder
+                ));
+            }
+            text = &text[end + 1..];
+        }
+
+        let time_format = self.time_format.as_deref().unwrap_or(DEFAULT_TIME_FORMAT);
+        if StrftimeItems::new(time_format).any(|item| item == Item::Error) {
+            return RuntimeError::expect("The time format of the overlay is invalid");
+        }
+        if self.scale.map(|scale| scale <= 0.).unwrap_or_default() {
+            return RuntimeError::expect("The scale of the overlay should be positive");
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn is_on(&self, target: OverlayTarget) -> bool {
+        match self.on.as_ref() {
+            Some(on) => on.contains(&target),
+            None => true,
+        }
+    }
+
+    /// Returns the lines of the text, filled with the fields of the frame.
+    fn text(&self, reader: &str, frame: &Frame) -> Vec<String> {
+        let time_format = self.time_format.as_deref().unwrap_or(DEFAULT_TIME_FORMAT);
+        let text = self
+            .text
+            .as_deref()
+            .unwrap_or(DEFAULT_TEXT)
+            .replace("{reader}", reader)
+            .replace(
+                "{timestamp}",
+                &frame.timestamp.format(time_format).to_string(),
+            )
+            .replace("{count}", &frame.count.to_string())
+            .replace("{width}", &frame.meta.width.to_string())
+            .replace("{height}", &frame.meta.height.to_string())
+            .replace("{fps}", &frame.meta.fps.to_string());
+        text.lines().map(str::to_string).collect()
+    }
+
+    /// Draws the text on the frame in place.
+    pub(crate) fn draw(&self, reader: &str, frame: &mut Frame) -> Result<(), RuntimeError> {
+        let lines = self.text(reader, frame);
+        let scale = self.scale.unwrap_or(0.5);
+        let [b, g, r] = self.color.unwrap_or([255, 255, 255]);
+        let color = Scalar::new(b as f64, g as f64, r as f64, 0.);
+        let position = self.position.unwrap_or(OverlayPosition::TopLeft);
+
+        let image = &mut *frame.image;
+        let (cols, rows) = (image.cols(), image.rows());
+        let line_height = (30. * scale).ceil() as i32;
+        let top = match position {
+            OverlayPosition::TopLeft | OverlayPosition::TopRight => MARGIN,
+            _ => rows - MARGIN - line_height * lines.len() as i32,
+        };
+
+        for (index, line) in lines.iter().enumerate() {
+            let mut baseline = 0;
+            let size = imgproc::get_text_size(line, FONT, scale, 1, &mut baseline)?;
+            let x = match position {
+                OverlayPosition::TopLeft | OverlayPosition::BottomLeft => MARGIN,
+                _ => cols - MARGIN - size.width,
+            };
+            let y = top + line_height * index as i32 + size.height;
+            draw_text(image, line, Point::new(x, y), scale, color)?;
+        }
+        Ok(())
+    }
+}
+
+/// Draws a text with a black outline, readable on any background.
+pub(crate) fn draw_text(
+    image: &mut Mat,
+    text: &str,
+    origin: Point,
+    scale: f64,
+    color: Scalar,
+) -> Result<(), RuntimeError> {
+    for (color, thickness) in &[(Scalar::all(0.), 3), (color, 1)] {
+        imgproc::put_text(
+            image,
+            text,
+            origin,
+            FONT,
+            scale,
+            *color,
+            *thickness,
+            imgproc::LINE_AA,
+            false,
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::VideoMeta;
+    use crate::frame::Image;
+
+    use chrono::prelude::*;
+    use opencv::core::{self, CV_8UC3};
+
+    #[test]
+    fn overlay_text() {
+        let config: OverlayConfig = serde_yaml::from_str(
+            "
+            text: \"{reader} #{count}\\n{timestamp} {width}x{height}@{fps}\"
+            time_format: \"%H:%M:%S\"
+            position: bottom_right
+            on: [record]
+            ",
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert!(config.is_on(OverlayTarget::Record));
+        assert!(!config.is_on(OverlayTarget::Export));
+
+        let mat = Mat::new_rows_cols_with_default(120, 320, CV_8UC3, Scalar::all(0.)).unwrap();
+        let mut frame = Frame {
+            image: Image::from(mat),
+            meta: VideoMeta {
+                codec: None,
+                color: None,
+                width: 320,
+                height: 120,
+                fps: 30,
+            },
+            timestamp: Utc.ymd(2020, 1, 2).and_hms(3, 4, 5),
+            count: 42,
+        };
+        assert_eq!(
+            config.text("main", &frame),
+            vec!["main #42", "03:04:05 320x120@30"]
+        );
+
+        config.draw("main", &mut frame).unwrap();
+        assert!(core::sum_elems(&*frame.image).unwrap()[0] > 0.);
+
+        let config: OverlayConfig = serde_yaml::from_str("time_format: \"%Q\"").unwrap();
+        assert!(config.validate().is_err());
+        let config: OverlayConfig = serde_yaml::from_str("text: \"{reader} {frame}\"").unwrap();
+        assert!(config.validate().is_err());
+        let config: OverlayConfig = serde_yaml::from_str("text: \"{reader\"").unwrap();
+        assert!(config.validate().is_err());
+    }
+}
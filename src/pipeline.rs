@@ -0,0 +1,644 @@
+//! The preprocessing of frames, run once in the capture thread of a reader.
+//!
+//! ```yaml
+//! main:
+//!     Cam:
+//!         device: 0
+//!         ...
+//!
+//!     pipeline:
+//!         - crop: { x: 80, y: 0, width: 480, height: 480 }
+//!         - resize: { width: 240, height: 240 }
+//!         - rotate: 90
+//!         - flip: horizontal
+//!         - color: Grayscale
+//!         - blur: { kernel: 5 }
+//!         - normalize: { alpha: 0, beta: 255 }
+//!         - warp:
+//!             width: 400
+//!             height: 600
+//!             points:
+//!                 src: [[120, 300], [360, 300], [480, 479], [0, 479]]
+//!                 dst: [[0, 0], [400, 0], [400, 600], [0, 600]]
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::calibration::{Intrinsics, Undistortion};
+use crate::config::{VideoColor, VideoMeta};
+use crate::frame::Image;
+use crate::privacy::PrivacyMask;
+
+use opencv::calib3d;
+use opencv::core::{self, Point2f, Rect, Scalar, Size, CV_64F};
+use opencv::imgproc;
+use opencv::prelude::*;
+use opencv::types::VectorOfPoint2f;
+use podo_core_driver::RuntimeError;
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlipAxis {
+    /// Mirrors left & right.
+    Horizontal,
+    /// Mirrors top & bottom.
+    Vertical,
+    Both,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Resize {
+        width: u32,
+        height: u32,
+    },
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// Rotates clockwise by the given degrees, one of 90, 180 and 270.
+    Rotate(u32),
+    Flip(FlipAxis),
+    Color(VideoColor),
+    /// Applies the Gaussian blur with the given odd kernel size.
+    Blur {
+        kernel: u32,
+    },
+    /// Stretches the values into the range of `alpha` to `beta`.
+    Normalize {
+        alpha: f64,
+        beta: f64,
+    },
+    /// Warps by a homography, given as 9 values in the row-major order or by 4 points.
+    Warp {
+        width: u32,
+        height: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        homography: Option<[f64; 9]>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        points: Option<WarpPoints>,
+    },
+}
+
+/// The 4 points of the source, and where they are warped to.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WarpPoints {
+    pub src: [[f32; 2]; 4],
+    pub dst: [[f32; 2]; 4],
+}
+
+/// Returns the homography mapping the source points to the destination ones.
+///
+/// More than 4 correspondences are fitted with RANSAC, rejecting the outliers.
+pub fn find_homography(src: &[[f32; 2]], dst: &[[f32; 2]]) -> Result<[f64; 9], RuntimeError> {
+    if src.len() != dst.len() || src.len() < 4 {
+        return RuntimeError::expect("The homography needs 4 pairs of points at least");
+    }
+    let method = match src.len() {
+        4 => 0,
+        _ => calib3d::RANSAC,
+    };
+    let matrix =
+        calib3d::find_homography(&points(src), &points(dst), &mut Mat::default()?, method, 3.)?;
+    if matrix.empty()? {
+        return RuntimeError::expect("The points should not be degenerate");
+    }
+
+    let mut homography = [0.; 9];
+    for (index, value) in homography.iter_mut().enumerate() {
+        *value = *matrix.at_2d::<f64>(index as i32 / 3, index as i32 % 3)?;
+    }
+    Ok(homography)
+}
+
+fn points(points: &[[f32; 2]]) -> VectorOfPoint2f {
+    points.iter().map(|[x, y]| Point2f::new(*x, *y)).collect()
+}
+
+/// The matrix of a warp stage, computed once for every frame.
+struct WarpMatrix(Mat);
+
+// the matrix is only read after being computed
+unsafe impl Send for WarpMatrix {}
+unsafe impl Sync for WarpMatrix {}
+
+impl fmt::Debug for WarpMatrix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("WarpMatrix").finish()
+    }
+}
+
+impl Stage {
+    fn validate(&self) -> Result<(), RuntimeError> {
+        match self {
+            Self::Resize { width, height } | Self::Crop { width, height, .. } => {
+                if *width == 0 || *height == 0 {
+                    return RuntimeError::expect("The size should not be zero");
+                }
+            }
+            Self::Rotate(degrees) => {
+                if ![90, 180, 270].contains(degrees) {
+                    return RuntimeError::expect("The rotation should be 90, 180 or 270 degrees");
+                }
+            }
+            Self::Blur { kernel } => {
+                if kernel % 2 == 0 {
+                    return RuntimeError::expect("The blur kernel should be odd");
+                }
+            }
+            Self::Warp {
+                width,
+                height,
+                homography,
+                points,
+            } => {
+                if *width == 0 || *height == 0 {
+                    return RuntimeError::expect("The size should not be zero");
+                }
+                if homography.is_some() == points.is_some() {
+                    return RuntimeError::expect(
+                        "The warp should have either homography or points",
+                    );
+                }
+                self.warp_matrix()?;
+            }
+            Self::Flip(_) | Self::Color(_) | Self::Normalize { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Returns the matrix of a warp stage, failing if it is singular.
+    fn warp_matrix(&self) -> Result<Option<WarpMatrix>, RuntimeError> {
+        let matrix = match self {
+            Self::Warp {
+                homography: Some(homography),
+                ..
+            } => {
+                let mut matrix = Mat::new_rows_cols_with_default(3, 3, CV_64F, Scalar::default())?;
+                for (index, value) in homography.iter().enumerate() {
+                    *matrix.at_2d_mut::<f64>(index as i32 / 3, index as i32 % 3)? = *value;
+                }
+                matrix
+            }
+            Self::Warp {
+                points: Some(points),
+                ..
+            } => imgproc::get_perspective_transform(
+                &self::points(&points.src),
+                &self::points(&points.dst),
+                core::DECOMP_LU,
+            )?,
+            _ => return Ok(None),
+        };
+        // the degenerate points give the zeros
+        if !(core::determinant(&matrix)?.abs() > 1e-12) {
+            return RuntimeError::expect("The warp should not be singular");
+        }
+        Ok(Some(WarpMatrix(matrix)))
+    }
+
+    fn meta(&self, meta: &mut VideoMeta) {
+        match self {
+            Self::Resize { width, height }
+            | Self::Crop { width, height, .. }
+            | Self::Warp { width, height, .. } => {
+                meta.width = *width;
+                meta.height = *height;
+            }
+            Self::Rotate(90) | Self::Rotate(270) => {
+                std::mem::swap(&mut meta.width, &mut meta.height);
+            }
+            Self::Color(color) => meta.color = Some(*color),
+            _ => {}
+        }
+    }
+
+    /// Returns `None` if the stage breaks the pinhole model, such as a mirroring.
+    fn intrinsics(&self, k: Intrinsics) -> Option<Intrinsics> {
+        match self {
+            Self::Resize { width, height } => {
+                let sx = *width as f64 / k.width as f64;
+                let sy = *height as f64 / k.height as f64;
+                Some(Intrinsics {
+                    fx: k.fx * sx,
+                    fy: k.fy * sy,
+                    cx: (k.cx + 0.5) * sx - 0.5,
+                    cy: (k.cy + 0.5) * sy - 0.5,
+                    width: *width,
+                    height: *height,
+                })
+            }
+            Self::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => Some(Intrinsics {
+                cx: k.cx - *x as f64,
+                cy: k.cy - *y as f64,
+                width: *width,
+                height: *height,
+                ..k
+            }),
+            // the camera is rotated around its optical axis
+            Self::Rotate(90) => Some(Intrinsics {
+                fx: k.fy,
+                fy: k.fx,
+                cx: (k.height - 1) as f64 - k.cy,
+                cy: k.cx,
+                width: k.height,
+                height: k.width,
+            }),
+            Self::Rotate(180) => Some(Intrinsics {
+                cx: (k.width - 1) as f64 - k.cx,
+                cy: (k.height - 1) as f64 - k.cy,
+                ..k
+            }),
+            Self::Rotate(_) => Some(Intrinsics {
+                fx: k.fy,
+                fy: k.fx,
+                cx: k.cy,
+                cy: (k.width - 1) as f64 - k.cx,
+                width: k.height,
+                height: k.width,
+            }),
+            Self::Flip(_) | Self::Warp { .. } => None,
+            Self::Color(_) | Self::Blur { .. } | Self::Normalize { .. } => Some(k),
+        }
+    }
+
+    fn apply(&self, image: &mut Image, warp: Option<&WarpMatrix>) -> Result<(), RuntimeError> {
+        let mut output = Mat::default()?;
+        match self {
+            Self::Resize { width, height } => {
+                let size = Size::new(*width as i32, *height as i32);
+                imgproc::resize(&**image, &mut output, size, 0., 0., imgproc::INTER_LINEAR)?;
+            }
+            Self::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let roi = Rect::new(*x as i32, *y as i32, *width as i32, *height as i32);
+                if roi.x + roi.width > image.cols() || roi.y + roi.height > image.rows() {
+                    return RuntimeError::expect("The crop should be inside of the image");
+                }
+                Mat::roi(&**image, roi)?.copy_to(&mut output)?;
+            }
+            Self::Rotate(degrees) => {
+                let code = match degrees {
+                    90 => core::ROTATE_90_CLOCKWISE,
+                    180 => core::ROTATE_180,
+                    _ => core::ROTATE_90_COUNTERCLOCKWISE,
+                };
+                core::rotate(&**image, &mut output, code)?;
+            }
+            Self::Flip(axis) => {
+                let code = match axis {
+                    FlipAxis::Horizontal => 1,
+                    FlipAxis::Vertical => 0,
+                    FlipAxis::Both => -1,
+                };
+                core::flip(&**image, &mut output, code)?;
+            }
+            Self::Color(color) => return color.convert(&mut **image),
+            Self::Blur { kernel } => {
+                let size = Size::new(*kernel as i32, *kernel as i32);
+                imgproc::gaussian_blur(&**image, &mut output, size, 0., 0., core::BORDER_DEFAULT)?;
+            }
+            Self::Normalize { alpha, beta } => {
+                let mask = Mat::default()?;
+                core::normalize(
+                    &**image,
+                    &mut output,
+                    *alpha,
+                    *beta,
+                    core::NORM_MINMAX,
+                    -1,
+                    &mask,
+                )?;
+            }
+            Self::Warp { width, height, .. } => {
+                let matrix = match warp {
+                    Some(warp) => &warp.0,
+                    None => return RuntimeError::expect("The warp should not be singular"),
+                };
+                imgproc::warp_perspective(
+                    &**image,
+                    &mut output,
+                    matrix,
+                    Size::new(*width as i32, *height as i32),
+                    imgproc::INTER_LINEAR,
+                    core::BORDER_CONSTANT,
+                    Scalar::default(),
+                )?;
+            }
+        }
+        *image = Image::from(output);
+        Ok(())
+    }
+}
+
+/// The stages applied to every frame of a reader, in order.
+///
+/// The privacy masks of the reader come first, and then the undistortion given by
+/// the calibration of the reader, if any.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<Stage>", into = "Vec<Stage>")]
+pub struct Pipeline {
+    stages: Vec<Stage>,
+    /// The matrices of the warp stages, or `None` for the other and the singular ones.
+    warps: Vec<Option<Arc<WarpMatrix>>>,
+    privacy: Vec<PrivacyMask>,
+    undistortion: Option<Arc<Undistortion>>,
+}
+
+impl From<Vec<Stage>> for Pipeline {
+    #[inline]
+    fn from(stages: Vec<Stage>) -> Self {
+        Self::new(stages)
+    }
+}
+
+impl From<Pipeline> for Vec<Stage> {
+    #[inline]
+    fn from(pipeline: Pipeline) -> Self {
+        pipeline.stages
+    }
+}
+
+impl Pipeline {
+    /// Creates a pipeline of the stages, computing the warps once.
+    pub fn new(stages: Vec<Stage>) -> Self {
+        let warps = stages
+            .iter()
+            .map(|stage| stage.warp_matrix().ok().flatten().map(Arc::new))
+            .collect();
+        Self {
+            stages,
+            warps,
+            privacy: vec![],
+            undistortion: None,
+        }
+    }
+
+    /// Hides the regions of the raw frames, before any other stage.
+    pub fn with_privacy(mut self, masks: Vec<PrivacyMask>) -> Self {
+        self.privacy = masks;
+        self
+    }
+
+    pub fn with_undistortion(mut self, undistortion: Undistortion) -> Self {
+        self.undistortion = Some(Arc::new(undistortion));
+        self
+    }
+
+    #[inline]
+    pub fn stages(&self) -> &[Stage] {
+        &self.stages
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty() && self.privacy.is_empty() && self.undistortion.is_none()
+    }
+
+    pub fn validate(&self) -> Result<(), RuntimeError> {
+        for mask in &self.privacy {
+            mask.validate()?;
+        }
+        self.stages.iter().map(Stage::validate).collect()
+    }
+
+    /// Returns the meta of the output frames, given the meta of the input ones.
+    pub fn meta(&self, meta: &VideoMeta) -> VideoMeta {
+        let mut meta = meta.clone();
+        for stage in &self.stages {
+            stage.meta(&mut meta);
+        }
+        meta
+    }
+
+    /// Returns the intrinsics of the output frames, given the ones of the input frames.
+    ///
+    /// The undistortion replaces the input intrinsics with the calibrated ones.
+    pub fn intrinsics(&self, intrinsics: Option<Intrinsics>) -> Option<Intrinsics> {
+        let intrinsics = match self.undistortion.as_ref() {
+            Some(undistortion) => Some(undistortion.intrinsics().clone()),
+            None => intrinsics,
+        };
+        self.stages
+            .iter()
+            .try_fold(intrinsics?, |k, stage| stage.intrinsics(k))
+    }
+
+    pub fn apply(&self, image: &mut Image) -> Result<(), RuntimeError> {
+        for mask in &self.privacy {
+            mask.apply(&mut **image)?;
+        }
+        if let Some(undistortion) = self.undistortion.as_ref() {
+            undistortion.apply(image)?;
+        }
+        self.stages
+            .iter()
+            .zip(&self.warps)
+            .map(|(stage, warp)| stage.apply(image, warp.as_deref()))
+            .collect()
+    }
+
+    /// Puts the stage into the pipeline of a reader in the config file.
+    ///
+    /// The first stage of the same kind is replaced, or the stage is appended.
+    /// Note that the comments of the file are not kept.
+    pub fn save_stage<P: AsRef<Path>>(
+        path: P,
+        reader: &str,
+        stage: &Stage,
+    ) -> Result<(), RuntimeError> {
+        stage.validate()?;
+        let mut root: serde_yaml::Value = serde_yaml::from_str(&fs::read_to_string(&path)?)?;
+        let config = match root.get_mut(reader).and_then(|c| c.as_mapping_mut()) {
+            Some(config) => config,
+            None => return RuntimeError::message(format!("No such reader: {}", reader)),
+        };
+
+        let stage = serde_yaml::to_value(stage)?;
+        let kind = stage
+            .as_mapping()
+            .and_then(|m| m.iter().next())
+            .map(|(k, _)| k.clone());
+        let key = serde_yaml::Value::from("pipeline");
+        if !config.contains_key(&key) {
+            config.insert(key.clone(), serde_yaml::Value::Sequence(vec![]));
+        }
+        let stages = match config.get_mut(&key).and_then(|p| p.as_sequence_mut()) {
+            Some(stages) => stages,
+            None => return RuntimeError::expect("The pipeline should be a list of stages"),
+        };
+        let same_kind = stages
+            .iter_mut()
+            .find(|s| match (s.as_mapping(), kind.as_ref()) {
+                (Some(s), Some(kind)) => s.contains_key(kind),
+                _ => false,
+            });
+        match same_kind {
+            Some(same_kind) => *same_kind = stage,
+            None => stages.push(stage),
+        }
+
+        fs::write(&path, serde_yaml::to_string(&root)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::frame::sample_frame;
+
+    #[test]
+    fn pipeline_geometry() {
+        let pipeline: Pipeline = serde_yaml::from_str(
+            "
+            - crop: { x: 4, y: 0, width: 24, height: 20 }
+            - resize: { width: 12, height: 10 }
+            - rotate: 90
+            - flip: horizontal
+            - color: Grayscale
+            - blur: { kernel: 5 }
+            - normalize: { alpha: 0, beta: 255 }
+            ",
+        )
+        .unwrap();
+        pipeline.validate().unwrap();
+
+        let frame = sample_frame();
+        let meta = pipeline.meta(&frame.meta);
+        assert_eq!((meta.width, meta.height), (10, 12));
+
+        let mut image = frame.image;
+        pipeline.apply(&mut image).unwrap();
+        assert_eq!(image.cols() as u32, meta.width);
+        assert_eq!(image.rows() as u32, meta.height);
+        assert_eq!(image.channels().unwrap(), 1);
+    }
+
+    #[test]
+    fn pipeline_intrinsics() {
+        let pipeline: Pipeline = serde_yaml::from_str(
+            "
+            - crop: { x: 80, y: 0, width: 480, height: 480 }
+            - resize: { width: 240, height: 240 }
+            - rotate: 90
+            ",
+        )
+        .unwrap();
+        let k = Intrinsics {
+            fx: 500.,
+            fy: 500.,
+            cx: 320.,
+            cy: 240.,
+            width: 640,
+            height: 480,
+        };
+
+        let output = pipeline.intrinsics(Some(k.clone())).unwrap();
+        assert_eq!((output.fx, output.fy), (250., 250.));
+        assert_eq!((output.cx, output.cy), (119.25, 119.75));
+        assert_eq!((output.width, output.height), (240, 240));
+
+        let mirror: Pipeline = serde_yaml::from_str("[flip: horizontal]").unwrap();
+        assert!(mirror.intrinsics(Some(k)).is_none());
+        assert!(pipeline.intrinsics(None).is_none());
+    }
+
+    #[test]
+    fn pipeline_warp() {
+        let src = [[5., 10.], [15., 10.], [20., 20.], [0., 20.]];
+        let dst = [[0., 0.], [10., 0.], [10., 15.], [0., 15.]];
+        let homography = find_homography(&src, &dst).unwrap();
+
+        let by_points = Stage::Warp {
+            width: 10,
+            height: 15,
+            homography: None,
+            points: Some(WarpPoints { src, dst }),
+        };
+        let by_homography = Stage::Warp {
+            width: 10,
+            height: 15,
+            homography: Some(homography),
+            points: None,
+        };
+
+        for stage in vec![by_points, by_homography] {
+            let pipeline = Pipeline::new(vec![stage]);
+            pipeline.validate().unwrap();
+            let mut image = sample_frame().image;
+            pipeline.apply(&mut image).unwrap();
+            assert_eq!((image.cols(), image.rows()), (10, 15));
+            assert_eq!(image.at_2d::<core::Vec3b>(7, 5).unwrap()[2], 30);
+        }
+    }
+
+    #[test]
+    fn pipeline_save_stage() {
+        let name = format!("podo-eye-save-stage-{}.yaml", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, "main:\n    Cam:\n        device: 0\n").unwrap();
+
+        let warp = |width| Stage::Warp {
+            width,
+            height: 300,
+            homography: Some([1., 0., 0., 0., 1., 0., 0., 0., 1.]),
+            points: None,
+        };
+        Pipeline::save_stage(&path, "main", &Stage::Rotate(90)).unwrap();
+        Pipeline::save_stage(&path, "main", &warp(100)).unwrap();
+        Pipeline::save_stage(&path, "main", &warp(200)).unwrap();
+        assert!(Pipeline::save_stage(&path, "none", &warp(200)).is_err());
+
+        let root: serde_yaml::Value =
+            serde_yaml::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        fs::remove_file(&path).ok();
+        let pipeline: Pipeline = serde_yaml::from_value(root["main"]["pipeline"].clone()).unwrap();
+        assert_eq!(pipeline.stages().len(), 2);
+        assert_eq!(
+            pipeline
+                .meta(&VideoMeta {
+                    codec: None,
+                    color: None,
+                    width: 640,
+                    height: 480,
+                    fps: 30,
+                })
+                .width,
+            200
+        );
+    }
+
+    #[test]
+    fn pipeline_reject_invalid() {
+        for stage in &[
+            "[rotate: 45]",
+            "[blur: { kernel: 4 }]",
+            "[resize: { width: 0, height: 1 }]",
+            "[warp: { width: 1, height: 1 }]",
+            "[warp: { width: 1, height: 1, homography: [1, 0, 0, 0, 1, 0, 0, 0, 0] }]",
+            "[warp: { width: 1, height: 1, points: { src: [[0, 0], [1, 1], [2, 2], [3, 3]], \
+                dst: [[0, 0], [1, 0], [1, 1], [0, 1]] } }]",
+        ] {
+            let pipeline: Pipeline = serde_yaml::from_str(stage).unwrap();
+            assert!(pipeline.validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! The regions of the frames hidden before they leave the capture thread.
+//!
+//! The polygons are in the coordinates of the raw frames, as the masks are applied
+//! before the undistortion and the other stages of the pipeline.
+//!
+//! ```yaml
+//! main:
+//!     Cam:
+//!         device: 0
+//!         ...
+//!
+//!     privacy:
+//!         - polygon: [[0, 0], [200, 0], [200, 120], [0, 120]]
+//!         - polygon: [[400, 300], [640, 260], [640, 480], [400, 480]]
+//!           mode: blur
+//!           kernel: 51
+//! ```
+
+use opencv::core::{self, Point, Rect, Scalar, Size, CV_8UC1};
+use opencv::imgproc;
+use opencv::prelude::*;
+use opencv::types::{VectorOfPoint, VectorOfVectorOfPoint};
+use podo_core_driver::RuntimeError;
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrivacyMode {
+    /// Paints over with the color.
+    Fill,
+    /// Applies the Gaussian blur with the kernel size.
+    Blur,
+}
+
+impl Default for PrivacyMode {
+    #[inline]
+    fn default() -> Self {
+        Self::Fill
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PrivacyMask {
+    /// The vertices of the region, 3 at least.
+    pub polygon: Vec<[i32; 2]>,
+    #[serde(default)]
+    pub mode: PrivacyMode,
+    /// The color to fill with in BGR, black by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<[u8; 3]>,
+    /// The odd kernel size of the blur, 51 by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<u32>,
+}
+
+impl PrivacyMask {
+    pub(crate) fn validate(&self) -> Result<(), RuntimeError> {
+        if self.polygon.len() < 3 {
+            return RuntimeError::expect("The privacy mask should have 3 points at least");
+        }
+        if self.kernel.unwrap_or(1) % 2 == 0 {
+            return RuntimeError::expect("The blur kernel should be odd");
+        }
+        Ok(())
+    }
+
+    pub(crate) fn apply(&self, image: &mut Mat) -> Result<(), RuntimeError> {
+        let polygon: VectorOfPoint = self
+            .polygon
+            .iter()
+            .map(|[x, y]| Point::new(*x, *y))
+            .collect();
+        let polygons: VectorOfVectorOfPoint = vec![polygon.clone()].into_iter().collect();
+        match self.mode {
+            PrivacyMode::Fill => {
+                let [b, g, r] = self.color.unwrap_or_default();
+                let color = Scalar::new(b as f64, g as f64, r as f64, 0.);
+                imgproc::fill_poly(
+                    image,
+                    &polygons,
+                    color,
+                    imgproc::LINE_8,
+                    0,
+                    Point::default(),
+                )?;
+            }
+            PrivacyMode::Blur => {
+                // only the bounds of the polygon inside of the image are blurred
+                let bounds = imgproc::bounding_rect(&polygon)?;
+                let roi = bounds & Rect::new(0, 0, image.cols(), image.rows());
+                if roi.width <= 0 || roi.height <= 0 {
+                    return Ok(());
+                }
+
+                let mut region = Mat::new_rows_cols_with_default(
+                    roi.height,
+                    roi.width,
+                    CV_8UC1,
+                    Scalar::all(0.),
+                )?;
+                let offset = Point::new(-roi.x, -roi.y);
+                imgproc::fill_poly(
+                    &mut region,
+                    &polygons,
+                    Scalar::all(255.),
+                    imgproc::LINE_8,
+                    0,
+                    offset,
+                )?;
+
+                let mut target = Mat::roi(image, roi)?;
+                let mut blurred = Mat::default()?;
+                let kernel = self.kernel.unwrap_or(51) as i32;
+                let size = Size::new(kernel, kernel);
+                imgproc::gaussian_blur(
+                    &target,
+                    &mut blurred,
+                    size,
+                    0.,
+                    0.,
+                    core::BORDER_REPLICATE,
+                )?;
+                blurred.copy_to_masked(&mut target, &region)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use opencv::core::{Vec3b, CV_8UC3};
+
+    #[test]
+    fn privacy_masks() {
+        let masks: Vec<PrivacyMask> = serde_yaml::from_str(
+            "
+            - polygon: [[0, 0], [20, 0], [20, 20], [0, 20]]
+              color: [0, 0, 255]
+            - polygon: [[-10, 30], [80, 30], [80, 80]]
+              mode: blur
+              kernel: 9
+            ",
+        )
+        .unwrap();
+        for mask in &masks {
+            mask.validate().unwrap();
+        }
+
+        // a checkerboard keeps no value under the blur
+        let mut image =
+            Mat::new_rows_cols_with_default(64, 64, CV_8UC3, Scalar::all(255.)).unwrap();
+        for y in 0..64 {
+            for x in 0..64 {
+                if (x + y) % 2 == 0 {
+                    *image.at_2d_mut::<Vec3b>(y, x).unwrap() = Vec3b::all(0);
+                }
+            }
+        }
+        for mask in &masks {
+            mask.apply(&mut image).unwrap();
+        }
+
+        assert_eq!(
+            *image.at_2d::<Vec3b>(10, 10).unwrap(),
+            Vec3b::from([0, 0, 255])
+        );
+        let blurred = image.at_2d::<Vec3b>(60, 50).unwrap()[0];
+        assert!(blurred > 64 && blurred < 192);
+        // outside of the polygons
+        assert_eq!(*image.at_2d::<Vec3b>(10, 40).unwrap(), Vec3b::all(0));
+
+        let mask: PrivacyMask = serde_yaml::from_str("polygon: [[0, 0], [1, 1]]").unwrap();
+        assert!(mask.validate().is_err());
+    }
+}
@@ -0,0 +1,260 @@
+//! The wire protocol of the export server.
+//!
+//! Every message is a `Packet`, whose body holds a request or a response envelope.
+//!
+//! Every connection starts with a `Hello` handshake in bincode, in which the client
+//! proposes a protocol version and the encodings it understands, in its preference.
+//! The server answers with its own version and the first of them it supports,
+//! along with a new session in the packet, or `Unsupported` if the versions differ.
+//! Any other request without a session is answered with `NoHandshake`.
+//!
+//! The later envelopes of the session, including the frames in them, are encoded
+//! with the negotiated encoding. A self-describing one encodes a frame like this:
+//!
+//! ```text
+//! Frame {
+//!     image: {
+//!         size: [i32],        // [rows, cols] or more dimensions
+//!         typ: i32,           // OpenCV type, e.g. CV_8UC3 = 16
+//!                             // bit 30 is set if data holds a PNG, JPEG or QOI file
+//!         data: bytes,        // product(size) * elem_size(typ), row-major
+//!     },
+//!     meta: {
+//!         codec: string?,
+//!         color: "Grayscale" | "Color" | null,
+//!         width: u32,
+//!         height: u32,
+//!         fps: u32,
+//!     },
+//!     timestamp: string,      // RFC 3339, UTC
+//!     count: u64,
+//! }
+//! ```
+
+use podo_core_driver::RuntimeError;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// The version of the wire protocol, bumped whenever `Frame` or the envelopes change.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encoding {
+    Bincode,
+    MessagePack,
+}
+
+impl Encoding {
+    pub const SUPPORTED: &'static [Self] = &[Self::Bincode, Self::MessagePack];
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, RuntimeError> {
+        match self {
+            Self::Bincode => {
+                bincode::serialize(value).or_else(|e| RuntimeError::message(e.to_string()))
+            }
+            Self::MessagePack => {
+                rmp_serde::to_vec_named(value).or_else(|e| RuntimeError::message(e.to_string()))
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, RuntimeError> {
+        match self {
+            Self::Bincode => {
+                bincode::deserialize(bytes).or_else(|e| RuntimeError::message(e.to_string()))
+            }
+            Self::MessagePack => {
+                rmp_serde::from_slice(bytes).or_else(|e| RuntimeError::message(e.to_string()))
+            }
+        }
+    }
+}
+
+impl Default for Encoding {
+    #[inline]
+    fn default() -> Self {
+        Self::Bincode
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Handshake {
+    pub version: u32,
+    pub encodings: Vec<Encoding>,
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            encodings: Encoding::SUPPORTED.to_vec(),
+        }
+    }
+}
+
+impl Handshake {
+    /// Returns the first encoding of the client which is supported.
+    pub fn negotiate(&self) -> Option<Encoding> {
+        self.encodings
+            .iter()
+            .find(|encoding| Encoding::SUPPORTED.contains(encoding))
+            .copied()
+    }
+}
+
+/// A message on the wire, always in bincode.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Packet {
+    /// The session given by the handshake, or 0 without one.
+    pub session: u64,
+    /// The envelope, in the encoding of the session.
+    #[serde(with = "serde_bytes")]
+    pub body: Vec<u8>,
+}
+
+impl Packet {
+    #[inline]
+    pub fn seal<T: Serialize>(
+        session: u64,
+        encoding: Encoding,
+        envelope: &T,
+    ) -> Result<Self, RuntimeError> {
+        Ok(Self {
+            session,
+            body: encoding.encode(envelope)?,
+        })
+    }
+
+    #[inline]
+    pub fn open<T: DeserializeOwned>(&self, encoding: Encoding) -> Result<T, RuntimeError> {
+        encoding.decode(&self.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{sample_frame, Frame, Image};
+
+    use chrono::{DateTime, Utc};
+    use opencv::core::Vec3b;
+    use opencv::prelude::*;
+    use serde_bytes::ByteBuf;
+
+    // mirrors the documented schema, without any help from the crate's own types
+    #[derive(Deserialize)]
+    struct SchemaFrame {
+        image: SchemaImage,
+        meta: SchemaMeta,
+        timestamp: String,
+        count: u64,
+    }
+
+    #[derive(Deserialize)]
+    struct SchemaImage {
+        size: Vec<i32>,
+        typ: i32,
+        data: ByteBuf,
+    }
+
+    #[derive(Deserialize)]
+    struct SchemaMeta {
+        codec: Option<String>,
+        color: Option<String>,
+        width: u32,
+        height: u32,
+        fps: u32,
+    }
+
+    #[test]
+    fn round_trip_frame() {
+        let frame = sample_frame();
+
+        for encoding in Encoding::SUPPORTED {
+            let bytes = encoding.encode(&frame).unwrap();
+            let clone: Frame = encoding.decode(&bytes).unwrap();
+
+            assert_eq!(frame.image.rows(), clone.image.rows());
+            assert_eq!(frame.image.cols(), clone.image.cols());
+            assert_eq!(frame.image.typ().unwrap(), clone.image.typ().unwrap());
+            assert_eq!(
+                *clone.image.at_2d::<Vec3b>(2, 1).unwrap(),
+                Vec3b::from([10, 20, 30])
+            );
+            assert_eq!(frame.timestamp, clone.timestamp);
+            assert_eq!(frame.count, clone.count);
+        }
+    }
+
+    #[test]
+    fn negotiate_encoding() {
+        let client = Handshake {
+            version: PROTOCOL_VERSION,
+            encodings: vec![Encoding::MessagePack, Encoding::Bincode],
+        };
+        assert_eq!(client.negotiate(), Some(Encoding::MessagePack));
+
+        let client = Handshake {
+            version: PROTOCOL_VERSION,
+            encodings: vec![],
+        };
+        assert_eq!(client.negotiate(), None);
+    }
+
+    #[test]
+    fn round_trip_packet() {
+        let frame = sample_frame();
+
+        for encoding in Encoding::SUPPORTED {
+            let packet = Packet::seal(7, *encoding, &frame).unwrap();
+            let packet: Packet =
+                bincode::deserialize(&bincode::serialize(&packet).unwrap()).unwrap();
+            assert_eq!(packet.session, 7);
+
+            let clone: Frame = packet.open(*encoding).unwrap();
+            assert_eq!(
+                *clone.image.at_2d::<Vec3b>(2, 1).unwrap(),
+                Vec3b::from([10, 20, 30])
+            );
+        }
+        // the body is opaque without the encoding of the session
+        let packet = Packet::seal(7, Encoding::MessagePack, &frame).unwrap();
+        assert!(packet.open::<Frame>(Encoding::Bincode).is_err());
+    }
+
+    #[test]
+    fn message_pack_schema() {
+        let frame = sample_frame();
+
+        let bytes = Encoding::MessagePack.encode(&frame).unwrap();
+        let schema: SchemaFrame = rmp_serde::from_slice(&bytes).unwrap();
+
+        assert_eq!(schema.image.size, vec![24, 32]);
+        assert_eq!(schema.image.typ, opencv::core::CV_8UC3);
+        assert_eq!(schema.image.data.len(), 24 * 32 * 3);
+        assert_eq!(&schema.image.data[(2 * 32 + 1) * 3..][..3], &[10, 20, 30]);
+
+        assert_eq!(schema.meta.codec.as_deref(), Some("MJPG"));
+        assert_eq!(schema.meta.color.as_deref(), Some("Color"));
+        assert_eq!(
+            (schema.meta.width, schema.meta.height, schema.meta.fps),
+            (32, 24, 30)
+        );
+        assert_eq!(
+            schema.timestamp.parse::<DateTime<Utc>>().unwrap(),
+            frame.timestamp
+        );
+        assert_eq!(schema.count, 7);
+    }
+
+    #[test]
+    fn message_pack_image_schema() {
+        let frame = sample_frame();
+
+        let bytes = Encoding::MessagePack.encode(&frame.image).unwrap();
+        let schema: SchemaImage = rmp_serde::from_slice(&bytes).unwrap();
+        let clone: Image = Encoding::MessagePack.decode(&bytes).unwrap();
+
+        assert_eq!(schema.size, vec![clone.rows(), clone.cols()]);
+        assert_eq!(schema.typ, clone.typ().unwrap());
+    }
+}
@@ -0,0 +1,381 @@
+//! A directory of still images with a JSON-lines manifest, for training data.
+//!
+//! ```text
+//! dataset/
+//!     manifest.jsonl
+//!     main-00000000.png
+//!     main-00000001.png
+//! ```
+//!
+//! Each line of the manifest describes an image:
+//!
+//! ```text
+//! {"file":"main-00000000.png","reader":"main","count":1042,"timestamp":"...","meta":{...}}
+//! ```
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Component, Path};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::common::{ArcVideoReader, Lease};
+use crate::config::VideoMeta;
+use crate::frame::Frame;
+use crate::overlay::OverlayConfig;
+use crate::snapshot::ImageFormat;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use podo_core_driver::*;
+use serde::{Deserialize, Serialize};
+
+pub(crate) const MANIFEST: &str = "manifest.jsonl";
+
+const DEFAULT_TEMPLATE: &str = "{reader}-{index}.png";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ManifestEntry {
+    pub(crate) file: String,
+    pub(crate) reader: String,
+    pub(crate) count: usize,
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) meta: VideoMeta,
+}
+
+/// Reads the manifest of a dataset, in the order of writing.
+pub(crate) fn read_manifest(dir: &Path) -> Result<Vec<ManifestEntry>, RuntimeError> {
+    let path = dir.join(MANIFEST);
+    BufReader::new(File::open(&path)?)
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| line.as_ref().map(|l| !l.is_empty()).unwrap_or(true))
+        .map(|(index, line)| {
+            serde_json::from_str(&line?).or_else(|e| {
+                RuntimeError::message(format!(
+                    "Malformed manifest {:?} at line {}: {}",
+                    &path,
+                    index + 1,
+                    e
+                ))
+            })
+        })
+        .collect()
+}
+
+/// Renders a file name, replacing `{reader}`, `{index}`, `{count}` and `{timestamp}`.
+///
+/// The numbers are zero-padded to 8 digits, to keep the names sorted.
+fn render(template: &str, reader: &str, index: usize, frame: &Frame) -> String {
+    template
+        .replace("{reader}", reader)
+        .replace("{index}", &format!("{:08}", index))
+        .replace("{count}", &format!("{:08}", frame.count))
+        .replace(
+            "{timestamp}",
+            &frame.timestamp.format("%Y%m%d-%H%M%S%.3f").to_string(),
+        )
+}
+
+struct Thread {
+    reader: ArcVideoReader,
+    alive: AliveFlag,
+    lease: Lease,
+
+    name: String,
+    dir: PathBuf,
+    manifest: BufWriter<File>,
+    template: String,
+    format: ImageFormat,
+    every: usize,
+    until: Option<DateTime<Utc>>,
+    first_index: usize,
+    overlay: Option<Arc<OverlayConfig>>,
+}
+
+impl Thread {
+    fn inner_loop(mut self) -> Result<(), RuntimeError> {
+        let mut frame = None;
+        let mut received = 0;
+        let mut index = self.first_index;
+        let result = loop {
+            // normal shutdown
+            if let false = self.alive.is_running() {
+                break Ok(());
+            }
+            // the session is over
+            if let Some(until) = self.until {
+                if Utc::now() >= until {
+                    break Ok(());
+                }
+            }
+            // unexpected shutdown
+            if let Err(e) = self.reader.get(&mut frame) {
+                break Err(e);
+            }
+            let frame = frame.as_mut().unwrap();
+
+            received += 1;
+            if (received - 1) % self.every != 0 {
+                continue;
+            }
+            if let Some(overlay) = self.overlay.as_ref() {
+                if let Err(e) = overlay.draw(&self.name, frame) {
+                    break Err(e);
+                }
+            }
+            if let Err(e) = self.write(index, frame) {
+                break Err(e);
+            }
+            index += 1;
+        };
+
+        // graceful shutdown
+        self.alive.stop().ok();
+        self.lease.release()?;
+        self.manifest.flush()?;
+        result
+    }
+
+    fn write(&mut self, index: usize, frame: &Frame) -> Result<(), RuntimeError> {
+        let file = render(&self.template, &self.name, index, frame);
+        let path = self.dir.join(&file);
+        // the template may name subdirectories
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        frame.save(path, self.format)?;
+
+        let entry = ManifestEntry {
+            file,
+            reader: self.name.clone(),
+            count: frame.count,
+            timestamp: frame.timestamp,
+            meta: frame.meta.clone(),
+        };
+        let line =
+            serde_json::to_string(&entry).or_else(|e| RuntimeError::message(e.to_string()))?;
+        // a line per image, to survive an interruption
+        writeln!(self.manifest, "{}", line)?;
+        self.manifest.flush()?;
+        Ok(())
+    }
+}
+
+/// Dumps the frames of a reader into a directory of images, with a manifest.
+pub struct DatasetWriter {
+    reader: ArcVideoReader,
+    alive: AliveFlag,
+    thread: Mutex<Option<thread::JoinHandle<Result<(), RuntimeError>>>>,
+
+    name: String,
+    dir: PathBuf,
+    template: String,
+    every: usize,
+    duration: Option<Duration>,
+    overlay: Option<Arc<OverlayConfig>>,
+}
+
+impl DatasetWriter {
+    pub fn new<P: AsRef<Path>>(
+        name: &str,
+        reader: ArcVideoReader,
+        dir: P,
+    ) -> Result<Self, RuntimeError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            reader,
+            alive: AliveFlag::default(),
+            thread: Mutex::new(None),
+            name: name.to_string(),
+            dir,
+            template: DEFAULT_TEMPLATE.to_string(),
+            every: 1,
+            duration: None,
+            overlay: None,
+        })
+    }
+
+    /// Names the images after the template, whose extension decides the format.
+    ///
+    /// The template may name subdirectories, but not outside of the dataset.
+    pub fn with_template(mut self, template: &str) -> Result<Self, RuntimeError> {
+        if ImageFormat::from_path(template).is_none() {
+            return RuntimeError::expect("The extension should be png, jpg or jpeg");
+        }
+        let is_inside = Path::new(template)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !is_inside {
+            return RuntimeError::expect("The template should be inside of the dataset");
+        }
+        self.template = template.to_string();
+        Ok(self)
+    }
+
+    /// Keeps every n-th frame only.
+    pub fn with_every(mut self, every: usize) -> Self {
+        self.every = every.max(1);
+        self
+    }
+
+    /// Ends each session after the given duration.
+    pub fn with_duration(mut self, duration: Duration) -> Self {
+        self.duration = Some(duration);
+        self
+    }
+
+    /// Draws the overlay on the images, such as the one of `EyeDriver::overlay`.
+    pub fn with_overlay(mut self, overlay: Arc<OverlayConfig>) -> Self {
+        self.overlay = Some(overlay);
+        self
+    }
+
+    pub fn start(&self) -> Result<(), RuntimeError> {
+        // the sessions are appended to the same manifest, continuing the index
+        let first_index = match self.dir.join(MANIFEST).exists() {
+            true => read_manifest(&self.dir)?.len(),
+            false => 0,
+        };
+        let manifest = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(MANIFEST))?;
+
+        let lease = Lease::new(&self.reader)?;
+
+        self.alive.start()?;
+        let this = Thread {
+            reader: self.reader.clone(),
+            alive: self.alive.clone(),
+            lease,
+            name: self.name.clone(),
+            dir: self.dir.clone(),
+            manifest: BufWriter::new(manifest),
+            template: self.template.clone(),
+            format: ImageFormat::from_path(&self.template).unwrap(),
+            every: self.every,
+            until: self.duration.map(|duration| Utc::now() + duration),
+            first_index,
+            overlay: self.overlay.clone(),
+        };
+        let t = thread::spawn(move || this.inner_loop());
+        self.thread.lock().unwrap().replace(t);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn stop(&self) -> Result<(), RuntimeError> {
+        self.alive.stop().ok();
+        match self.thread.lock().unwrap().take() {
+            Some(thread) => match thread.join() {
+                Ok(res) => res,
+                Err(_) => RuntimeError::unexpected(),
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Returns `false` after the session is over.
+    #[inline]
+    pub fn is_running(&self) -> bool {
+        self.alive.is_running()
+    }
+}
+
+impl Drop for DatasetWriter {
+    fn drop(&mut self) {
+        self.stop().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    use crate::common::VideoReader;
+    use crate::frame::sample_frame;
+
+    #[test]
+    fn render_template() {
+        let mut frame = sample_frame();
+        frame.count = 1042;
+        frame.timestamp = Utc.ymd(2020, 6, 1).and_hms_milli(12, 0, 0, 33);
+
+        let name = render(
+            "{reader}/{index}-{count}-{timestamp}.jpg",
+            "main",
+            7,
+            &frame,
+        );
+        assert_eq!(name, "main/00000007-00001042-20200601-120000.033.jpg");
+    }
+
+    /// Gives the same image with increasing counts, every few milliseconds.
+    #[derive(Default)]
+    struct Still {
+        alive: AliveFlag,
+    }
+
+    impl VideoReader for Still {
+        fn start(&self) -> Result<(), RuntimeError> {
+            self.alive.start()
+        }
+
+        fn stop(&self) -> Result<(), RuntimeError> {
+            self.alive.stop().ok();
+            Ok(())
+        }
+
+        fn is_running(&self) -> bool {
+            self.alive.is_running()
+        }
+
+        fn is_export(&self) -> bool {
+            false
+        }
+
+        fn get(&self, frame: &mut Option<Frame>) -> Result<(), RuntimeError> {
+            thread::sleep(std::time::Duration::from_millis(5));
+            let count = frame
+                .as_ref()
+                .map(|frame| frame.count + 1)
+                .unwrap_or_default();
+            let mut next = sample_frame();
+            next.count = count;
+            frame.replace(next);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn template_subdirectory() {
+        let dir = env::temp_dir().join(format!("podo-eye-dataset-{}", process::id()));
+        let reader: ArcVideoReader = Arc::new(Still::default());
+
+        let writer = DatasetWriter::new("main", reader.clone(), &dir).unwrap();
+        for template in &["../{index}.png", "/tmp/{index}.png"] {
+            assert!(DatasetWriter::new("main", reader.clone(), &dir)
+                .unwrap()
+                .with_template(template)
+                .is_err());
+        }
+
+        let writer = writer.with_template("{reader}/{index}.png").unwrap();
+        writer.start().unwrap();
+        while read_manifest(&dir).map(|m| m.len()).unwrap_or_default() < 2 {
+            thread::yield_now();
+        }
+        writer.stop().unwrap();
+
+        let manifest = read_manifest(&dir).unwrap();
+        assert_eq!(manifest[1].file, "main/00000001.png");
+        assert!(dir.join(&manifest[1].file).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
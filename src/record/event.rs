@@ -0,0 +1,471 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use super::video::Segment;
+use crate::codec::{self, ImageCodec};
+use crate::common::{ArcVideoReader, Lease};
+use crate::config::VideoMeta;
+use crate::frame::{Frame, MAX_IMAGE_SIZE};
+use crate::overlay::OverlayConfig;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use opencv::prelude::*;
+use podo_core_driver::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct EventConfig {
+    /// The directory of the clips, relative to the config file.
+    pub(crate) path: String,
+
+    /// The seconds of the history kept before a trigger.
+    pub(crate) pre_secs: u64,
+    /// The seconds recorded after a trigger.
+    pub(crate) post_secs: u64,
+
+    /// Compresses the history with JPEG of the given quality to bound the memory.
+    pub(crate) quality: Option<u8>,
+
+    pub(crate) codec: Option<String>,
+    pub(crate) container: Option<String>,
+}
+
+impl EventConfig {
+    #[inline]
+    fn codec(&self) -> &str {
+        self.codec.as_deref().unwrap_or("MJPG")
+    }
+
+    #[inline]
+    fn container(&self) -> &str {
+        self.container.as_deref().unwrap_or("avi")
+    }
+}
+
+enum Entry {
+    Raw(Mat),
+    Encoded(Vec<u8>),
+}
+
+impl Entry {
+    fn new(frame: &Frame, quality: Option<u8>) -> Result<Self, RuntimeError> {
+        match quality {
+            Some(quality) => Ok(Self::Encoded(
+                ImageCodec::Jpeg { quality }.encode(&frame.image)?,
+            )),
+            None => {
+                let mut image = Mat::default()?;
+                frame.image.copy_to(&mut image)?;
+                Ok(Self::Raw(image))
+            }
+        }
+    }
+
+    fn try_clone(&self) -> Result<Self, RuntimeError> {
+        match self {
+            Self::Raw(image) => {
+                let mut clone = Mat::default()?;
+                image.copy_to(&mut clone)?;
+                Ok(Self::Raw(clone))
+            }
+            Self::Encoded(data) => Ok(Self::Encoded(data.clone())),
+        }
+    }
+
+    fn image(&self) -> Result<Mat, RuntimeError> {
+        match self {
+            Self::Raw(image) => Ok(Mat::copy(image)?),
+            Self::Encoded(data) => {
+                codec::decode(data, MAX_IMAGE_SIZE).or_else(RuntimeError::message)
+            }
+        }
+    }
+}
+
+/// The sidecar metadata of a clip.
+#[derive(Serialize)]
+struct ClipMeta<'a> {
+    reader: &'a str,
+    trigger: DateTime<Utc>,
+    begin: DateTime<Utc>,
+    end: DateTime<Utc>,
+    frames: usize,
+    meta: &'a VideoMeta,
+}
+
+/// The timestamps, the counts and the images of the frames.
+type Entries = Vec<(DateTime<Utc>, usize, Entry)>;
+
+/// The frames of a trigger, given to the writer thread.
+struct Clip {
+    trigger: DateTime<Utc>,
+    meta: VideoMeta,
+    entries: Entries,
+}
+
+/// The rolling history of a reader, keeping the pre-roll of the pending trigger.
+///
+/// The trigger is stamped with the timestamp of the frames, not with the clock,
+/// as the frames of a replay or a remote reader may be far from now.
+struct History {
+    pre: Duration,
+    post: Duration,
+    trigger: Option<DateTime<Utc>>,
+    entries: VecDeque<(DateTime<Utc>, usize, Entry)>,
+}
+
+impl History {
+    fn new(config: &EventConfig) -> Self {
+        Self {
+            pre: Duration::seconds(config.pre_secs as i64),
+            post: Duration::seconds(config.post_secs as i64),
+            trigger: None,
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Pushes a frame, which stamps the trigger if it is the first one since.
+    ///
+    /// A trigger during the post-roll of another one is merged into it.
+    fn push(&mut self, timestamp: DateTime<Utc>, count: usize, entry: Entry, triggered: bool) {
+        if triggered && self.trigger.is_none() {
+            self.trigger = Some(timestamp);
+        }
+        self.entries.push_back((timestamp, count, entry));
+
+        // keep the pre-roll of a pending trigger
+        let oldest = self.trigger.unwrap_or(timestamp) - self.pre;
+        while let Some((timestamp, _, _)) = self.entries.front() {
+            if *timestamp >= oldest {
+                break;
+            }
+            self.entries.pop_front();
+        }
+    }
+
+    /// Returns the trigger and its frames, once its post-roll is over.
+    ///
+    /// The frames are kept, as the pre-roll of the next trigger.
+    fn clip(&mut self) -> Result<Option<(DateTime<Utc>, Entries)>, RuntimeError> {
+        let trigger = match (self.trigger, self.entries.back()) {
+            (Some(trigger), Some((timestamp, _, _))) if *timestamp >= trigger + self.post => {
+                trigger
+            }
+            _ => return Ok(None),
+        };
+        let entries = self
+            .entries
+            .iter()
+            .map(|(timestamp, count, entry)| Ok((*timestamp, *count, entry.try_clone()?)))
+            .collect::<Result<_, RuntimeError>>()?;
+        self.trigger.take();
+        Ok(Some((trigger, entries)))
+    }
+}
+
+struct Thread {
+    reader: ArcVideoReader,
+    alive: AliveFlag,
+    lease: Lease,
+    triggered: Arc<AtomicBool>,
+
+    name: String,
+    config: EventConfig,
+    overlay: Option<Arc<OverlayConfig>>,
+    tx: mpsc::Sender<Clip>,
+}
+
+impl Thread {
+    fn inner_loop(self) -> Result<(), RuntimeError> {
+        let mut frame = None;
+        let mut history = History::new(&self.config);
+        let result = loop {
+            // normal shutdown
+            if let false = self.alive.is_running() {
+                break Ok(());
+            }
+            // unexpected shutdown
+            if let Err(e) = self.reader.get(&mut frame) {
+                break Err(e);
+            }
+            let frame = frame.as_mut().unwrap();
+            if let Some(overlay) = self.overlay.as_ref() {
+                if let Err(e) = overlay.draw(&self.name, frame) {
+                    break Err(e);
+                }
+            }
+
+            match self.push(&mut history, frame) {
+                Ok(Some(clip)) => {
+                    // the writer has been terminated
+                    if self.tx.send(clip).is_err() {
+                        break Ok(());
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => break Err(e),
+            }
+        };
+
+        // graceful shutdown
+        self.alive.stop().ok();
+        self.lease.release()?;
+        result
+    }
+
+    fn push(&self, history: &mut History, frame: &Frame) -> Result<Option<Clip>, RuntimeError> {
+        let entry = Entry::new(frame, self.config.quality)?;
+        let triggered = self.triggered.swap(false, Ordering::SeqCst);
+        history.push(frame.timestamp, frame.count, entry, triggered);
+
+        Ok(history.clip()?.map(|(trigger, entries)| Clip {
+            trigger,
+            meta: frame.meta.clone(),
+            entries,
+        }))
+    }
+}
+
+/// Writes the clips apart from the reader, not to miss the frames meanwhile.
+fn write_loop(
+    name: String,
+    dir: PathBuf,
+    config: EventConfig,
+    rx: mpsc::Receiver<Clip>,
+) -> Result<(), RuntimeError> {
+    // ends when the reader thread has been terminated
+    for clip in rx {
+        write_clip(&name, &dir, &config, &clip)?;
+    }
+    Ok(())
+}
+
+fn write_clip(
+    name: &str,
+    dir: &Path,
+    config: &EventConfig,
+    clip: &Clip,
+) -> Result<(), RuntimeError> {
+    let (begin, end) = match (clip.entries.first(), clip.entries.last()) {
+        (Some((begin, _, _)), Some((end, _, _))) => (*begin, *end),
+        _ => return Ok(()),
+    };
+
+    // apart from the segments of a video recorder in the same directory
+    let ext = format!("event.{}", config.container());
+    let path = Segment::path(dir, name, clip.trigger, &ext);
+    let mut segment: Option<Segment> = None;
+    for (timestamp, count, entry) in &clip.entries {
+        let image = entry.image()?;
+        if segment.is_none() {
+            let path = path.clone();
+            let s = Segment::new(path, config.codec(), clip.meta.fps, &image, *timestamp)?;
+            segment.replace(s);
+        }
+        segment
+            .as_mut()
+            .unwrap()
+            .write(&image, *count, *timestamp)?;
+    }
+    if let Some(segment) = segment {
+        segment.finish()?;
+    }
+
+    let sidecar = ClipMeta {
+        reader: name,
+        trigger: clip.trigger,
+        begin,
+        end,
+        frames: clip.entries.len(),
+        meta: &clip.meta,
+    };
+    fs::write(
+        path.with_extension("yaml"),
+        serde_yaml::to_string(&sidecar)?,
+    )?;
+    Ok(())
+}
+
+/// Keeps a rolling history of a reader, and dumps it into a clip when triggered.
+pub struct EventRecorder {
+    reader: ArcVideoReader,
+    alive: AliveFlag,
+    threads: Mutex<Vec<thread::JoinHandle<Result<(), RuntimeError>>>>,
+    triggered: Arc<AtomicBool>,
+
+    name: String,
+    dir: PathBuf,
+    config: EventConfig,
+    overlay: Option<Arc<OverlayConfig>>,
+}
+
+impl EventRecorder {
+    pub fn new<P: AsRef<Path>>(
+        name: &str,
+        reader: ArcVideoReader,
+        config: EventConfig,
+        path: P,
+    ) -> Result<Self, RuntimeError> {
+        let mut dir = path.as_ref().to_path_buf();
+        dir.push(&config.path);
+        fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            reader,
+            alive: AliveFlag::default(),
+            threads: Mutex::new(vec![]),
+            triggered: Arc::new(AtomicBool::new(false)),
+            name: name.to_string(),
+            dir,
+            config,
+            overlay: None,
+        })
+    }
+
+    /// Draws the overlay on the frames of the clips.
+    pub fn with_overlay(mut self, overlay: Arc<OverlayConfig>) -> Self {
+        self.overlay = Some(overlay);
+        self
+    }
+
+    pub fn start(&self) -> Result<(), RuntimeError> {
+        // the lease is given back on a failure, by being dropped
+        let lease = Lease::new(&self.reader)?;
+
+        self.alive.start()?;
+        let (tx, rx) = mpsc::channel();
+        let this = Thread {
+            reader: self.reader.clone(),
+            alive: self.alive.clone(),
+            lease,
+            triggered: self.triggered.clone(),
+            name: self.name.clone(),
+            config: self.config.clone(),
+            overlay: self.overlay.clone(),
+            tx,
+        };
+        let (name, dir, config) = (self.name.clone(), self.dir.clone(), self.config.clone());
+
+        let mut threads = self.threads.lock().unwrap();
+        threads.push(thread::spawn(move || this.inner_loop()));
+        threads.push(thread::spawn(move || write_loop(name, dir, config, rx)));
+        Ok(())
+    }
+
+    /// Stops the recorder, after writing the clips of the past triggers.
+    pub fn stop(&self) -> Result<(), RuntimeError> {
+        self.alive.stop().ok();
+        self.threads
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|thread| match thread.join() {
+                Ok(res) => res,
+                Err(_) => RuntimeError::unexpected(),
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    #[inline]
+    pub fn is_running(&self) -> bool {
+        self.alive.is_running()
+    }
+
+    /// Dumps the pre-roll and the post-roll of the next frame into a clip.
+    ///
+    /// A trigger during the post-roll of another one is merged into it.
+    pub fn trigger(&self) -> Result<(), RuntimeError> {
+        self.alive.assert_running()?;
+        self.triggered.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl Drop for EventRecorder {
+    fn drop(&mut self) {
+        self.stop().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> EventConfig {
+        serde_yaml::from_str(
+            "
+            path: events
+            pre_secs: 1
+            post_secs: 1
+            ",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn event_history() {
+        let mut history = History::new(&config());
+
+        let origin = Utc::now();
+        let at = |index: i64| origin + Duration::milliseconds(100 * index);
+        let entry = || Entry::Encoded(vec![]);
+
+        // only the pre-roll is kept without a trigger
+        for index in 0..30 {
+            history.push(at(index), index as usize, entry(), false);
+        }
+        assert_eq!(history.entries.len(), 11);
+        assert_eq!(history.entries.front().unwrap().1, 19);
+
+        // the pre-roll of the trigger is kept during the post-roll
+        for index in 30..40 {
+            history.push(at(index), index as usize, entry(), index == 30);
+            assert!(history.clip().unwrap().is_none());
+        }
+        assert_eq!(history.entries.front().unwrap().1, 20);
+
+        // a trigger during the post-roll is merged
+        history.push(at(40), 40, entry(), true);
+        let (trigger, clip) = history.clip().unwrap().unwrap();
+        assert_eq!(trigger, at(30));
+        let counts: Vec<_> = clip.iter().map(|(_, count, _)| *count).collect();
+        assert_eq!(counts, (20..=40).collect::<Vec<_>>());
+
+        // the clip is kept as the pre-roll of the next trigger
+        history.push(at(41), 41, entry(), false);
+        assert_eq!(history.entries.front().unwrap().1, 31);
+        assert!(history.clip().unwrap().is_none());
+    }
+
+    #[test]
+    fn event_history_past() {
+        let mut history = History::new(&config());
+
+        // the frames of a replay, years before now
+        let origin = Utc.ymd(2020, 6, 1).and_hms(12, 0, 0);
+        let at = |index: i64| origin + Duration::milliseconds(100 * index);
+        let entry = || Entry::Encoded(vec![]);
+
+        for index in 0..=20 {
+            history.push(at(index), index as usize, entry(), index == 10);
+        }
+        let (trigger, clip) = history.clip().unwrap().unwrap();
+        assert_eq!(trigger, at(10));
+        let counts: Vec<_> = clip.iter().map(|(_, count, _)| *count).collect();
+        assert_eq!(counts, (0..=20).collect::<Vec<_>>());
+
+        // the next trigger is not stuck behind the first one
+        for index in 21..=35 {
+            history.push(at(index), index as usize, entry(), index == 25);
+        }
+        let (trigger, _) = history.clip().unwrap().unwrap();
+        assert_eq!(trigger, at(25));
+    }
+}
@@ -0,0 +1,571 @@
+//! A chunked log file, which interleaves the frames of several readers.
+//!
+//! ```text
+//! file   := "PODOEYE\0" version:u32 chunk* footer?
+//! chunk  := kind:u8 len:u32 payload[len]
+//! footer := index_offset:u64 "EYEINDEX"
+//! ```
+//!
+//! All integers are little-endian, and the payloads are bincode:
+//!
+//! - `Stream` (0): `StreamInfo`, written before the first frame of a stream.
+//! - `Frame` (1): the stream id as `u32` and the timestamp as `i64` seconds and `u32`
+//!   nanoseconds since the epoch, followed by a `Frame`.
+//! - `Index` (2): `LogIndex`, the streams and the offsets of every frame chunk.
+//!
+//! A log without the footer, such as an interrupted recording, is indexed by scanning its chunks.
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use crate::codec::{Compressed, ImageCodec};
+use crate::common::{ArcVideoReader, EyeDriver, Lease};
+use crate::config::VideoMeta;
+use crate::frame::Frame;
+use crate::overlay::{OverlayConfig, OverlayTarget};
+
+use chrono::prelude::*;
+use podo_core_driver::*;
+use serde::{Deserialize, Serialize};
+
+const MAGIC: &[u8; 8] = b"PODOEYE\0";
+const MAGIC_FOOTER: &[u8; 8] = b"EYEINDEX";
+const LOG_VERSION: u32 = 2;
+
+const CHUNK_STREAM: u8 = 0;
+const CHUNK_FRAME: u8 = 1;
+const CHUNK_INDEX: u8 = 2;
+
+const HEADER_LEN: u64 = 12;
+const CHUNK_HEADER_LEN: u64 = 5;
+const FOOTER_LEN: u64 = 16;
+/// The stream id and the timestamp before the frame.
+const FRAME_PREFIX_LEN: usize = 16;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct StreamInfo {
+    pub(crate) id: u32,
+    pub(crate) name: String,
+    pub(crate) meta: VideoMeta,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct IndexEntry {
+    pub(crate) stream: u32,
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) offset: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct LogIndex {
+    streams: Vec<StreamInfo>,
+    entries: Vec<IndexEntry>,
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, RuntimeError> {
+    bincode::serialize(value).or_else(|e| RuntimeError::message(e.to_string()))
+}
+
+fn decode<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T, RuntimeError> {
+    bincode::deserialize(bytes).or_else(|e| RuntimeError::message(e.to_string()))
+}
+
+struct LogWriter {
+    inner: BufWriter<File>,
+    offset: u64,
+    index: LogIndex,
+}
+
+impl LogWriter {
+    fn create(path: &Path) -> Result<Self, RuntimeError> {
+        let mut inner = BufWriter::new(File::create(path)?);
+        inner.write_all(MAGIC)?;
+        inner.write_all(&LOG_VERSION.to_le_bytes())?;
+        Ok(Self {
+            inner,
+            offset: HEADER_LEN,
+            index: LogIndex::default(),
+        })
+    }
+
+    fn write_chunk(&mut self, kind: u8, payload: &[&[u8]]) -> Result<u64, RuntimeError> {
+        let len: usize = payload.iter().map(|p| p.len()).sum();
+        let len: u32 = match len.try_into() {
+            Ok(len) => len,
+            Err(_) => return RuntimeError::expect("The chunk is too large"),
+        };
+
+        let offset = self.offset;
+        self.inner.write_all(&[kind])?;
+        self.inner.write_all(&len.to_le_bytes())?;
+        for p in payload {
+            self.inner.write_all(p)?;
+        }
+        self.offset += CHUNK_HEADER_LEN + u64::from(len);
+        Ok(offset)
+    }
+
+    fn add_stream(&mut self, id: u32, name: &str, meta: VideoMeta) -> Result<(), RuntimeError> {
+        let stream = StreamInfo {
+            id,
+            name: name.to_string(),
+            meta,
+        };
+        self.write_chunk(CHUNK_STREAM, &[&encode(&stream)?])?;
+        self.index.streams.push(stream);
+        Ok(())
+    }
+
+    fn add_frame(
+        &mut self,
+        stream: u32,
+        timestamp: DateTime<Utc>,
+        frame: &[u8],
+    ) -> Result<(), RuntimeError> {
+        let mut prefix = Vec::with_capacity(FRAME_PREFIX_LEN);
+        prefix.extend_from_slice(&stream.to_le_bytes());
+        prefix.extend_from_slice(&timestamp.timestamp().to_le_bytes());
+        prefix.extend_from_slice(&timestamp.timestamp_subsec_nanos().to_le_bytes());
+        let offset = self.write_chunk(CHUNK_FRAME, &[&prefix, frame])?;
+        self.index.entries.push(IndexEntry {
+            stream,
+            timestamp,
+            offset,
+        });
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), RuntimeError> {
+        let index = encode(&self.index)?;
+        let offset = self.write_chunk(CHUNK_INDEX, &[&index])?;
+        self.inner.write_all(&offset.to_le_bytes())?;
+        self.inner.write_all(MAGIC_FOOTER)?;
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads the frames of a log file, in any order.
+pub(crate) struct LogFile {
+    inner: BufReader<File>,
+    index: LogIndex,
+}
+
+impl LogFile {
+    pub(crate) fn open(path: &Path) -> Result<Self, RuntimeError> {
+        let mut inner = BufReader::new(File::open(path)?);
+
+        let mut header = [0; HEADER_LEN as usize];
+        inner.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return RuntimeError::message(format!("Not a log file: {:?}", path));
+        }
+        let version = u32::from_le_bytes(header[8..].try_into().unwrap());
+        if version != LOG_VERSION {
+            return RuntimeError::message(format!("Unsupported log version: {}", version));
+        }
+
+        let index = match Self::read_footer(&mut inner)? {
+            Some(offset) => match Self::read_chunk(&mut inner, offset)? {
+                (CHUNK_INDEX, payload) => decode(&payload)?,
+                _ => return RuntimeError::expect("The log index is corrupted"),
+            },
+            None => Self::scan(&mut inner)?,
+        };
+        Ok(Self { inner, index })
+    }
+
+    fn read_footer(inner: &mut BufReader<File>) -> Result<Option<u64>, RuntimeError> {
+        let len = inner.seek(SeekFrom::End(0))?;
+        if len < HEADER_LEN + FOOTER_LEN {
+            return Ok(None);
+        }
+
+        let mut footer = [0; FOOTER_LEN as usize];
+        inner.seek(SeekFrom::End(-(FOOTER_LEN as i64)))?;
+        inner.read_exact(&mut footer)?;
+        match &footer[8..] == MAGIC_FOOTER {
+            true => Ok(Some(u64::from_le_bytes(footer[..8].try_into().unwrap()))),
+            false => Ok(None),
+        }
+    }
+
+    fn read_chunk(inner: &mut BufReader<File>, offset: u64) -> Result<(u8, Vec<u8>), RuntimeError> {
+        let mut header = [0; CHUNK_HEADER_LEN as usize];
+        inner.seek(SeekFrom::Start(offset))?;
+        inner.read_exact(&mut header)?;
+
+        // a corrupted length should not allocate beyond the file
+        let len = u32::from_le_bytes(header[1..].try_into().unwrap());
+        let mut payload = vec![];
+        inner.by_ref().take(len.into()).read_to_end(&mut payload)?;
+        match payload.len() == len as usize {
+            true => Ok((header[0], payload)),
+            false => RuntimeError::expect("The log chunk is truncated"),
+        }
+    }
+
+    /// Rebuilds the index of a log without the footer, stopping at a truncated chunk.
+    fn scan(inner: &mut BufReader<File>) -> Result<LogIndex, RuntimeError> {
+        let len = inner.seek(SeekFrom::End(0))?;
+
+        let mut index = LogIndex::default();
+        let mut offset = HEADER_LEN;
+        while offset + CHUNK_HEADER_LEN <= len {
+            let (kind, payload) = match Self::read_chunk(inner, offset) {
+                Ok(chunk) => chunk,
+                Err(_) => break,
+            };
+            match kind {
+                CHUNK_STREAM => index.streams.push(decode(&payload)?),
+                // the frames themselves are not decoded
+                CHUNK_FRAME if payload.len() >= FRAME_PREFIX_LEN => {
+                    let stream = u32::from_le_bytes(payload[..4].try_into().unwrap());
+                    let secs = i64::from_le_bytes(payload[4..12].try_into().unwrap());
+                    let nanos = u32::from_le_bytes(payload[12..16].try_into().unwrap());
+                    let timestamp = match Utc.timestamp_opt(secs, nanos).single() {
+                        Some(timestamp) => timestamp,
+                        None => return RuntimeError::expect("The log timestamp is corrupted"),
+                    };
+                    index.entries.push(IndexEntry {
+                        stream,
+                        timestamp,
+                        offset,
+                    });
+                }
+                _ => {}
+            }
+            offset += CHUNK_HEADER_LEN + payload.len() as u64;
+        }
+        Ok(index)
+    }
+
+    pub(crate) fn stream(&self, name: &str) -> Option<&StreamInfo> {
+        self.index.streams.iter().find(|s| s.name == name)
+    }
+
+    /// Returns the timestamp of the first frame among all streams.
+    pub(crate) fn origin(&self) -> Option<DateTime<Utc>> {
+        self.index.entries.iter().map(|e| e.timestamp).min()
+    }
+
+    /// Returns the frames of a stream, since the given timestamp.
+    pub(crate) fn seek(&self, stream: u32, since: DateTime<Utc>) -> Vec<IndexEntry> {
+        self.index
+            .entries
+            .iter()
+            .filter(|e| e.stream == stream && e.timestamp >= since)
+            .copied()
+            .collect()
+    }
+
+    pub(crate) fn read_frame(&mut self, entry: &IndexEntry) -> Result<Frame, RuntimeError> {
+        match Self::read_chunk(&mut self.inner, entry.offset)? {
+            (CHUNK_FRAME, payload) if payload.len() >= FRAME_PREFIX_LEN => {
+                decode(&payload[FRAME_PREFIX_LEN..])
+            }
+            _ => RuntimeError::expect("The log frame is corrupted"),
+        }
+    }
+}
+
+struct Message {
+    stream: u32,
+    meta: Option<VideoMeta>,
+    timestamp: DateTime<Utc>,
+    frame: Vec<u8>,
+}
+
+struct Thread {
+    reader: ArcVideoReader,
+    alive: AliveFlag,
+    lease: Lease,
+
+    name: String,
+    stream: u32,
+    codec: ImageCodec,
+    overlay: Option<Arc<OverlayConfig>>,
+    tx: mpsc::SyncSender<Message>,
+}
+
+impl Thread {
+    fn inner_loop(self) -> Result<(), RuntimeError> {
+        let mut frame = None;
+        let mut introduced = false;
+        let result = loop {
+            // normal shutdown
+            if let false = self.alive.is_running() {
+                break Ok(());
+            }
+            // unexpected shutdown
+            if let Err(e) = self.reader.get(&mut frame) {
+                break Err(e);
+            }
+            let frame = frame.as_mut().unwrap();
+            if let Some(overlay) = self.overlay.as_ref() {
+                if let Err(e) = overlay.draw(&self.name, frame) {
+                    break Err(e);
+                }
+            }
+
+            let message = match encode(&Compressed::new(frame, self.codec)) {
+                Ok(bytes) => Message {
+                    stream: self.stream,
+                    // the first frame introduces the stream
+                    meta: Some(frame.meta.clone()).filter(|_| !introduced),
+                    timestamp: frame.timestamp,
+                    frame: bytes,
+                },
+                Err(e) => break Err(e),
+            };
+            introduced = true;
+            // the writer has been terminated
+            if self.tx.send(message).is_err() {
+                break Ok(());
+            }
+        };
+
+        // graceful shutdown
+        self.lease.release()?;
+        result
+    }
+}
+
+fn write_loop(
+    mut writer: LogWriter,
+    names: Vec<String>,
+    rx: mpsc::Receiver<Message>,
+) -> Result<(), RuntimeError> {
+    let mut introduced = vec![false; names.len()];
+    // ends when every reader thread has been terminated
+    for message in rx {
+        let stream = message.stream as usize;
+        if !introduced[stream] {
+            let meta = match message.meta {
+                Some(meta) => meta,
+                None => continue,
+            };
+            writer.add_stream(message.stream, &names[stream], meta)?;
+            introduced[stream] = true;
+        }
+        writer.add_frame(message.stream, message.timestamp, &message.frame)?;
+    }
+    writer.finish()
+}
+
+/// Writes the frames of several readers into one log file.
+///
+/// The overlays of the readers are drawn, if they are on `log`.
+pub struct LogRecorder {
+    alive: AliveFlag,
+    threads: Mutex<Vec<thread::JoinHandle<Result<(), RuntimeError>>>>,
+
+    readers: BTreeMap<String, ArcVideoReader>,
+    overlays: BTreeMap<String, Arc<OverlayConfig>>,
+    path: PathBuf,
+    codec: ImageCodec,
+}
+
+impl LogRecorder {
+    pub fn new<P: AsRef<Path>>(
+        driver: &EyeDriver,
+        names: &[&str],
+        path: P,
+    ) -> Result<Self, RuntimeError> {
+        let readers = names
+            .iter()
+            .map(|&name| match driver.get(name) {
+                Some(reader) => Ok((name.to_string(), reader.clone())),
+                None => RuntimeError::message(format!("No such reader: {}", name)),
+            })
+            .collect::<Result<_, RuntimeError>>()?;
+        let overlays = names
+            .iter()
+            .filter_map(|&name| {
+                let overlay = driver.overlay(name, OverlayTarget::Log)?;
+                Some((name.to_string(), overlay))
+            })
+            .collect();
+
+        Ok(Self {
+            alive: AliveFlag::default(),
+            threads: Mutex::new(vec![]),
+            readers,
+            overlays,
+            path: path.as_ref().to_path_buf(),
+            codec: ImageCodec::Raw,
+        })
+    }
+
+    /// Compresses the images with the given codec.
+    pub fn with_codec(mut self, codec: ImageCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn start(&self) -> Result<(), RuntimeError> {
+        // the log being written is kept on a second start
+        self.alive.start()?;
+
+        // the leases taken so far are given back on a failure
+        let prepared = self
+            .readers
+            .values()
+            .map(Lease::new)
+            .collect::<Result<Vec<_>, _>>()
+            .and_then(|leases| Ok((leases, LogWriter::create(&self.path)?)));
+        let (leases, writer) = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                self.alive.stop().ok();
+                return Err(e);
+            }
+        };
+
+        let (tx, rx) = mpsc::sync_channel(2 * self.readers.len());
+        let mut threads = self.threads.lock().unwrap();
+        let streams = self.readers.iter().zip(leases).enumerate();
+        for (stream, ((name, reader), lease)) in streams {
+            let this = Thread {
+                reader: reader.clone(),
+                alive: self.alive.clone(),
+                lease,
+                name: name.clone(),
+                stream: stream as u32,
+                codec: self.codec,
+                overlay: self.overlays.get(name).cloned(),
+                tx: tx.clone(),
+            };
+            threads.push(thread::spawn(move || this.inner_loop()));
+        }
+
+        let names = self.readers.keys().cloned().collect();
+        threads.push(thread::spawn(move || write_loop(writer, names, rx)));
+        Ok(())
+    }
+
+    pub fn stop(&self) -> Result<(), RuntimeError> {
+        self.alive.stop().ok();
+        self.threads
+            .lock()
+            .unwrap()
+            .drain(..)
+            .map(|thread| match thread.join() {
+                Ok(res) => res,
+                Err(_) => RuntimeError::unexpected(),
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    #[inline]
+    pub fn is_running(&self) -> bool {
+        self.alive.is_running()
+    }
+}
+
+impl Drop for LogRecorder {
+    fn drop(&mut self) {
+        self.stop().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::process;
+
+    use crate::frame::Image;
+
+    use chrono::Duration;
+    use opencv::core::{Scalar, CV_8UC1};
+    use opencv::prelude::*;
+
+    const META: VideoMeta = VideoMeta {
+        codec: None,
+        color: None,
+        width: 3,
+        height: 2,
+        fps: 30,
+    };
+
+    fn timestamp(stream: u32, index: usize) -> DateTime<Utc> {
+        let ms = 33 * index as i64 + i64::from(stream);
+        Utc.ymd(2020, 1, 2).and_hms(3, 4, 5) + Duration::milliseconds(ms)
+    }
+
+    /// Writes 4 frames of the left & right streams, without the footer.
+    fn write_log(path: &Path) -> LogWriter {
+        let mut writer = LogWriter::create(path).unwrap();
+        writer.add_stream(0, "left", META).unwrap();
+        writer.add_stream(1, "right", META).unwrap();
+        for index in 0..4 {
+            for stream in 0..2 {
+                let value = f64::from(stream * 10) + index as f64;
+                let mat = Mat::new_rows_cols_with_default(2, 3, CV_8UC1, Scalar::all(value));
+                let frame = Frame {
+                    image: Image::from(mat.unwrap()),
+                    meta: META,
+                    timestamp: timestamp(stream, index),
+                    count: index,
+                };
+                let bytes = encode(&frame).unwrap();
+                writer.add_frame(stream, frame.timestamp, &bytes).unwrap();
+            }
+        }
+        writer
+    }
+
+    #[test]
+    fn log_round_trip() {
+        let path = env::temp_dir().join(format!("podo-eye-log-{}.log", process::id()));
+        write_log(&path).finish().unwrap();
+
+        let mut file = LogFile::open(&path).unwrap();
+        let stream = file.stream("right").unwrap().id;
+        assert_eq!(stream, 1);
+        assert_eq!(file.origin(), Some(timestamp(0, 0)));
+
+        let entries = file.seek(stream, timestamp(0, 2));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].timestamp, timestamp(1, 2));
+
+        let frame = file.read_frame(&entries[1]).unwrap();
+        assert_eq!(frame.count, 3);
+        assert_eq!(frame.timestamp, timestamp(1, 3));
+        assert_eq!(*frame.image.at_2d::<u8>(1, 2).unwrap(), 13);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn log_truncated() {
+        let path = env::temp_dir().join(format!("podo-eye-truncated-{}.log", process::id()));
+        // an interrupted recording, in the middle of the last chunk
+        drop(write_log(&path));
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 3).unwrap();
+
+        let mut file = LogFile::open(&path).unwrap();
+        assert_eq!(file.seek(0, timestamp(0, 0)).len(), 4);
+        let entries = file.seek(1, timestamp(0, 0));
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].timestamp, timestamp(1, 2));
+
+        let frame = file.read_frame(&entries[2]).unwrap();
+        assert_eq!(frame.count, 2);
+        assert_eq!(*frame.image.at_2d::<u8>(0, 0).unwrap(), 12);
+
+        fs::remove_file(path).unwrap();
+    }
+}
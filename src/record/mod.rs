@@ -0,0 +1,10 @@
+pub(crate) mod dataset;
+mod event;
+pub(crate) mod log;
+pub(crate) mod sidecar;
+mod video;
+
+pub use self::dataset::DatasetWriter;
+pub use self::event::{EventConfig, EventRecorder};
+pub use self::log::LogRecorder;
+pub use self::video::{RecordConfig, VideoRecorder};
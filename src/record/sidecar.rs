@@ -0,0 +1,117 @@
+//! The sidecar files of recordings, which keep the original timestamps.
+//!
+//! A sidecar is a CSV file next to its video, with the extension `csv`:
+//!
+//! ```text
+//! index,count,timestamp
+//! 0,1042,2020-06-01T12:00:00.033012Z
+//! 1,1043,2020-06-01T12:00:00.066153Z
+//! ```
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use chrono::prelude::*;
+use podo_core_driver::*;
+
+const HEADER: &str = "index,count,timestamp";
+
+/// Returns the path of the sidecar of a video file.
+#[inline]
+pub(crate) fn path_of(video: &Path) -> PathBuf {
+    video.with_extension("csv")
+}
+
+pub(crate) struct SidecarWriter {
+    inner: BufWriter<File>,
+    index: usize,
+}
+
+impl SidecarWriter {
+    pub(crate) fn new(video: &Path) -> Result<Self, RuntimeError> {
+        let mut inner = BufWriter::new(File::create(path_of(video))?);
+        writeln!(inner, "{}", HEADER)?;
+        Ok(Self { inner, index: 0 })
+    }
+
+    pub(crate) fn write(
+        &mut self,
+        count: usize,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), RuntimeError> {
+        writeln!(
+            self.inner,
+            "{},{},{}",
+            self.index,
+            count,
+            timestamp.to_rfc3339_opts(SecondsFormat::Micros, true)
+        )?;
+        self.index += 1;
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> Result<(), RuntimeError> {
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads the sequence numbers and the timestamps of the frames in a sidecar.
+pub(crate) fn read(video: &Path) -> Result<Vec<(usize, DateTime<Utc>)>, RuntimeError> {
+    let path = path_of(video);
+    let text = fs::read_to_string(&path)?;
+
+    text.lines()
+        .skip_while(|line| *line == HEADER)
+        .filter(|line| !line.is_empty())
+        .enumerate()
+        .map(|(index, line)| {
+            let mut fields = line.split(',').skip(1);
+            let count = fields.next().and_then(|f| f.parse().ok());
+            let timestamp = fields.next().and_then(|f| f.parse().ok());
+            match (count, timestamp) {
+                (Some(count), Some(timestamp)) => Ok((count, timestamp)),
+                _ => RuntimeError::message(format!(
+                    "Malformed sidecar {:?} at line {}",
+                    &path,
+                    index + 2
+                )),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn sidecar_round_trip() {
+        let video = env::temp_dir().join(format!("podo-eye-sidecar-{}.avi", process::id()));
+        let origin = Utc.ymd(2020, 6, 1).and_hms_micro(12, 0, 0, 33_012);
+        let frames: Vec<_> = (0..3)
+            .map(|index| {
+                let timestamp = origin + chrono::Duration::microseconds(33_141 * index);
+                (1042 + 2 * index as usize, timestamp)
+            })
+            .collect();
+
+        let mut writer = SidecarWriter::new(&video).unwrap();
+        for (count, timestamp) in &frames {
+            writer.write(*count, *timestamp).unwrap();
+        }
+        writer.finish().unwrap();
+        assert_eq!(read(&video).unwrap(), frames);
+
+        let text = fs::read_to_string(path_of(&video)).unwrap();
+        assert!(text.starts_with("index,count,timestamp\n0,1042,2020-06-01T12:00:00.033012Z\n"));
+
+        fs::write(path_of(&video), "index,count,timestamp\n0,1042,yesterday\n").unwrap();
+        assert!(read(&video).is_err());
+        fs::remove_file(path_of(&video)).unwrap();
+    }
+}
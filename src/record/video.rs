@@ -0,0 +1,448 @@
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::sidecar::{self, SidecarWriter};
+use crate::common::{ArcVideoReader, Lease};
+use crate::config::fourcc;
+use crate::frame::Frame;
+use crate::overlay::OverlayConfig;
+
+use chrono::prelude::*;
+use opencv::core::Size;
+use opencv::prelude::*;
+use opencv::videoio;
+use podo_core_driver::*;
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RecordConfig {
+    /// The directory of the segments, relative to the config file.
+    pub(crate) path: String,
+
+    pub(crate) codec: Option<String>,
+    pub(crate) container: Option<String>,
+    pub(crate) fps: Option<u32>,
+
+    /// Rotates the segment after the given seconds.
+    pub(crate) segment_secs: Option<u64>,
+    /// Rotates the segment after the given bytes.
+    pub(crate) segment_bytes: Option<u64>,
+    /// Deletes the oldest segments with their sidecars when the total bytes exceed the quota,
+    /// including the open segment, which is checked once per second.
+    pub(crate) quota_bytes: Option<u64>,
+}
+
+impl RecordConfig {
+    #[inline]
+    fn codec(&self) -> &str {
+        self.codec.as_deref().unwrap_or("MJPG")
+    }
+
+    #[inline]
+    fn container(&self) -> &str {
+        self.container.as_deref().unwrap_or("avi")
+    }
+}
+
+pub(super) struct Segment {
+    writer: videoio::VideoWriter,
+    sidecar: SidecarWriter,
+    path: PathBuf,
+    begin: DateTime<Utc>,
+    count: usize,
+}
+
+/// The format of the timestamps in the names of the segments.
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S%.3f";
+
+impl Segment {
+    /// Returns the path of a file, named after the reader and the timestamp.
+    pub(super) fn path(dir: &Path, name: &str, timestamp: DateTime<Utc>, ext: &str) -> PathBuf {
+        let mut path = dir.to_path_buf();
+        path.push(format!(
+            "{}-{}.{}",
+            name,
+            timestamp.format(TIMESTAMP_FORMAT),
+            ext
+        ));
+        path
+    }
+
+    /// Returns the timestamp of a file named by `Segment::path`, if it is of the reader.
+    fn parse(path: &Path, name: &str, ext: &str) -> Option<NaiveDateTime> {
+        if path.extension()? != ext {
+            return None;
+        }
+        let stem = path.file_stem()?.to_str()?;
+        let prefix = format!("{}-", name);
+        if !stem.starts_with(&prefix) {
+            return None;
+        }
+        NaiveDateTime::parse_from_str(&stem[prefix.len()..], TIMESTAMP_FORMAT).ok()
+    }
+
+    pub(super) fn new(
+        path: PathBuf,
+        codec: &str,
+        fps: u32,
+        image: &Mat,
+        begin: DateTime<Utc>,
+    ) -> Result<Self, RuntimeError> {
+        let fourcc = match fourcc(codec)? {
+            Some(fourcc) => fourcc,
+            None => return RuntimeError::expect("The codec should be a FOURCC code"),
+        };
+        let size = Size::new(image.cols(), image.rows());
+        let is_color = image.channels()? != 1;
+
+        let filename = match path.to_str() {
+            Some(filename) => filename,
+            None => return RuntimeError::expect("The path should be a valid UTF-8 string"),
+        };
+        let writer = videoio::VideoWriter::new(filename, fourcc, fps.into(), size, is_color)?;
+        match writer.is_opened()? {
+            true => Ok(Self {
+                writer,
+                sidecar: SidecarWriter::new(&path)?,
+                path,
+                begin,
+                count: 0,
+            }),
+            false => RuntimeError::expect("Failed to open VideoWriter"),
+        }
+    }
+
+    pub(super) fn write(
+        &mut self,
+        image: &Mat,
+        count: usize,
+        timestamp: DateTime<Utc>,
+    ) -> Result<(), RuntimeError> {
+        self.writer.write(image)?;
+        self.sidecar.write(count, timestamp)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Returns whether the file sizes should be checked, once per second to spare syscalls.
+    #[inline]
+    fn is_due(&self, config: &RecordConfig, frame: &Frame) -> bool {
+        let fps = config.fps.unwrap_or(frame.meta.fps).max(1) as usize;
+        self.count % fps == 0
+    }
+
+    fn is_full(&self, config: &RecordConfig, frame: &Frame) -> Result<bool, RuntimeError> {
+        if let Some(secs) = config.segment_secs {
+            if (frame.timestamp - self.begin).num_seconds() >= secs as i64 {
+                return Ok(true);
+            }
+        }
+        if let Some(bytes) = config.segment_bytes {
+            if self.is_due(config, frame) && fs::metadata(&self.path)?.len() >= bytes {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    pub(super) fn finish(mut self) -> Result<PathBuf, RuntimeError> {
+        self.writer.release()?;
+        self.sidecar.finish()?;
+        Ok(self.path)
+    }
+}
+
+struct Thread {
+    reader: ArcVideoReader,
+    alive: AliveFlag,
+    lease: Lease,
+
+    name: String,
+    dir: PathBuf,
+    config: RecordConfig,
+    overlay: Option<Arc<OverlayConfig>>,
+}
+
+impl Thread {
+    fn inner_loop(self) -> Result<(), RuntimeError> {
+        let mut frame = None;
+        let mut segment: Option<Segment> = None;
+        let result = loop {
+            // normal shutdown
+            if let false = self.alive.is_running() {
+                break Ok(());
+            }
+            // unexpected shutdown
+            if let Err(e) = self.reader.get(&mut frame) {
+                break Err(e);
+            }
+            let frame = frame.as_mut().unwrap();
+            if let Some(overlay) = self.overlay.as_ref() {
+                if let Err(e) = overlay.draw(&self.name, frame) {
+                    break Err(e);
+                }
+            }
+
+            if let Err(e) = self.write(&mut segment, frame) {
+                break Err(e);
+            }
+        };
+
+        // graceful shutdown
+        self.alive.stop().ok();
+        if let Some(segment) = segment {
+            segment.finish()?;
+        }
+        self.lease.release()?;
+        result
+    }
+
+    fn write(&self, segment: &mut Option<Segment>, frame: &Frame) -> Result<(), RuntimeError> {
+        if let Some(s) = segment.as_ref() {
+            if s.is_full(&self.config, frame)? {
+                segment.take().unwrap().finish()?;
+                self.apply_quota(None)?;
+            }
+        }
+
+        if segment.is_none() {
+            let path = Segment::path(
+                &self.dir,
+                &self.name,
+                frame.timestamp,
+                self.config.container(),
+            );
+            let fps = self.config.fps.unwrap_or(frame.meta.fps);
+            let s = Segment::new(
+                path,
+                self.config.codec(),
+                fps,
+                &frame.image,
+                frame.timestamp,
+            )?;
+            segment.replace(s);
+        }
+
+        let s = segment.as_mut().unwrap();
+        s.write(&frame.image, frame.count, frame.timestamp)?;
+        if s.is_due(&self.config, frame) {
+            self.apply_quota(Some(&s.path))?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the oldest segments of this reader until they fit in the quota.
+    ///
+    /// The open segment is counted, but never deleted.
+    fn apply_quota(&self, open: Option<&Path>) -> Result<(), RuntimeError> {
+        let quota = match self.config.quota_bytes {
+            Some(quota) => quota,
+            None => return Ok(()),
+        };
+
+        let segments = segments(&self.dir, &self.name, self.config.container())?;
+        for path in expired(segments, quota, open) {
+            fs::remove_file(sidecar::path_of(&path)).ok();
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the segments of a reader with their sizes.
+///
+/// The files of the other readers, even with a name sharing the prefix, are left out.
+fn segments(dir: &Path, name: &str, ext: &str) -> Result<Vec<(u64, PathBuf)>, RuntimeError> {
+    fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| Segment::parse(path, name, ext).is_some())
+        .map(|path| {
+            // the sidecar goes away with its segment
+            let sidecar = fs::metadata(sidecar::path_of(&path)).map(|m| m.len());
+            let len = fs::metadata(&path)?.len() + sidecar.unwrap_or_default();
+            Ok((len, path))
+        })
+        .collect()
+}
+
+/// Returns the oldest segments to delete, until the rest fit in the quota.
+fn expired(mut segments: Vec<(u64, PathBuf)>, quota: u64, open: Option<&Path>) -> Vec<PathBuf> {
+    // the names are sorted by their timestamps
+    segments.sort_by(|(_, a), (_, b)| a.cmp(b));
+
+    let mut total: u64 = segments.iter().map(|(len, _)| len).sum();
+    let mut expired = vec![];
+    for (len, path) in segments {
+        if total <= quota {
+            break;
+        }
+        if Some(path.as_path()) == open {
+            continue;
+        }
+        total -= len;
+        expired.push(path);
+    }
+    expired
+}
+
+/// Writes the frames of a reader into video files, rotating the segments.
+pub struct VideoRecorder {
+    reader: ArcVideoReader,
+    alive: AliveFlag,
+    thread: Mutex<Option<thread::JoinHandle<Result<(), RuntimeError>>>>,
+
+    name: String,
+    dir: PathBuf,
+    config: RecordConfig,
+    overlay: Option<Arc<OverlayConfig>>,
+}
+
+impl VideoRecorder {
+    pub fn new<P: AsRef<Path>>(
+        name: &str,
+        reader: ArcVideoReader,
+        config: RecordConfig,
+        path: P,
+    ) -> Result<Self, RuntimeError> {
+        let mut dir = path.as_ref().to_path_buf();
+        dir.push(&config.path);
+        fs::create_dir_all(&dir)?;
+
+        Ok(Self {
+            reader,
+            alive: AliveFlag::default(),
+            thread: Mutex::new(None),
+            name: name.to_string(),
+            dir,
+            config,
+            overlay: None,
+        })
+    }
+
+    /// Draws the overlay on the recorded frames.
+    pub fn with_overlay(mut self, overlay: Arc<OverlayConfig>) -> Self {
+        self.overlay = Some(overlay);
+        self
+    }
+
+    pub fn start(&self) -> Result<(), RuntimeError> {
+        let lease = Lease::new(&self.reader)?;
+
+        self.alive.start()?;
+        let this = Thread {
+            reader: self.reader.clone(),
+            alive: self.alive.clone(),
+            lease,
+            name: self.name.clone(),
+            dir: self.dir.clone(),
+            config: self.config.clone(),
+            overlay: self.overlay.clone(),
+        };
+        let t = thread::spawn(move || this.inner_loop());
+        self.thread.lock().unwrap().replace(t);
+        Ok(())
+    }
+
+    #[inline]
+    pub fn stop(&self) -> Result<(), RuntimeError> {
+        self.alive.stop().ok();
+        match self.thread.lock().unwrap().take() {
+            Some(thread) => match thread.join() {
+                Ok(res) => res,
+                Err(_) => RuntimeError::unexpected(),
+            },
+            None => Ok(()),
+        }
+    }
+
+    #[inline]
+    pub fn is_running(&self) -> bool {
+        self.alive.is_running()
+    }
+}
+
+impl Drop for VideoRecorder {
+    fn drop(&mut self) {
+        self.stop().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_path() {
+        let timestamp = Utc.ymd(2020, 1, 2).and_hms_milli(3, 4, 5, 67);
+        assert_eq!(
+            Segment::path(Path::new("records"), "main", timestamp, "avi"),
+            Path::new("records/main-20200102-030405.067.avi"),
+        );
+
+        let path = Path::new("records/main-20200102-030405.067.avi");
+        let parsed = Segment::parse(path, "main", "avi").unwrap();
+        assert_eq!(DateTime::<Utc>::from_utc(parsed, Utc), timestamp);
+        assert!(Segment::parse(path, "mai", "avi").is_none());
+        assert!(Segment::parse(path, "main", "mp4").is_none());
+    }
+
+    #[test]
+    fn segment_of_reader() {
+        let name = format!("podo-eye-segments-{}", std::process::id());
+        let dir = std::env::temp_dir().join(name);
+        fs::create_dir_all(&dir).unwrap();
+        for (name, len) in &[
+            ("cam-20200102-030400.000.avi", 10),
+            ("cam-20200102-030400.000.csv", 1),
+            ("cam-2-20200102-030400.000.avi", 20),
+            ("cam-2-20200102-030400.000.csv", 2),
+            ("cam-20200102-030410.000.event.avi", 30),
+            ("cam-notes.avi", 40),
+        ] {
+            fs::write(dir.join(name), vec![0; *len]).unwrap();
+        }
+
+        let cam = segments(&dir, "cam", "avi").unwrap();
+        let cam_2 = segments(&dir, "cam-2", "avi").unwrap();
+        fs::remove_dir_all(&dir).ok();
+
+        // the sidecar is counted with its segment
+        assert_eq!(cam, vec![(11, dir.join("cam-20200102-030400.000.avi"))]);
+        assert_eq!(cam_2, vec![(22, dir.join("cam-2-20200102-030400.000.avi"))]);
+    }
+
+    #[test]
+    fn segment_quota() {
+        let segments = vec![
+            (40, PathBuf::from("main-20200102-030410.000.avi")),
+            (30, PathBuf::from("main-20200102-030400.000.avi")),
+            (20, PathBuf::from("main-20200102-030420.000.avi")),
+        ];
+
+        // the oldest first
+        assert_eq!(
+            expired(segments.clone(), 60, None),
+            vec![PathBuf::from("main-20200102-030400.000.avi")],
+        );
+        assert_eq!(
+            expired(segments.clone(), 40, None),
+            vec![
+                PathBuf::from("main-20200102-030400.000.avi"),
+                PathBuf::from("main-20200102-030410.000.avi"),
+            ],
+        );
+        assert!(expired(segments.clone(), 90, None).is_empty());
+
+        // the open segment is counted, but kept
+        let open = PathBuf::from("main-20200102-030420.000.avi");
+        assert_eq!(expired(segments.clone(), 10, Some(&open)).len(), 2);
+        let open = PathBuf::from("main-20200102-030400.000.avi");
+        assert_eq!(
+            expired(segments, 60, Some(&open)),
+            vec![PathBuf::from("main-20200102-030410.000.avi")],
+        );
+    }
+}
@@ -0,0 +1,248 @@
+//! Still images of frames, optionally tagged with their origin.
+//!
+//! The tags are a YAML document, embedded as a `tEXt` chunk with the keyword
+//! `podo-eye` in PNG, or as a comment (`COM`) segment in JPEG:
+//!
+//! ```text
+//! reader: main
+//! timestamp: "2020-06-01T12:00:00.033012Z"
+//! meta:
+//!   codec: MJPG
+//!   color: Color
+//!   width: 640
+//!   height: 480
+//!   fps: 30
+//! ```
+
+use std::fs;
+use std::path::Path;
+
+use crate::codec::ImageCodec;
+use crate::config::VideoMeta;
+use crate::frame::Frame;
+
+use chrono::{DateTime, Utc};
+use podo_core_driver::RuntimeError;
+use serde::{Deserialize, Serialize};
+
+/// The keyword of the PNG text chunk holding the tags.
+const PNG_KEYWORD: &[u8] = b"podo-eye";
+
+/// The end of the PNG signature & the `IHDR` chunk, which should come first.
+const PNG_IHDR_END: usize = 8 + 4 + 4 + 13 + 4;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImageFormat {
+    Png,
+    Jpeg { quality: u8 },
+}
+
+impl ImageFormat {
+    /// Guesses the format from the extension of a path, with the JPEG quality of 95.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let ext = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "png" => Some(Self::Png),
+            "jpg" | "jpeg" => Some(Self::Jpeg { quality: 95 }),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    fn codec(self) -> ImageCodec {
+        match self {
+            Self::Png => ImageCodec::Png,
+            Self::Jpeg { quality } => ImageCodec::Jpeg { quality },
+        }
+    }
+}
+
+/// The tags embedded into a snapshot.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotTags {
+    pub reader: String,
+    pub timestamp: DateTime<Utc>,
+    pub meta: VideoMeta,
+}
+
+impl Frame {
+    /// Encodes the image, embedding the tags if a reader name is given.
+    pub fn snapshot(
+        &self,
+        format: ImageFormat,
+        reader: Option<&str>,
+    ) -> Result<Vec<u8>, RuntimeError> {
+        let data = format.codec().encode(&self.image)?;
+        let reader = match reader {
+            Some(reader) => reader,
+            None => return Ok(data),
+        };
+
+        let tags = SnapshotTags {
+            reader: reader.to_string(),
+            timestamp: self.timestamp,
+            meta: self.meta.clone(),
+        };
+        let text = serde_yaml::to_string(&tags)?;
+        match format {
+            ImageFormat::Png => embed_png(data, text.as_bytes()),
+            ImageFormat::Jpeg { .. } => embed_jpeg(data, text.as_bytes()),
+        }
+    }
+
+    /// Writes the image into a file.
+    pub fn save<P: AsRef<Path>>(&self, path: P, format: ImageFormat) -> Result<(), RuntimeError> {
+        fs::write(path, self.snapshot(format, None)?)?;
+        Ok(())
+    }
+
+    /// Writes the image into a file, embedding the reader name, the timestamp and the meta.
+    pub fn save_with_tags<P: AsRef<Path>>(
+        &self,
+        path: P,
+        format: ImageFormat,
+        reader: &str,
+    ) -> Result<(), RuntimeError> {
+        fs::write(path, self.snapshot(format, Some(reader))?)?;
+        Ok(())
+    }
+}
+
+/// Reads the tags embedded into a snapshot, if any.
+pub fn read_tags(data: &[u8]) -> Result<Option<SnapshotTags>, RuntimeError> {
+    let text = match find_png_text(data).or_else(|| find_jpeg_comment(data)) {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+    Ok(Some(serde_yaml::from_slice(text)?))
+}
+
+/// Inserts a `tEXt` chunk right after the `IHDR` chunk.
+fn embed_png(mut data: Vec<u8>, text: &[u8]) -> Result<Vec<u8>, RuntimeError> {
+    if data.len() < PNG_IHDR_END || &data[12..16] != b"IHDR" {
+        return RuntimeError::expect("The PNG should begin with IHDR");
+    }
+
+    let mut body = b"tEXt".to_vec();
+    body.extend_from_slice(PNG_KEYWORD);
+    body.push(0);
+    body.extend_from_slice(text);
+
+    let mut chunk = ((body.len() - 4) as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(&body);
+    chunk.extend_from_slice(&crc32(&body).to_be_bytes());
+
+    data.splice(PNG_IHDR_END..PNG_IHDR_END, chunk);
+    Ok(data)
+}
+
+/// Inserts a `COM` segment right after `SOI` and the `APP0` (JFIF) segment, if any.
+fn embed_jpeg(mut data: Vec<u8>, text: &[u8]) -> Result<Vec<u8>, RuntimeError> {
+    if text.len() > u16::MAX as usize - 2 {
+        return RuntimeError::expect("The tags should fit in a JPEG comment");
+    }
+    let mut offset = 2;
+    if data.get(2..4) == Some(&[0xff, 0xe0][..]) {
+        match data.get(4..6) {
+            Some(len) => offset += 2 + u16::from_be_bytes([len[0], len[1]]) as usize,
+            None => return RuntimeError::expect("The JPEG should not be truncated"),
+        }
+    }
+    if offset > data.len() {
+        return RuntimeError::expect("The JPEG should not be truncated");
+    }
+
+    let mut segment = vec![0xff, 0xfe];
+    segment.extend_from_slice(&((text.len() + 2) as u16).to_be_bytes());
+    segment.extend_from_slice(text);
+
+    data.splice(offset..offset, segment);
+    Ok(data)
+}
+
+fn find_png_text(data: &[u8]) -> Option<&[u8]> {
+    let mut offset = 8;
+    while let Some(header) = data.get(offset..offset + 8) {
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let body = data.get(offset + 8..offset + 8 + len)?;
+        match &header[4..] {
+            b"tEXt" => {
+                let (keyword, text) = body.split_at(body.iter().position(|b| *b == 0)?);
+                if keyword == PNG_KEYWORD {
+                    return Some(&text[1..]);
+                }
+                offset += 12 + len;
+            }
+            b"IDAT" | b"IEND" => return None,
+            _ => offset += 12 + len,
+        }
+    }
+    None
+}
+
+fn find_jpeg_comment(data: &[u8]) -> Option<&[u8]> {
+    let mut offset = 2;
+    // the tags are placed before any image data
+    while let Some(header) = data.get(offset..offset + 4) {
+        if header[0] != 0xff {
+            return None;
+        }
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        match header[1] {
+            0xfe => return data.get(offset + 4..offset + 2 + len),
+            0xe0..=0xef => offset += 2 + len,
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// The CRC-32 of PNG chunks, as defined in ISO 3309.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec;
+    use crate::frame::{sample_frame, MAX_IMAGE_SIZE};
+
+    use opencv::prelude::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn snapshot_tags() {
+        let frame = sample_frame();
+
+        for format in &[ImageFormat::Png, ImageFormat::Jpeg { quality: 90 }] {
+            let plain = frame.snapshot(*format, None).unwrap();
+            assert!(read_tags(&plain).unwrap().is_none());
+
+            let data = frame.snapshot(*format, Some("main")).unwrap();
+            let tags = read_tags(&data).unwrap().unwrap();
+            assert_eq!(tags.reader, "main");
+            assert_eq!(tags.timestamp, frame.timestamp);
+            assert_eq!(tags.meta.width, 32);
+
+            // the image is still readable by OpenCV
+            let image = codec::decode(&data, MAX_IMAGE_SIZE).unwrap();
+            assert_eq!(image.rows(), 24);
+            assert_eq!(image.cols(), 32);
+        }
+    }
+}